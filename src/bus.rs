@@ -4,54 +4,225 @@
 //!
 //! This implementation is not complete and probably buggy.
 //!
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::{
+    cell::{Cell, RefCell},
+    cmp::min,
+    sync::atomic::{AtomicU8, Ordering},
+};
 use log::{debug, info, trace};
-use std::{cell::RefCell, cmp::min, rc::Rc};
+use spin::{Mutex, MutexGuard};
 
 use usb_device::bus::PollResult;
 use usb_device::endpoint::{EndpointAddress, EndpointType};
 use usb_device::{Result as UsbDeviceResult, UsbDirection, UsbError};
 
-const NUM_ENDPOINTS: usize = 8;
+use crate::{InCompleteMode, PollOrder};
+
+/// Maximum number of endpoints in each direction
+/// allowed by the USB specification.
+pub(crate) const MAX_ENDPOINTS: usize = 16;
+
+/// Transfer statistics of an Endpoint, collected
+/// from the Device side of the emulated bus.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct EpStats {
+    /// Number of packets, including Setup and
+    /// zero-length packets, the Device read or wrote.
+    pub packets: usize,
+    /// Number of bytes the Device read or wrote.
+    pub bytes: usize,
+    /// Number of reads which returned `WouldBlock`.
+    pub would_block: usize,
+    /// Number of reads and writes while the Endpoint
+    /// was stalled.
+    pub stalled: usize,
+    /// Number of `poll()` calls which reported
+    /// the Endpoint.
+    pub polls: usize,
+}
+
+impl EpStats {
+    fn packet(&mut self, len: usize) {
+        self.packets += 1;
+        self.bytes += len;
+    }
+}
+
+/// Bus event reported by the next `poll()`
+/// instead of endpoint events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BusEvent {
+    Reset,
+    Suspend,
+    Resume,
+}
+
+/// Endpoint allocated by a Class.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct EndpointInfo {
+    /// Endpoint address.
+    pub address: EndpointAddress,
+    /// Endpoint type.
+    pub ep_type: EndpointType,
+    /// Maximum packet size.
+    pub max_packet_size: u16,
+    /// Polling interval, `bInterval` of the Endpoint descriptor.
+    pub interval: u8,
+}
 
 /// Holds a simulated Endpoint status which allows bi-directional
-/// communication via 1024 byte buffers.
+/// communication via buffers allocated together with the Endpoint.
 struct EndpointImpl {
     ep_type: Option<EndpointType>,
     stall: bool,
     read_len: usize,
-    read: [u8; 1024],
+    read: Vec<u8>,
     read_ready: bool,
     write_len: usize,
-    write: [u8; 1024],
-    write_done: bool,
+    write: Vec<u8>,
+    /// Lengths and data toggles of packets in `write`,
+    /// one per `write()` call.
+    write_packets: Vec<(usize, bool)>,
+    write_ready: bool,
+    /// Number of transmitted packets `poll()` didn't
+    /// report yet, one is reported per poll.
+    in_complete: usize,
+    /// When a packet written by the Device is transmitted.
+    in_complete_mode: InCompleteMode,
     setup: bool,
+    setup_len: usize,
     max_size: usize,
+    interval: u8,
+    /// Data toggle of the next packet the Device sends
+    /// or expects to receive, `true` for DATA1.
+    toggle: bool,
+    /// Data toggle of the next packet the Host sends
+    /// or expects to receive.
+    host_toggle: bool,
+    /// Expected data toggle of the first packet
+    /// received with a wrong one.
+    toggle_mismatch: Option<bool>,
+    stats: EpStats,
 }
 
 impl EndpointImpl {
-    fn new() -> Self {
+    fn new(in_complete_mode: InCompleteMode) -> Self {
         EndpointImpl {
             ep_type: None,
             stall: false,
             read_len: 0,
-            read: [0; 1024],
+            read: Vec::new(),
             read_ready: false,
             write_len: 0,
-            write: [0; 1024],
-            write_done: false,
+            write: Vec::new(),
+            write_packets: Vec::new(),
+            write_ready: false,
+            in_complete: 0,
+            in_complete_mode,
             setup: false,
+            setup_len: 0,
             max_size: 0,
+            interval: 0,
+            toggle: false,
+            host_toggle: false,
+            toggle_mismatch: None,
+            stats: EpStats::default(),
         }
     }
 
-    /// Sets data that will be read by usb-device from the Endpoint
-    fn set_read(&mut self, data: &[u8], setup: bool) -> usize {
-        self.read_len = data.len();
-        if self.read_len > 0 {
-            self.read[..self.read_len].clone_from_slice(data);
-            self.setup = setup;
-            self.read_ready = true;
+    fn is_iso(&self) -> bool {
+        matches!(self.ep_type, Some(EndpointType::Isochronous { .. }))
+    }
+
+    /// Sets data toggle of both the Device and the Host.
+    fn reset_toggle(&mut self, toggle: bool) {
+        self.toggle = toggle;
+        self.host_toggle = toggle;
+    }
+
+    /// Records `packets` packets the Host retrieved
+    /// or lost as transmitted.
+    fn host_consumed(&mut self, packets: usize) {
+        if self.in_complete_mode == InCompleteMode::OnHostRead {
+            self.in_complete += packets;
         }
+    }
+
+    fn toggle_mismatch(&mut self, expected: bool) {
+        debug!("EP : data toggle mismatch, expected DATA{}", expected as u8);
+        if self.toggle_mismatch.is_none() {
+            self.toggle_mismatch = Some(expected);
+        }
+    }
+
+    /// Returns data toggle of a packet the Device sends
+    /// and advances it. Isochronous packets are always DATA0.
+    fn device_sent(&mut self) -> bool {
+        if self.is_iso() {
+            return false;
+        }
+        let toggle = self.toggle;
+        self.toggle = !toggle;
+        toggle
+    }
+
+    /// Checks data toggle of a packet the Device received
+    /// from the Host. The Host advances its toggle, the Device
+    /// does it only if the toggle is the expected one.
+    fn device_received(&mut self) {
+        if self.is_iso() {
+            return;
+        }
+        let toggle = self.host_toggle;
+        self.host_toggle = !toggle;
+        if toggle == self.toggle {
+            self.toggle = !toggle;
+        } else {
+            self.toggle_mismatch(self.toggle);
+        }
+    }
+
+    /// Checks data toggle of a packet the Host received
+    /// from the Device.
+    fn host_received(&mut self, toggle: bool) {
+        if self.is_iso() {
+            return;
+        }
+        if toggle == self.host_toggle {
+            self.host_toggle = !toggle;
+        } else {
+            self.toggle_mismatch(self.host_toggle);
+        }
+    }
+
+    /// Sets data that will be read by usb-device from the Endpoint.
+    ///
+    /// Any data that wasn't read yet is replaced, including
+    /// a previous Setup packet.
+    ///
+    /// A Setup packet of any length, including an empty one,
+    /// is delivered with a single `read()`.
+    ///
+    /// Returns `Err` with the buffer size if `data` doesn't
+    /// fit into the buffer, the buffer is not changed then.
+    fn set_read(&mut self, data: &[u8], setup: bool) -> Result<usize, usize> {
+        if data.len() > self.read.len() {
+            debug!(
+                "EP : {} bytes don't fit into {} byte buffer",
+                data.len(),
+                self.read.len()
+            );
+            return Err(self.read.len());
+        }
+        self.read_len = data.len();
+        self.read[..self.read_len].clone_from_slice(data);
+        self.setup = setup;
+        self.setup_len = if setup { self.read_len } else { 0 };
+        self.read_ready = self.read_len > 0 || setup;
 
         debug!(
             "EP : set data to read: {} bytes, setup: {}",
@@ -59,14 +230,14 @@ impl EndpointImpl {
         );
         trace!("EP : {} {:02x?}", if setup { "<==" } else { "<--" }, data);
 
-        self.read_len
+        Ok(self.read_len)
     }
 
     fn append_read(&mut self, data: &[u8]) -> usize {
-        let len = data.len();
+        let len = min(data.len(), self.read.len() - self.read_len);
 
         if len > 0 {
-            self.read[self.read_len..self.read_len + len].clone_from_slice(data);
+            self.read[self.read_len..self.read_len + len].clone_from_slice(&data[..len]);
             self.read_ready = true;
             self.read_len += len;
         }
@@ -77,17 +248,163 @@ impl EndpointImpl {
         len
     }
 
-    /// Returns data that was written by usb-device to the Endpoint
-    fn get_write(&mut self, data: &mut [u8]) -> usize {
+    /// Sets a zero-length packet that will be read by
+    /// usb-device from the Endpoint.
+    fn set_read_zlp(&mut self) {
+        self.read_len = 0;
+        self.setup = false;
+        self.setup_len = 0;
+        self.read_ready = true;
+
+        debug!("EP : set zero-length packet to read");
+    }
+
+    /// Reads the whole pending Setup packet regardless of
+    /// its length and endpoint's maximum packet size.
+    ///
+    /// If the packet doesn't fit into `buf`, it's dropped and
+    /// `BufferOverflow` is returned.
+    fn read_setup(&mut self, buf: &mut [u8]) -> UsbDeviceResult<usize> {
+        let len = self.setup_len;
+
+        let res = if len > buf.len() {
+            debug!("EP : Setup packet doesn't fit, dropped");
+            Err(UsbError::BufferOverflow)
+        } else {
+            buf[..len].clone_from_slice(&self.read[..len]);
+            Ok(len)
+        };
+
+        self.setup = false;
+        self.setup_len = 0;
+        self.read_len -= len;
+        self.read.copy_within(len.., 0);
+        self.read_ready = self.read_len > 0;
+
+        res
+    }
+
+    /// Discards data in both Endpoint buffers
+    fn discard(&mut self) -> usize {
+        let res = self.read_len + self.write_len;
+        self.read_len = 0;
+        self.read_ready = false;
+        self.setup = false;
+        self.setup_len = 0;
+        self.write_len = 0;
+        self.write_packets.clear();
+        self.write_ready = false;
+        self.in_complete = 0;
+
+        debug!("EP : discard data: {} bytes", res);
+
+        res
+    }
+
+    /// Drops Isochronous packets that weren't transferred
+    /// during the frame. A dropped IN packet is reported
+    /// as transmitted, the same as if the Host lost it.
+    fn iso_drop(&mut self) -> usize {
+        let res = self.read_len + self.write_len;
+        self.read_len = 0;
+        self.read_ready = false;
+        if self.write_ready {
+            self.write_len = 0;
+            self.write_packets.clear();
+            self.write_ready = false;
+            self.host_consumed(1);
+        }
+
+        if res > 0 {
+            debug!("EP : drop iso data: {} bytes", res);
+        }
+
+        res
+    }
+
+    /// Returns data that was written by usb-device to the Endpoint.
+    ///
+    /// Returns `None` if nothing was written, `Some(0)` if
+    /// a zero-length packet was written.
+    ///
+    /// Returns `Err` with the length of the written data if
+    /// it doesn't fit into `data`, nothing is retrieved then.
+    fn get_write(&mut self, data: &mut [u8]) -> Result<Option<usize>, usize> {
+        if !self.write_ready {
+            debug!("EP : no written data");
+            return Ok(None);
+        }
+
         let res = self.write_len;
+        if res > data.len() {
+            debug!(
+                "EP : {} written bytes don't fit into {} byte buffer",
+                res,
+                data.len()
+            );
+            return Err(res);
+        }
         self.write_len = 0;
+        let packets = core::mem::take(&mut self.write_packets);
+        self.host_consumed(packets.len().max(1));
+        for (_, toggle) in packets {
+            self.host_received(toggle);
+        }
         data[..res].clone_from_slice(&self.write[..res]);
-        self.write_done = true;
+        self.write_ready = false;
 
         debug!("EP : retrieve written data: {}", res);
         trace!("EP : --> {:02x?}", &data[..res]);
 
-        res
+        Ok(Some(res))
+    }
+
+    /// Returns the length of the first packet written
+    /// by usb-device and not yet retrieved.
+    fn next_packet_len(&self) -> Option<usize> {
+        self.write_ready.then(|| {
+            self.write_packets
+                .first()
+                .map_or(self.write_len, |(len, _)| *len)
+        })
+    }
+
+    /// Returns the first packet written by usb-device
+    /// to the Endpoint, keeping the rest.
+    ///
+    /// Returns `None` if nothing was written, `Some(0)` if
+    /// a zero-length packet was written.
+    ///
+    /// Returns `Err` with the packet length if it doesn't
+    /// fit into `data`, nothing is retrieved then.
+    fn get_write_packet(&mut self, data: &mut [u8]) -> Result<Option<usize>, usize> {
+        let Some(res) = self.next_packet_len() else {
+            debug!("EP : no written data");
+            return Ok(None);
+        };
+        if res > data.len() {
+            debug!(
+                "EP : {} byte packet doesn't fit into {} byte buffer",
+                res,
+                data.len()
+            );
+            return Err(res);
+        }
+
+        data[..res].clone_from_slice(&self.write[..res]);
+        self.write.copy_within(res..self.write_len, 0);
+        self.write_len -= res;
+        if !self.write_packets.is_empty() {
+            let (_, toggle) = self.write_packets.remove(0);
+            self.host_received(toggle);
+        }
+        self.write_ready = !self.write_packets.is_empty();
+        self.host_consumed(1);
+
+        debug!("EP : retrieve written packet: {}", res);
+        trace!("EP : --> {:02x?}", &data[..res]);
+
+        Ok(Some(res))
     }
 }
 
@@ -95,33 +412,86 @@ impl EndpointImpl {
 /// methods to access endpoint buffers like from
 /// the "Host" side.
 pub(crate) struct UsbBusImpl {
-    ep_i: [RefCell<EndpointImpl>; NUM_ENDPOINTS],
-    ep_o: [RefCell<EndpointImpl>; NUM_ENDPOINTS],
+    ep_i: Vec<RefCell<EndpointImpl>>,
+    ep_o: Vec<RefCell<EndpointImpl>>,
+    poll_order: PollOrder,
+    rng: Cell<u64>,
+    /// The first `write()` that exceeded endpoint's maximum
+    /// packet size: endpoint, max size and write length.
+    size_violation: Cell<Option<(EndpointAddress, usize, usize)>>,
+    /// Size of each Endpoint buffer.
+    buffer_size: usize,
+    /// Reject maximum packet sizes not allowed
+    /// at Full Speed or not fitting the buffer.
+    strict_ep_size: bool,
+    /// Event the next `poll()` reports.
+    event: Cell<Option<BusEvent>>,
+    /// `usb-device` suspended the bus.
+    suspended: Cell<bool>,
+    /// Number of resets requested by the Device.
+    forced_resets: Cell<usize>,
+    /// Faults of Device side reads and writes.
+    injector: RefCell<Option<Box<dyn FaultInjector>>>,
 }
 
 impl UsbBusImpl {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(
+        poll_order: PollOrder,
+        num_endpoints: usize,
+        buffer_size: usize,
+        strict_ep_size: bool,
+        in_complete: InCompleteMode,
+    ) -> Self {
+        let num_endpoints = num_endpoints.clamp(1, MAX_ENDPOINTS);
+        let seed = match poll_order {
+            PollOrder::Shuffled(seed) => seed,
+            _ => 0,
+        };
         Self {
-            ep_i: [
-                RefCell::new(EndpointImpl::new()),
-                RefCell::new(EndpointImpl::new()),
-                RefCell::new(EndpointImpl::new()),
-                RefCell::new(EndpointImpl::new()),
-                RefCell::new(EndpointImpl::new()),
-                RefCell::new(EndpointImpl::new()),
-                RefCell::new(EndpointImpl::new()),
-                RefCell::new(EndpointImpl::new()),
-            ],
-            ep_o: [
-                RefCell::new(EndpointImpl::new()),
-                RefCell::new(EndpointImpl::new()),
-                RefCell::new(EndpointImpl::new()),
-                RefCell::new(EndpointImpl::new()),
-                RefCell::new(EndpointImpl::new()),
-                RefCell::new(EndpointImpl::new()),
-                RefCell::new(EndpointImpl::new()),
-                RefCell::new(EndpointImpl::new()),
-            ],
+            poll_order,
+            rng: Cell::new(seed),
+            size_violation: Cell::new(None),
+            buffer_size,
+            strict_ep_size,
+            event: Cell::new(None),
+            suspended: Cell::new(false),
+            forced_resets: Cell::new(0),
+            injector: RefCell::new(None),
+            ep_i: (0..num_endpoints)
+                .map(|_| RefCell::new(EndpointImpl::new(in_complete)))
+                .collect(),
+            ep_o: (0..num_endpoints)
+                .map(|_| RefCell::new(EndpointImpl::new(in_complete)))
+                .collect(),
+        }
+    }
+
+    /// Number of endpoints in each direction, including EP0.
+    fn num_endpoints(&self) -> usize {
+        self.ep_i.len()
+    }
+
+    /// splitmix64
+    fn next_random(&self) -> u64 {
+        let state = self.rng.get().wrapping_add(0x9e3779b97f4a7c15);
+        self.rng.set(state);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Chooses which of the `pending` non-control endpoints
+    /// is reported by the next poll according to `PollOrder`.
+    fn next_polled(&self, pending: &[usize]) -> Option<usize> {
+        match self.poll_order {
+            PollOrder::Ascending => pending.first().copied(),
+            PollOrder::Descending => pending.last().copied(),
+            PollOrder::Shuffled(_) if pending.is_empty() => None,
+            PollOrder::Shuffled(_) => {
+                let n = self.next_random() % pending.len() as u64;
+                pending.get(n as usize).copied()
+            }
         }
     }
 
@@ -132,27 +502,154 @@ impl UsbBusImpl {
         }
     }
 
-    pub(crate) fn get_write(&self, ep_addr: EndpointAddress, data: &mut [u8]) -> usize {
+    /// Same as `EndpointImpl::get_write()`.
+    pub(crate) fn get_write(
+        &self,
+        ep_addr: EndpointAddress,
+        data: &mut [u8],
+    ) -> Result<Option<usize>, usize> {
         let mut ep = self.epidx(ep_addr).borrow_mut();
         ep.get_write(data)
     }
 
-    pub(crate) fn set_read(&self, ep_addr: EndpointAddress, data: &[u8], setup: bool) -> usize {
+    /// Same as `get_write()`, but returns a single packet.
+    pub(crate) fn get_write_packet(
+        &self,
+        ep_addr: EndpointAddress,
+        data: &mut [u8],
+    ) -> Result<Option<usize>, usize> {
+        let mut ep = self.epidx(ep_addr).borrow_mut();
+        ep.get_write_packet(data)
+    }
+
+    /// Returns the number of packets written by the Device
+    /// and not yet retrieved.
+    pub(crate) fn pending_packets(&self, ep_addr: EndpointAddress) -> usize {
+        let ep = self.epidx(ep_addr).borrow();
+        if ep.write_ready {
+            ep.write_packets.len().max(1)
+        } else {
+            0
+        }
+    }
+
+    /// Returns the length of the next packet written by
+    /// the Device and not yet retrieved.
+    pub(crate) fn next_packet_len(&self, ep_addr: EndpointAddress) -> Option<usize> {
+        let ep = self.epidx(ep_addr).borrow();
+        ep.next_packet_len()
+    }
+
+    /// Returns the length of data written by the Device
+    /// and not yet retrieved with `get_write()`.
+    pub(crate) fn pending_write_len(&self, ep_addr: EndpointAddress) -> Option<usize> {
+        let ep = self.epidx(ep_addr).borrow();
+        ep.write_ready.then_some(ep.write_len)
+    }
+
+    /// Same as `EndpointImpl::set_read()`.
+    pub(crate) fn set_read(
+        &self,
+        ep_addr: EndpointAddress,
+        data: &[u8],
+        setup: bool,
+    ) -> Result<usize, usize> {
         let mut ep = self.epidx(ep_addr).borrow_mut();
+        if data.len() > ep.read.len() {
+            return ep.set_read(data, setup);
+        }
         if setup && ep_addr.index() == 0 && ep_addr.direction() == UsbDirection::Out {
-            // setup packet on EP0OUT removes stall condition
+            // setup packet on EP0OUT removes stall condition,
+            // data and status stages start with DATA1
             ep.stall = false;
+            ep.reset_toggle(true);
             let mut ep0in = self.ep_i.get(ep_addr.index()).unwrap().borrow_mut();
             ep0in.stall = false;
+            ep0in.reset_toggle(true);
         }
         ep.set_read(data, setup)
     }
 
+    pub(crate) fn set_read_zlp(&self, ep_addr: EndpointAddress) {
+        let mut ep = self.epidx(ep_addr).borrow_mut();
+        ep.set_read_zlp()
+    }
+
+    /// Returns `true` if the Endpoint has a packet,
+    /// possibly zero-length, which wasn't read yet.
+    pub(crate) fn ep_read_pending(&self, ep_addr: EndpointAddress) -> bool {
+        let ep = self.epidx(ep_addr).borrow();
+        ep.read_ready
+    }
+
     pub(crate) fn append_read(&self, ep_addr: EndpointAddress, data: &[u8]) -> usize {
         let mut ep = self.epidx(ep_addr).borrow_mut();
         ep.append_read(data)
     }
 
+    /// Returns all allocated endpoints, including EP0,
+    /// ordered by index, OUT before IN.
+    pub(crate) fn allocated_endpoints(&self) -> Vec<EndpointInfo> {
+        let mut res = Vec::new();
+        for index in 0..self.num_endpoints() {
+            for dir in [UsbDirection::Out, UsbDirection::In] {
+                let address = EndpointAddress::from_parts(index, dir);
+                let ep = self.epidx(address).borrow();
+                if let Some(ep_type) = ep.ep_type {
+                    res.push(EndpointInfo {
+                        address,
+                        ep_type,
+                        max_packet_size: ep.max_size as u16,
+                        interval: ep.interval,
+                    });
+                }
+            }
+        }
+        res
+    }
+
+    pub(crate) fn ep_discard(&self, ep_addr: EndpointAddress) -> usize {
+        let mut ep = self.epidx(ep_addr).borrow_mut();
+        ep.discard()
+    }
+
+    /// Returns `true` if the Endpoint was allocated.
+    pub(crate) fn ep_allocated(&self, ep_addr: EndpointAddress) -> bool {
+        ep_addr.index() < self.num_endpoints() && self.epidx(ep_addr).borrow().ep_type.is_some()
+    }
+
+    /// Returns `true` if the Endpoint is Isochronous.
+    pub(crate) fn ep_is_iso(&self, ep_addr: EndpointAddress) -> bool {
+        let ep = self.epidx(ep_addr).borrow();
+        ep.is_iso()
+    }
+
+    /// Drops data which wasn't transferred during the frame
+    /// on all Isochronous endpoints. IN endpoints in `read`
+    /// were read by the Host during the frame and keep
+    /// the packet for the next frame.
+    ///
+    /// Returns the number of bytes dropped.
+    pub(crate) fn iso_end_frame(&self, read: &[EndpointAddress]) -> usize {
+        let mut res = 0;
+        for index in 0..self.num_endpoints() {
+            for dir in [UsbDirection::Out, UsbDirection::In] {
+                let ep_addr = EndpointAddress::from_parts(index, dir);
+                let mut ep = self.epidx(ep_addr).borrow_mut();
+                if ep.is_iso() && !read.contains(&ep_addr) {
+                    res += ep.iso_drop();
+                }
+            }
+        }
+        res
+    }
+
+    /// Returns the size of the Endpoint buffer.
+    pub(crate) fn ep_buffer_size(&self, ep_addr: EndpointAddress) -> usize {
+        let ep = self.epidx(ep_addr).borrow();
+        ep.read.len()
+    }
+
     pub(crate) fn ep_max_size(&self, ep_addr: EndpointAddress) -> usize {
         let ep = self.epidx(ep_addr).borrow();
         ep.max_size
@@ -166,6 +663,20 @@ impl UsbBusImpl {
         }
     }
 
+    /// Returns data written by the Device and not yet
+    /// retrieved, without retrieving it.
+    pub(crate) fn peek_write(&self, ep_addr: EndpointAddress) -> Vec<u8> {
+        let ep = self.epidx(ep_addr).borrow();
+        ep.write[..ep.write_len].to_vec()
+    }
+
+    /// Returns data written by the Host and not yet
+    /// read by the Device, without reading it.
+    pub(crate) fn peek_read(&self, ep_addr: EndpointAddress) -> Vec<u8> {
+        let ep = self.epidx(ep_addr).borrow();
+        ep.read[..ep.read_len].to_vec()
+    }
+
     pub(crate) fn ep_data_len(&self, ep_addr: EndpointAddress) -> usize {
         let ep = self.epidx(ep_addr).borrow();
         match ep_addr.direction() {
@@ -174,6 +685,177 @@ impl UsbBusImpl {
         }
     }
 
+    /// Sets `injector` which can fail Device side
+    /// `read()` and `write()` calls.
+    pub(crate) fn with_fault_injector(self, injector: Option<Box<dyn FaultInjector>>) -> Self {
+        self.injector.replace(injector);
+        self
+    }
+
+    /// Returns an error injected into a Device side `read()`
+    /// or `write()` call on `ep_addr`, if any.
+    fn inject_fault(&self, ep_addr: EndpointAddress, data: Option<&[u8]>) -> Option<UsbError> {
+        let mut injector = self.injector.borrow_mut();
+        let injector = injector.as_mut()?;
+        let res = match data {
+            None => injector.read(ep_addr),
+            Some(data) => injector.write(ep_addr, data),
+        };
+        if let Some(err) = res {
+            debug!(
+                "Bus: injected {:?} on EP {} {:?}",
+                err,
+                ep_addr.index(),
+                ep_addr.direction()
+            );
+        }
+        res
+    }
+
+    /// Returns `true` if `poll()` would report
+    /// endpoint `index`.
+    fn has_events(&self, index: usize) -> bool {
+        let addr_in = EndpointAddress::from_parts(index, UsbDirection::In);
+        let addr_out = EndpointAddress::from_parts(index, UsbDirection::Out);
+        let ep_in = self.epidx(addr_in).borrow();
+        let ep_out = self.epidx(addr_out).borrow();
+        ep_in.in_complete > 0 || ep_in.read_ready || ep_out.read_ready || ep_out.setup
+    }
+
+    /// Discards data and clears stall condition of all
+    /// non-control endpoints, like a Device does when
+    /// it leaves `Configured` state.
+    pub(crate) fn reset_endpoints(&self) {
+        for index in 1..self.num_endpoints() {
+            for ep in [&self.ep_i[index], &self.ep_o[index]] {
+                let mut ep = ep.borrow_mut();
+                ep.discard();
+                ep.stall = false;
+            }
+        }
+    }
+
+    /// Discards data of all non-control endpoints, stall
+    /// conditions and data toggles are kept.
+    ///
+    /// Returns the number of bytes discarded.
+    pub(crate) fn discard_endpoints(&self) -> usize {
+        (1..self.num_endpoints())
+            .flat_map(|index| [&self.ep_i[index], &self.ep_o[index]])
+            .map(|ep| ep.borrow_mut().discard())
+            .sum()
+    }
+
+    /// Makes the next `poll()` report `event`.
+    pub(crate) fn request_event(&self, event: BusEvent) {
+        self.event.set(Some(event));
+    }
+
+    /// Returns `true` if `usb-device` suspended the bus.
+    pub(crate) fn suspended(&self) -> bool {
+        self.suspended.get()
+    }
+
+    /// Returns number of `force_reset()` calls.
+    pub(crate) fn forced_resets(&self) -> usize {
+        self.forced_resets.get()
+    }
+
+    /// Discards data, clears stall condition and resets
+    /// data toggles of all endpoints. Endpoint allocations
+    /// are kept.
+    fn reset(&self) {
+        for ep in self.ep_i.iter().chain(self.ep_o.iter()) {
+            let mut ep = ep.borrow_mut();
+            ep.discard();
+            ep.stall = false;
+            ep.reset_toggle(false);
+            ep.toggle_mismatch = None;
+            ep.stats = EpStats::default();
+        }
+    }
+
+    /// Returns and clears the first recorded `write()` that
+    /// exceeded endpoint's maximum packet size.
+    pub(crate) fn take_size_violation(&self) -> Option<(EndpointAddress, usize, usize)> {
+        self.size_violation.take()
+    }
+
+    /// Sets or clears the stall condition of the Endpoint.
+    /// Isochronous endpoints can't be stalled.
+    pub(crate) fn set_stalled(&self, ep_addr: EndpointAddress, stalled: bool) {
+        let mut ep = self.epidx(ep_addr).borrow_mut();
+        if ep.is_iso() {
+            debug!("Bus: EP {} is Isochronous, can't stall", ep_addr.index());
+            return;
+        }
+        ep.stall = stalled;
+        if !stalled && ep_addr.index() != 0 {
+            // clearing halt resets data toggle
+            ep.toggle = false;
+        }
+    }
+
+    /// Returns transfer statistics of the Endpoint.
+    pub(crate) fn ep_stats(&self, ep_addr: EndpointAddress) -> EpStats {
+        if ep_addr.index() >= self.num_endpoints() {
+            return EpStats::default();
+        }
+        self.epidx(ep_addr).borrow().stats
+    }
+
+    /// Resets transfer statistics of all endpoints.
+    pub(crate) fn reset_stats(&self) {
+        for ep in self.ep_i.iter().chain(self.ep_o.iter()) {
+            ep.borrow_mut().stats = EpStats::default();
+        }
+    }
+
+    /// Returns data toggle of the next packet the Device
+    /// sends or expects to receive, `true` for DATA1.
+    pub(crate) fn data_toggle(&self, ep_addr: EndpointAddress) -> bool {
+        self.epidx(ep_addr).borrow().toggle
+    }
+
+    /// Resets data toggles of all non-control endpoints
+    /// to DATA0 on both the Device and the Host side.
+    pub(crate) fn reset_toggles(&self) {
+        for index in 1..self.num_endpoints() {
+            for ep in [&self.ep_i[index], &self.ep_o[index]] {
+                ep.borrow_mut().reset_toggle(false);
+            }
+        }
+    }
+
+    /// Resets the Host side data toggle of the Endpoint
+    /// to DATA0.
+    pub(crate) fn reset_host_toggle(&self, ep_addr: EndpointAddress) {
+        if self.ep_allocated(ep_addr) {
+            self.epidx(ep_addr).borrow_mut().host_toggle = false;
+        }
+    }
+
+    /// Returns and clears the first data toggle mismatch
+    /// and the expected toggle.
+    pub(crate) fn take_toggle_mismatch(&self) -> Option<(EndpointAddress, bool)> {
+        let mut res = None;
+        for index in 0..self.num_endpoints() {
+            for dir in [UsbDirection::Out, UsbDirection::In] {
+                let ep_addr = EndpointAddress::from_parts(index, dir);
+                let mut ep = self.epidx(ep_addr).borrow_mut();
+                if let Some(expected) = ep.toggle_mismatch.take() {
+                    res = res.or(Some((ep_addr, expected)));
+                }
+            }
+        }
+        res
+    }
+
+    /// Returns `true` if the Endpoint is allocated and stalled.
+    pub(crate) fn ep_stalled(&self, ep_addr: EndpointAddress) -> bool {
+        self.ep_allocated(ep_addr) && self.epidx(ep_addr).borrow().stall
+    }
+
     pub(crate) fn stalled(&self, index: usize) -> bool {
         let addr_in = EndpointAddress::from_parts(index, UsbDirection::In);
         let addr_out = EndpointAddress::from_parts(index, UsbDirection::Out);
@@ -193,33 +875,70 @@ impl UsbBusImpl {
     }
 }
 
+/// Fails Device side endpoint reads and writes on the
+/// emulated bus, like flaky hardware does, see
+/// `UsbDeviceCtx::fault_injector()`.
+///
+/// The data stays in the endpoint buffer when a call
+/// fails. Both methods don't inject anything by default.
+pub trait FaultInjector: Send {
+    /// Called when the Device reads from endpoint `ep`.
+    /// Returns an error the read fails with, or `None`
+    /// to perform it.
+    fn read(&mut self, ep: EndpointAddress) -> Option<UsbError> {
+        let _ = ep;
+        None
+    }
+
+    /// Called when the Device writes `data` to endpoint
+    /// `ep`. Returns an error the write fails with, or
+    /// `None` to perform it.
+    fn write(&mut self, ep: EndpointAddress, data: &[u8]) -> Option<UsbError> {
+        let _ = (ep, data);
+        None
+    }
+}
+
+/// Returns `true` if `max_packet_size` is allowed for
+/// a Full Speed endpoint of type `ep_type`.
+fn full_speed_packet_size(ep_type: EndpointType, max_packet_size: u16) -> bool {
+    match ep_type {
+        EndpointType::Control | EndpointType::Bulk => {
+            matches!(max_packet_size, 8 | 16 | 32 | 64)
+        }
+        EndpointType::Interrupt => max_packet_size <= 64,
+        EndpointType::Isochronous { .. } => max_packet_size <= 1023,
+    }
+}
+
 /// Implements `usb-device` UsbBus on top
 /// of `UsbBusImpl`.
 ///
-/// Not thread-safe.
+/// `UsbBusImpl` is shared with `Device` behind a mutex,
+/// so the Device can be polled from another thread.
 pub struct EmulatedUsbBus {
-    usb_address: RefCell<u8>,
-    bus: Rc<RefCell<UsbBusImpl>>,
+    usb_address: AtomicU8,
+    bus: Arc<Mutex<UsbBusImpl>>,
 }
 
-unsafe impl Sync for EmulatedUsbBus {}
-
 impl EmulatedUsbBus {
-    pub(crate) fn new(bus: &Rc<RefCell<UsbBusImpl>>) -> Self {
+    pub(crate) fn new(bus: &Arc<Mutex<UsbBusImpl>>) -> Self {
         Self {
-            usb_address: RefCell::new(0),
+            usb_address: AtomicU8::new(0),
             bus: bus.clone(),
         }
     }
 
-    fn bus_ref(&self) -> &RefCell<UsbBusImpl> {
-        self.bus.as_ref()
+    /// Locks `UsbBusImpl`. The lock is not reentrant,
+    /// the guard must be dropped before locking again.
+    fn io(&self) -> MutexGuard<'_, UsbBusImpl> {
+        self.bus.lock()
     }
 
     /// Returns USB Address assigned to Device
     /// by the Host.
     pub fn get_address(&self) -> u8 {
-        *self.usb_address.borrow()
+        self.usb_address.load(Ordering::Relaxed)
     }
 }
 
@@ -230,28 +949,62 @@ impl usb_device::bus::UsbBus for EmulatedUsbBus {
         ep_addr: Option<EndpointAddress>,
         ep_type: EndpointType,
         max_packet_size: u16,
-        _interval: u8,
+        interval: u8,
     ) -> UsbDeviceResult<EndpointAddress> {
+        let (num_endpoints, buffer_size, strict_ep_size) = {
+            let io = self.io();
+            (io.num_endpoints(), io.buffer_size, io.strict_ep_size)
+        };
+        if strict_ep_size {
+            if !full_speed_packet_size(ep_type, max_packet_size) {
+                info!(
+                    "Bus: {:?} endpoint max packet size {} is not allowed",
+                    ep_type, max_packet_size
+                );
+                return Err(UsbError::Unsupported);
+            }
+            if max_packet_size as usize > buffer_size {
+                info!(
+                    "Bus: max packet size {} exceeds buffer size {}",
+                    max_packet_size, buffer_size
+                );
+                return Err(UsbError::EndpointMemoryOverflow);
+            }
+        }
         for index in ep_addr
             .map(|a| a.index()..a.index() + 1)
-            .unwrap_or(1..NUM_ENDPOINTS)
+            .unwrap_or(1..num_endpoints)
         {
+            if index >= num_endpoints {
+                break;
+            }
             let found_addr = EndpointAddress::from_parts(index, ep_dir);
-            let io = self.bus_ref().borrow();
+            let io = self.io();
             let mut ep = io.epidx(found_addr).borrow_mut();
 
             match ep.ep_type {
                 None => {
                     ep.ep_type = Some(ep_type);
+                    // the buffer holds at least one packet
+                    let size = io.buffer_size.max(max_packet_size as usize);
+                    ep.read = vec![0; size];
+                    ep.write = vec![0; size];
                 }
-                Some(t) if t != ep_type => {
+                Some(t) if ep_addr.is_some() => {
+                    info!(
+                        "Bus: EP {} {:?} is already allocated as {:?}",
+                        index, ep_dir, t
+                    );
+                    return Err(UsbError::InvalidEndpoint);
+                }
+                Some(_) => {
                     continue;
                 }
-                _ => {}
             };
 
             ep.stall = false;
             ep.max_size = max_packet_size as usize;
+            ep.interval = interval;
 
             return Ok(found_addr);
         }
@@ -267,8 +1020,11 @@ impl usb_device::bus::UsbBus for EmulatedUsbBus {
     }
 
     fn force_reset(&self) -> UsbDeviceResult<()> {
-        info!("Bus: force reset not supported");
-        Err(UsbError::Unsupported)
+        info!("Bus: force reset");
+        let io = self.io();
+        io.forced_resets.set(io.forced_resets.get() + 1);
+        io.request_event(BusEvent::Reset);
+        Ok(())
     }
 
     fn poll(&self) -> PollResult {
@@ -276,17 +1032,46 @@ impl usb_device::bus::UsbBus for EmulatedUsbBus {
         let mut mask_ep_out = 0;
         let mut mask_ep_setup = 0;
 
-        for index in 0..NUM_ENDPOINTS {
+        let io = self.io();
+
+        if let Some(event) = io.event.take() {
+            debug!("Bus: poll results: {:?}", event);
+            return match event {
+                BusEvent::Reset => PollResult::Reset,
+                BusEvent::Suspend => PollResult::Suspend,
+                BusEvent::Resume => PollResult::Resume,
+            };
+        }
+
+        // EP0 is always reported, other endpoints
+        // are reported one at a time unless
+        // the order is ascending.
+        let polled = match io.poll_order {
+            PollOrder::Ascending => None,
+            _ => {
+                let pending: Vec<usize> = (1..io.num_endpoints())
+                    .filter(|&index| io.has_events(index))
+                    .collect();
+                io.next_polled(&pending)
+            }
+        };
+
+        for index in 0..io.num_endpoints() {
+            if index != 0 && polled.is_some_and(|polled| polled != index) {
+                continue;
+            }
+
             let addrin = EndpointAddress::from_parts(index, UsbDirection::In);
             let addrout = EndpointAddress::from_parts(index, UsbDirection::Out);
             let bit = 1 << index;
 
-            let io = self.bus_ref().borrow();
-            let ep_out = io.epidx(addrout).borrow();
+            let mut ep_out = io.epidx(addrout).borrow_mut();
             let mut ep_in = io.epidx(addrin).borrow_mut();
 
-            if ep_in.write_done {
+            if ep_in.in_complete > 0 {
                 mask_in_complete |= bit;
+                ep_in.in_complete -= 1;
+                ep_in.stats.polls += 1;
             }
             if ep_out.read_ready | ep_in.read_ready {
                 mask_ep_out |= bit;
@@ -294,8 +1079,9 @@ impl usb_device::bus::UsbBus for EmulatedUsbBus {
             if ep_out.setup {
                 mask_ep_setup |= bit;
             }
-
-            ep_in.write_done = false;
+            if ep_out.read_ready | ep_out.setup {
+                ep_out.stats.polls += 1;
+            }
         }
 
         debug!(
@@ -315,8 +1101,31 @@ impl usb_device::bus::UsbBus for EmulatedUsbBus {
     }
 
     fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> UsbDeviceResult<usize> {
-        let io = self.bus_ref().borrow();
+        let io = self.io();
+        if let Some(err) = io.inject_fault(ep_addr, None) {
+            return Err(err);
+        }
         let mut ep = io.epidx(ep_addr).borrow_mut();
+
+        if ep.stall {
+            ep.stats.stalled += 1;
+        }
+
+        if ep.setup {
+            debug!(
+                "Bus: reading Setup from EP {} {:#?} {} bytes into {} byte buffer",
+                ep_addr.index(),
+                ep_addr.direction(),
+                ep.setup_len,
+                buf.len()
+            );
+            let res = ep.read_setup(buf);
+            if let Ok(len) = res {
+                ep.stats.packet(len);
+            }
+            return res;
+        }
+
         let len = min(buf.len(), min(ep.read_len, ep.max_size));
 
         debug!(
@@ -328,6 +1137,14 @@ impl usb_device::bus::UsbBus for EmulatedUsbBus {
         );
 
         if len == 0 {
+            if ep.read_ready && ep.read_len == 0 {
+                // zero-length packet
+                ep.read_ready = false;
+                ep.device_received();
+                ep.stats.packet(0);
+                return Ok(0);
+            }
+            ep.stats.would_block += 1;
             return Err(UsbError::WouldBlock);
         }
 
@@ -336,52 +1153,57 @@ impl usb_device::bus::UsbBus for EmulatedUsbBus {
         ep.read_len -= len;
         ep.read.copy_within(len.., 0);
 
-        if ep.read_len == 0 {
-            ep.setup = false;
-        }
-
         ep.read_ready = ep.read_len > 0;
+        ep.device_received();
+        ep.stats.packet(len);
 
         Ok(len)
     }
 
     fn reset(&self) {
         info!("Bus: reset");
-        todo!()
+        self.io().reset();
+        self.usb_address.store(0, Ordering::Relaxed);
     }
 
     fn resume(&self) {
         info!("Bus: resume");
-        todo!()
+        self.io().suspended.set(false);
     }
 
     fn suspend(&self) {
         info!("Bus: suspend");
-        todo!()
+        self.io().suspended.set(true);
     }
 
     fn set_device_address(&self, addr: u8) {
         debug!("Bus: set device address: {}", addr);
-        self.usb_address.replace(addr);
+        self.usb_address.store(addr, Ordering::Relaxed);
     }
 
     fn is_stalled(&self, ep_addr: EndpointAddress) -> bool {
-        let io = self.bus_ref().borrow();
+        let io = self.io();
         let ep = io.epidx(ep_addr).borrow();
         ep.stall
     }
 
     fn set_stalled(&self, ep_addr: EndpointAddress, stalled: bool) {
-        let io = self.bus_ref().borrow();
-        let mut ep = io.epidx(ep_addr).borrow_mut();
-        ep.stall = stalled;
+        let io = self.io();
+        io.set_stalled(ep_addr, stalled);
     }
 
     fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> UsbDeviceResult<usize> {
-        let io = self.bus_ref().borrow();
+        let io = self.io();
+        if let Some(err) = io.inject_fault(ep_addr, Some(buf)) {
+            return Err(err);
+        }
         let mut ep = io.epidx(ep_addr).borrow_mut();
-        let offset = ep.write_len;
-        let mut len = 0;
+        // Isochronous endpoint holds a single packet for the frame
+        let offset = if ep.is_iso() { 0 } else { ep.write_len };
+
+        if ep.stall {
+            ep.stats.stalled += 1;
+        }
 
         if buf.len() > ep.max_size {
             debug!(
@@ -389,17 +1211,25 @@ impl usb_device::bus::UsbBus for EmulatedUsbBus {
                 ep_addr.index(),
                 ep_addr.direction()
             );
+            if io.size_violation.get().is_none() {
+                io.size_violation
+                    .set(Some((ep_addr, ep.max_size, buf.len())));
+            }
             return Err(UsbError::BufferOverflow);
         }
 
-        for (i, e) in ep.write[offset..].iter_mut().enumerate() {
-            if i >= buf.len() {
-                break;
-            }
-            *e = buf[i];
-            len += 1;
+        if offset + buf.len() > ep.write.len() {
+            debug!(
+                "Bus: EP {} {:#?} buffer is full",
+                ep_addr.index(),
+                ep_addr.direction()
+            );
+            return Err(UsbError::WouldBlock);
         }
 
+        ep.write[offset..offset + buf.len()].clone_from_slice(buf);
+        let len = buf.len();
+
         debug!(
             "Bus: wrote to EP {} {:#?} {} bytes",
             ep_addr.index(),
@@ -407,8 +1237,17 @@ impl usb_device::bus::UsbBus for EmulatedUsbBus {
             len
         );
 
-        ep.write_len += len;
-        ep.write_done = false;
+        if offset == 0 {
+            ep.write_packets.clear();
+        }
+        let toggle = ep.device_sent();
+        ep.write_packets.push((len, toggle));
+        ep.stats.packet(len);
+        ep.write_len = offset + len;
+        ep.write_ready = true;
+        if ep.in_complete_mode == InCompleteMode::OnNextPoll {
+            ep.in_complete += 1;
+        }
         Ok(len)
     }
 }
@@ -0,0 +1,45 @@
+//! Support for polling several classes together, like
+//! a composite device does.
+//!
+
+use alloc::vec::Vec;
+
+use usb_device::class::UsbClass;
+
+use crate::bus::EmulatedUsbBus;
+
+/// One or more classes which are polled together
+/// by `usb-device`.
+///
+/// Implemented for every `UsbClass` and for `Composite`
+/// of such sets.
+pub trait UsbClassSet {
+    /// Appends classes of the set to `classes` in the
+    /// order they should be polled.
+    fn collect_classes<'s>(&'s mut self, classes: &mut Vec<&'s mut dyn UsbClass<EmulatedUsbBus>>);
+}
+
+impl<T: UsbClass<EmulatedUsbBus>> UsbClassSet for T {
+    fn collect_classes<'s>(&'s mut self, classes: &mut Vec<&'s mut dyn UsbClass<EmulatedUsbBus>>) {
+        classes.push(self);
+    }
+}
+
+/// Two classes of a composite device which share EP0.
+///
+/// Classes are polled in order, the first one gets
+/// Control requests first. More classes can be added
+/// by nesting, e.g. `Composite<A, Composite<B, C>>`.
+///
+/// # Example
+/// ```ignore
+/// type C<'c> = Composite<CdcClass<'c, EmulatedUsbBus>, HidClass<'c, EmulatedUsbBus>>;
+/// ```
+pub struct Composite<A, B>(pub A, pub B);
+
+impl<A: UsbClassSet, B: UsbClassSet> UsbClassSet for Composite<A, B> {
+    fn collect_classes<'s>(&'s mut self, classes: &mut Vec<&'s mut dyn UsbClass<EmulatedUsbBus>>) {
+        self.0.collect_classes(classes);
+        self.1.collect_classes(classes);
+    }
+}
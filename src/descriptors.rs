@@ -0,0 +1,303 @@
+//! Parsing of standard USB descriptors returned
+//! by GET_DESCRIPTOR requests.
+//!
+//! Must not use `usb-device` implementation to
+//! be able to test anything.
+//!
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::usbdata::parse_string_descriptor;
+use crate::{AnyResult, AnyUsbError};
+
+/// DEVICE descriptor type.
+pub const DEVICE: u8 = 1;
+/// CONFIGURATION descriptor type.
+pub const CONFIGURATION: u8 = 2;
+/// STRING descriptor type.
+pub const STRING: u8 = 3;
+/// INTERFACE descriptor type.
+pub const INTERFACE: u8 = 4;
+/// ENDPOINT descriptor type.
+pub const ENDPOINT: u8 = 5;
+/// DEVICE_QUALIFIER descriptor type.
+pub const DEVICE_QUALIFIER: u8 = 6;
+/// BOS descriptor type.
+pub const BOS: u8 = 15;
+
+/// Device descriptor.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct DeviceDescriptor {
+    /// bcdUSB, USB specification release number.
+    pub usb: u16,
+    /// bDeviceClass.
+    pub device_class: u8,
+    /// bDeviceSubClass.
+    pub device_sub_class: u8,
+    /// bDeviceProtocol.
+    pub device_protocol: u8,
+    /// bMaxPacketSize0, maximum packet size of EP0.
+    pub max_packet_size_0: u8,
+    /// idVendor.
+    pub vendor_id: u16,
+    /// idProduct.
+    pub product_id: u16,
+    /// bcdDevice, Device release number.
+    pub device_release: u16,
+    /// iManufacturer, String descriptor index.
+    pub manufacturer: u8,
+    /// iProduct, String descriptor index.
+    pub product: u8,
+    /// iSerialNumber, String descriptor index.
+    pub serial_number: u8,
+    /// bNumConfigurations.
+    pub num_configurations: u8,
+}
+
+/// Configuration descriptor header, without
+/// the descriptors which follow it.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct ConfigurationDescriptor {
+    /// wTotalLength, length of the Configuration
+    /// descriptor and all descriptors which follow it.
+    pub total_length: u16,
+    /// bNumInterfaces.
+    pub num_interfaces: u8,
+    /// bConfigurationValue.
+    pub configuration_value: u8,
+    /// iConfiguration, String descriptor index.
+    pub configuration: u8,
+    /// bmAttributes.
+    pub attributes: u8,
+    /// bMaxPower, in 2 mA units.
+    pub max_power: u8,
+}
+
+/// Interface descriptor.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct InterfaceDescriptor {
+    /// bInterfaceNumber.
+    pub interface_number: u8,
+    /// bAlternateSetting.
+    pub alternate_setting: u8,
+    /// bNumEndpoints, EP0 is not included.
+    pub num_endpoints: u8,
+    /// bInterfaceClass.
+    pub interface_class: u8,
+    /// bInterfaceSubClass.
+    pub interface_sub_class: u8,
+    /// bInterfaceProtocol.
+    pub interface_protocol: u8,
+    /// iInterface, String descriptor index.
+    pub interface: u8,
+}
+
+/// Endpoint descriptor.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct EndpointDescriptor {
+    /// bEndpointAddress, including the direction bit.
+    pub address: u8,
+    /// bmAttributes, transfer type in bits 0..1.
+    pub attributes: u8,
+    /// wMaxPacketSize.
+    pub max_packet_size: u16,
+    /// bInterval.
+    pub interval: u8,
+}
+
+/// Device Qualifier descriptor.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct DeviceQualifierDescriptor {
+    /// bcdUSB, USB specification release number.
+    pub usb: u16,
+    /// bDeviceClass.
+    pub device_class: u8,
+    /// bDeviceSubClass.
+    pub device_sub_class: u8,
+    /// bDeviceProtocol.
+    pub device_protocol: u8,
+    /// bMaxPacketSize0 for the other speed.
+    pub max_packet_size_0: u8,
+    /// bNumConfigurations for the other speed.
+    pub num_configurations: u8,
+}
+
+/// Binary Object Store descriptor header, without
+/// the Device Capability descriptors which follow it.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct BosDescriptor {
+    /// wTotalLength, length of the BOS descriptor
+    /// and all descriptors which follow it.
+    pub total_length: u16,
+    /// bNumDeviceCaps.
+    pub num_device_caps: u8,
+}
+
+/// A standard descriptor decoded by `parse()`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Descriptor {
+    /// Device descriptor.
+    Device(DeviceDescriptor),
+    /// Configuration descriptor.
+    Configuration(ConfigurationDescriptor),
+    /// String descriptor.
+    String(String),
+    /// String descriptor zero, LANGIDs supported
+    /// by the Device.
+    LanguageIds(Vec<u16>),
+    /// Interface descriptor.
+    Interface(InterfaceDescriptor),
+    /// Endpoint descriptor.
+    Endpoint(EndpointDescriptor),
+    /// Device Qualifier descriptor.
+    DeviceQualifier(DeviceQualifierDescriptor),
+    /// BOS descriptor.
+    Bos(BosDescriptor),
+    /// Descriptor of a type `parse()` doesn't decode.
+    Raw {
+        /// bDescriptorType.
+        b_descriptor_type: u8,
+        /// The whole descriptor, including bLength
+        /// and bDescriptorType.
+        data: Vec<u8>,
+    },
+}
+
+/// Parses the first descriptor in `bytes`.
+///
+/// Only bLength bytes are parsed, the rest, like Interface
+/// and Endpoint descriptors which follow a Configuration
+/// descriptor, is ignored. Descriptors of unknown types
+/// are returned as `Descriptor::Raw`.
+///
+/// Returns `AnyUsbError::InvalidDescriptorLength` if bLength
+/// is larger than `bytes` or too short for the type.
+///
+/// String descriptors are always decoded as text, use
+/// `parse_language_ids()` for String descriptor zero.
+pub fn parse(bytes: &[u8]) -> AnyResult<Descriptor> {
+    let descr = header(bytes)?;
+    let u16_at = |i: usize| u16::from_le_bytes([descr[i], descr[i + 1]]);
+
+    let res = match descr[1] {
+        DEVICE => {
+            check_length(descr, 18)?;
+            Descriptor::Device(DeviceDescriptor {
+                usb: u16_at(2),
+                device_class: descr[4],
+                device_sub_class: descr[5],
+                device_protocol: descr[6],
+                max_packet_size_0: descr[7],
+                vendor_id: u16_at(8),
+                product_id: u16_at(10),
+                device_release: u16_at(12),
+                manufacturer: descr[14],
+                product: descr[15],
+                serial_number: descr[16],
+                num_configurations: descr[17],
+            })
+        }
+        CONFIGURATION => {
+            check_length(descr, 9)?;
+            Descriptor::Configuration(ConfigurationDescriptor {
+                total_length: u16_at(2),
+                num_interfaces: descr[4],
+                configuration_value: descr[5],
+                configuration: descr[6],
+                attributes: descr[7],
+                max_power: descr[8],
+            })
+        }
+        STRING => Descriptor::String(parse_string_descriptor(descr)?),
+        INTERFACE => {
+            check_length(descr, 9)?;
+            Descriptor::Interface(InterfaceDescriptor {
+                interface_number: descr[2],
+                alternate_setting: descr[3],
+                num_endpoints: descr[4],
+                interface_class: descr[5],
+                interface_sub_class: descr[6],
+                interface_protocol: descr[7],
+                interface: descr[8],
+            })
+        }
+        ENDPOINT => {
+            check_length(descr, 7)?;
+            Descriptor::Endpoint(EndpointDescriptor {
+                address: descr[2],
+                attributes: descr[3],
+                max_packet_size: u16_at(4),
+                interval: descr[6],
+            })
+        }
+        DEVICE_QUALIFIER => {
+            check_length(descr, 10)?;
+            Descriptor::DeviceQualifier(DeviceQualifierDescriptor {
+                usb: u16_at(2),
+                device_class: descr[4],
+                device_sub_class: descr[5],
+                device_protocol: descr[6],
+                max_packet_size_0: descr[7],
+                num_configurations: descr[8],
+            })
+        }
+        BOS => {
+            check_length(descr, 5)?;
+            Descriptor::Bos(BosDescriptor {
+                total_length: u16_at(2),
+                num_device_caps: descr[4],
+            })
+        }
+        b_descriptor_type => Descriptor::Raw {
+            b_descriptor_type,
+            data: descr.to_vec(),
+        },
+    };
+    Ok(res)
+}
+
+/// Parses all descriptors in `bytes` with `parse()`,
+/// for example, a Configuration descriptor with all
+/// descriptors which follow it.
+pub fn parse_all(bytes: &[u8]) -> AnyResult<Vec<Descriptor>> {
+    let mut res = Vec::new();
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        let descr = header(rest)?;
+        res.push(parse(descr)?);
+        rest = &rest[descr.len()..];
+    }
+    Ok(res)
+}
+
+/// Parses String descriptor zero, which holds
+/// LANGIDs supported by the Device.
+pub fn parse_language_ids(bytes: &[u8]) -> AnyResult<Vec<u16>> {
+    let descr = header(bytes)?;
+    if descr[1] != STRING {
+        return Err(AnyUsbError::InvalidDescriptorType);
+    }
+    if !descr.len().is_multiple_of(2) {
+        return Err(AnyUsbError::InvalidStringLength);
+    }
+    Ok(descr[2..]
+        .chunks(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect())
+}
+
+/// Returns the first bLength bytes of `bytes`.
+fn header(bytes: &[u8]) -> AnyResult<&[u8]> {
+    match bytes {
+        [len, _, ..] if *len >= 2 && *len as usize <= bytes.len() => Ok(&bytes[..*len as usize]),
+        _ => Err(AnyUsbError::InvalidDescriptorLength),
+    }
+}
+
+/// Checks that `descr` has at least `min` bytes.
+fn check_length(descr: &[u8], min: usize) -> AnyResult<()> {
+    if descr.len() < min {
+        return Err(AnyUsbError::InvalidDescriptorLength);
+    }
+    Ok(())
+}
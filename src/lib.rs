@@ -1,3 +1,4 @@
+#![no_std]
 #![allow(clippy::test_attr_in_doctest)]
 #![warn(missing_docs)]
 //!
@@ -26,15 +27,14 @@
 //!
 //! * IN and OUT EP0 control transfers
 //! * Transfers on other endpoints (e.g. Interrupt)
+//! * Bulk transfers of any length
+//! * Isochronous transfers, one packet per frame
+//! * Bus reset, Suspend and Resume
 //!
 //! ### Not supported operations
 //!
 //! Almost everything else, including but not limited to:
 //!
-//! * Reset
-//! * Suspend and Resume
-//! * Bulk transfers
-//! * Iso transfers
 //! * ...
 //!
 //! ## License
@@ -98,16 +98,38 @@
 //! USB debug logging can be enabled, for example, by running tests with:
 //! `$ RUST_LOG=trace cargo test -- --nocapture`
 //!
+//! ## Features
+//!
+//! * `std` - enabled by default. Without it the library only
+//!   depends on `core` and `alloc`.
+//! * `initlog` - enabled by default, requires `std`. Initializes
+//!   `env_logger` logging in `UsbDeviceCtx::initialize()`.
+//! * `pcap` - requires `std`. Adds `Device::write_pcap()` which
+//!   exports recorded transactions in usbmon pcap format.
+//! * `arbitrary` - implements `arbitrary::Arbitrary` for
+//!   `SetupPacket` and `CtrRequestType` to generate random
+//!   Control requests in fuzz targets.
+//!
 
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
 use log::{debug, info, warn};
-use std::fmt::Debug;
-use std::marker::PhantomData;
-use std::{cell::RefCell, rc::Rc};
+use spin::Mutex;
 
 use usb_device::bus::UsbBusAllocator;
-use usb_device::class::UsbClass;
-use usb_device::device::{StringDescriptors, UsbDevice, UsbDeviceBuilder, UsbVidPid};
-use usb_device::endpoint::EndpointAddress;
+use usb_device::class::{ControlIn, ControlOut, UsbClass};
+use usb_device::device::{
+    StringDescriptors, UsbDevice, UsbDeviceBuilder, UsbDeviceState, UsbVidPid,
+};
+use usb_device::endpoint::{EndpointAddress, EndpointType};
 use usb_device::prelude::BuilderError;
 use usb_device::UsbDirection;
 
@@ -117,19 +139,36 @@ use bus::*;
 mod usbdata;
 use usbdata::*;
 
+mod composite;
+pub use composite::{Composite, UsbClassSet};
+
+pub mod descriptors;
+use descriptors::Descriptor;
+
+#[cfg(feature = "pcap")]
+mod pcap;
+
 /// Prelude
 pub mod prelude {
-    pub use crate::bus::EmulatedUsbBus;
-    pub use crate::usbdata::{CtrRequestType, SetupPacket};
-    pub use crate::{AnyResult, AnyUsbError, Device, HookAction, HookWhen, UsbDeviceCtx};
+    pub use crate::bus::{EmulatedUsbBus, EndpointInfo, EpStats, FaultInjector};
+    pub use crate::descriptors::Descriptor;
+    pub use crate::usbdata::{
+        CtrRequestType, DataToggle, DeviceStatus, EndpointStatus, Feature, FromControlData,
+        MsOsStringDescriptor, SetupPacket, WebUsbUrl,
+    };
+    pub use crate::{
+        AnyResult, AnyUsbError, Composite, ControlOutcome, DetailedError, DetailedResult, Device,
+        HookAction, HookWhen, InCompleteMode, PollOrder, SetupOutcome, UsbClassSet, UsbDeviceCtx,
+    };
 }
 
 const DEFAULT_EP0_SIZE: u8 = 8;
 const DEFAULT_ADDRESS: u8 = 5;
+const DEFAULT_MAX_TRANSFER_POLLS: usize = 128;
 
 /// Possible errors or other abnormal
 /// conditions.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum AnyUsbError {
     /// EP Stalled after Setup packet. Not
     /// necessarily an error, the Device
@@ -137,7 +176,8 @@ pub enum AnyUsbError {
     /// Next request should clear Stall for EP0.
     EP0Stalled,
     /// EP0 buffer is not empty after Setup
-    /// packet was consumed.
+    /// packet was consumed, not counting OUT data
+    /// sent together with it.
     EP0NotEmptyAfterSetup,
     /// Can't get how many bytes were written.
     /// Usually, this is some internal error.
@@ -152,10 +192,54 @@ pub enum AnyUsbError {
     /// No data or data limit reached.
     /// Usually, this is some internal error.
     EPReadFailed,
+    /// The Device didn't consume OUT data within
+    /// `UsbDeviceCtx::MAX_TRANSFER_POLLS` polls.
+    OutDataStuck {
+        /// OUT endpoint.
+        ep: EndpointAddress,
+        /// Number of bytes put into the endpoint buffer.
+        queued: usize,
+        /// Number of bytes the Device didn't consume,
+        /// including the ones not queued yet.
+        remaining: usize,
+        /// Number of polls performed during the transfer.
+        polls: usize,
+    },
+    /// Data sent by the Host at once, like a Setup packet,
+    /// doesn't fit into the emulated endpoint buffer.
+    HostBufferOverflow {
+        /// OUT endpoint.
+        ep: EndpointAddress,
+        /// Size of the endpoint buffer.
+        capacity: usize,
+        /// Number of bytes the Host tried to send.
+        attempted: usize,
+    },
+    /// The Device didn't consume the whole OUT data stage
+    /// of a Control transfer.
+    EP0DataRemaining {
+        /// Number of bytes the Device didn't consume.
+        remaining: usize,
+    },
+    /// The Device didn't consume all data sent to
+    /// a non-control OUT endpoint, see
+    /// `UsbDeviceCtx::STRICT_OUT_DATA`.
+    EPDataRemaining {
+        /// Number of bytes the Device didn't consume.
+        remaining: usize,
+    },
     /// Bad reply length for GET_STATUS control request.
     /// Length should be 2.
     /// Usually, this is some internal error.
     EP0BadGetStatusSize,
+    /// GET_STATUS response has bits set which are
+    /// reserved by the USB specification.
+    ReservedStatusBits {
+        /// Status returned by the Device.
+        status: u16,
+        /// Reserved bits which are set.
+        reserved: u16,
+    },
     /// Bad reply length for GET_CONFIGURATION control request.
     /// Length should be 1.
     /// Usually, this is some internal error.
@@ -167,6 +251,191 @@ pub enum AnyUsbError {
     /// SET_ADDRESS didn't work during Device setup.
     /// Usually, this is some internal error.
     SetAddressFailed,
+    /// Length of OUT data differs from `wLength`
+    /// of the Setup packet.
+    DataLengthMismatch,
+    /// Device sent more IN data than `wLength` of
+    /// the Setup packet allows.
+    ResponseTooLong {
+        /// `wLength` of the Setup packet.
+        expected: usize,
+        /// Number of bytes the Device sent so far.
+        got: usize,
+    },
+    /// Device sent more IN data on EP0 than the Host buffer
+    /// can store. `usb-device` truncates EP0 responses to
+    /// `wLength`, see also `ResponseTooLong`. Other endpoints
+    /// report `HostReadBufferTooSmall`.
+    EP0Overrun {
+        /// Size of the Host buffer.
+        buffer: usize,
+        /// Number of bytes the Device sent so far.
+        got: usize,
+    },
+    /// Device sent IN data on an endpoint other than EP0
+    /// which doesn't fit into the rest of the Host buffer.
+    HostReadBufferTooSmall {
+        /// IN endpoint the data was sent on.
+        ep: EndpointAddress,
+        /// Number of bytes needed to store the data read
+        /// so far and the pending data.
+        needed: usize,
+        /// Size of the Host buffer.
+        available: usize,
+    },
+    /// Device sent less IN data than it announced
+    /// in the response itself.
+    ResponseTooShort {
+        /// Length announced by the Device.
+        expected: usize,
+        /// Number of bytes the Device sent.
+        got: usize,
+    },
+    /// Device sent less data than requested
+    /// with `Device::ep_read_exact()`.
+    EPShortRead {
+        /// Requested length.
+        expected: usize,
+        /// Number of bytes the Device sent.
+        got: usize,
+    },
+    /// Device sent IN data which is a multiple of the
+    /// maximum packet size and shorter than `wLength`,
+    /// but didn't end it with a zero-length packet.
+    /// Only returned if `UsbDeviceCtx::STRICT_ZLP` is `true`.
+    MissingZlp,
+    /// bMaxPacketSize0 of the Device descriptor differs
+    /// from `UsbDeviceCtx::EP0_SIZE`.
+    EP0SizeMismatch {
+        /// bMaxPacketSize0 of the Device descriptor.
+        descriptor: u8,
+        /// `UsbDeviceCtx::EP0_SIZE`.
+        expected: u8,
+    },
+    /// Device sent IN data when no IN data stage
+    /// was expected.
+    UnexpectedInData,
+    /// `Device::ep0_collect()` was called without
+    /// a transfer queued with `Device::ep0_queue()`.
+    NoQueuedTransfer,
+    /// Device was expected to stall the request,
+    /// but it didn't.
+    NotStalled,
+    /// Device configuration after SET_CONFIGURATION
+    /// differs from the requested one.
+    ConfigurationMismatch {
+        /// Requested configuration value.
+        requested: u8,
+        /// Configuration value returned by GET_CONFIGURATION.
+        actual: u8,
+        /// `usb-device` state after the request.
+        state: UsbDeviceState,
+    },
+    /// `Device::setup()` was called for a Device which
+    /// is not in `Default` state.
+    AlreadySetUp {
+        /// `usb-device` state.
+        state: UsbDeviceState,
+    },
+    /// The Device is in `Default` state, but the emulated
+    /// bus still has an address assigned.
+    AddressNotCleared {
+        /// Address stored in the emulated bus.
+        address: u8,
+    },
+    /// Interface alternate setting after SET_INTERFACE
+    /// differs from the requested one.
+    AltSettingMismatch {
+        /// Requested alternate setting.
+        requested: u8,
+        /// Alternate setting returned by GET_INTERFACE.
+        actual: u8,
+    },
+    /// Endpoint was not allocated by `usb-device`
+    /// or the class.
+    EndpointNotAllocated(EndpointAddress),
+    /// bInterval of an Endpoint descriptor differs from
+    /// the interval the endpoint was allocated with.
+    EndpointIntervalMismatch {
+        /// Endpoint.
+        ep: EndpointAddress,
+        /// Interval the endpoint was allocated with.
+        allocated: u8,
+        /// bInterval of the Endpoint descriptor.
+        descriptor: u8,
+    },
+    /// Non-zero endpoint was used while the Device
+    /// is not in `Configured` state.
+    NotConfigured(EndpointAddress),
+    /// A transfer was attempted while the Device
+    /// is in `Suspend` state.
+    Suspended,
+    /// The class tried to write more than endpoint's
+    /// maximum packet size with a single `write()`.
+    PacketSizeViolation {
+        /// Endpoint the class wrote to.
+        ep: EndpointAddress,
+        /// Endpoint's maximum packet size.
+        max_size: usize,
+        /// Length of the write.
+        got: usize,
+    },
+    /// Transfer needed more polls than allowed.
+    PollLimitExceeded {
+        /// Maximum number of polls.
+        max_polls: usize,
+        /// Number of polls the transfer needed.
+        polls: usize,
+    },
+    /// A packet with a wrong data toggle was transferred.
+    DataToggleMismatch {
+        /// Endpoint the packet was transferred through.
+        ep: EndpointAddress,
+        /// Data toggle the receiver expected.
+        expected: DataToggle,
+    },
+    /// OUT endpoint still had unconsumed data after
+    /// `UsbDeviceCtx::MAX_NAK_RETRIES` retries.
+    EndpointBusy {
+        /// OUT endpoint.
+        ep: EndpointAddress,
+        /// Number of retries.
+        retries: usize,
+        /// Number of bytes the Device didn't consume.
+        remaining: usize,
+    },
+    /// Operation doesn't support Isochronous endpoints,
+    /// `Device::iso_*()` methods must be used instead.
+    IsoEndpoint,
+    /// `Device::iso_*()` method was used on an endpoint
+    /// which is not Isochronous.
+    NotIsoEndpoint,
+    /// Isochronous endpoint already transferred a packet
+    /// during the current frame.
+    IsoFrameUsed,
+    /// Isochronous packet is larger than the maximum
+    /// packet size of the endpoint.
+    IsoPacketTooLarge {
+        /// Maximum packet size of the endpoint.
+        max_size: usize,
+        /// Packet size.
+        got: usize,
+    },
+    /// The Device didn't produce IN data within
+    /// the given number of polls.
+    InDataTimeout {
+        /// Number of polls performed.
+        polls: usize,
+    },
+    /// The Device wrote a different number of packets
+    /// than expected by `Device::ep_read_packets()`.
+    PacketCountMismatch {
+        /// Expected number of packets.
+        expected: usize,
+        /// Number of packets received, including the
+        /// ones pending after the expected number.
+        got: usize,
+    },
     /// Descriptor length is larger than the size
     /// of data returned.
     InvalidDescriptorLength,
@@ -174,6 +443,9 @@ pub enum AnyUsbError {
     InvalidDescriptorType,
     /// String Descriptor length is odd.
     InvalidStringLength,
+    /// Microsoft OS String Descriptor doesn't have
+    /// "MSFT100" signature.
+    InvalidMsOsSignature,
     /// Wrapper for `BuilderError` of `usb-device`
     /// when `UsbDeviceBuilder` fails.
     UsbDeviceBuilder(BuilderError),
@@ -191,6 +463,37 @@ pub enum AnyUsbError {
     UserDefinedString(String),
 }
 
+/// `AnyUsbError` together with the transaction that
+/// caused it. Returned by `Device::*_detailed()` methods.
+#[derive(Debug, PartialEq, Clone)]
+pub struct DetailedError {
+    /// The error.
+    pub error: AnyUsbError,
+    /// Endpoint index of the transaction.
+    pub ep_index: usize,
+    /// Setup packet of the transaction, if it was sent.
+    pub setup: Option<SetupPacket>,
+}
+
+impl DetailedError {
+    fn new(error: AnyUsbError, ep_index: usize, setup: Option<SetupPacket>) -> Self {
+        DetailedError {
+            error,
+            ep_index,
+            setup,
+        }
+    }
+}
+
+impl From<DetailedError> for AnyUsbError {
+    fn from(value: DetailedError) -> Self {
+        value.error
+    }
+}
+
+/// Result for `Device::*_detailed()` methods.
+pub type DetailedResult<T> = core::result::Result<T, DetailedError>;
+
 /// Specifies why `Device::hook()` was called.
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum HookWhen {
@@ -205,10 +508,60 @@ pub enum HookWhen {
     /// After `poll()` once some portion of data is received from
     /// the device.
     DataIn(EndpointAddress),
+    /// After `poll()` once the Host sent a zero-length packet
+    /// during the status stage of a Device-to-host Control transfer.
+    StatusOut(EndpointAddress),
     /// After a manual `poll()` from `with_usb()`'s `case`.
     ManualPoll,
 }
 
+/// Order in which the emulated bus reports endpoints
+/// with pending events to `usb-device` on `poll()`.
+///
+/// `usb-device` services all endpoints reported by a single
+/// poll from the lowest to the highest index. Orders other
+/// than `Ascending` report one non-control endpoint per poll,
+/// so the class sees events in a different order. EP0
+/// is always reported immediately.
+///
+/// Note that an endpoint is reported until the class reads
+/// its data, so with `Descending` order an endpoint which
+/// is never read prevents lower endpoints from being reported.
+#[derive(Debug, PartialEq, Eq, Default, Clone, Copy)]
+pub enum PollOrder {
+    /// All endpoints are reported together and serviced
+    /// from the lowest to the highest index.
+    #[default]
+    Ascending,
+    /// The endpoint with the highest index
+    /// is reported first.
+    Descending,
+    /// A random endpoint is reported, the sequence
+    /// is the same for the same seed.
+    Shuffled(u64),
+}
+
+/// When the emulated bus considers a packet written by
+/// the Device to an IN endpoint transmitted and reports
+/// it to `usb-device`, which calls
+/// `UsbClass::endpoint_in_complete()`.
+///
+/// Completions are counted per packet and reported one
+/// per poll, so a class which writes N packets gets exactly
+/// N `endpoint_in_complete()` calls in either mode.
+#[derive(Debug, PartialEq, Eq, Default, Clone, Copy)]
+pub enum InCompleteMode {
+    /// A packet is complete when the Host retrieves it,
+    /// or when an Isochronous packet is dropped at the end
+    /// of a frame.
+    #[default]
+    OnHostRead,
+    /// A packet is complete on the next poll after the
+    /// Device writes it, regardless of whether the Host
+    /// retrieved it.
+    OnNextPoll,
+}
+
 /// Specifies what `Device::hook()`'s caller should
 /// do.
 #[derive(Debug, PartialEq, Eq, Default, Clone, Copy)]
@@ -224,8 +577,97 @@ pub enum HookAction {
     Stop,
 }
 
+/// How the Device reacted to a Setup packet sent
+/// with `Device::ep0_malformed_setup()`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SetupOutcome {
+    /// The Device read the packet and sent a response.
+    Accepted,
+    /// The Device read the packet and stalled EP0.
+    Stalled,
+    /// The Device read the packet and did nothing.
+    Ignored,
+    /// The Device didn't read the packet.
+    Wedged,
+}
+
+/// How the Device completed a Control transfer
+/// sent with `Device::control_in_outcome()`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ControlOutcome {
+    /// The Device accepted the request and sent data.
+    Data(Vec<u8>),
+    /// The Device accepted the request without data.
+    Empty,
+    /// The Device rejected or didn't handle the request
+    /// and stalled EP0.
+    Stalled,
+}
+
+/// A class without descriptors polled before and after the
+/// Device classes. It sees a Control request only if it's
+/// passed to the classes, and the classes before it didn't
+/// accept or reject it.
+#[derive(Default)]
+struct ControlProbe {
+    requests: usize,
+}
+
+impl UsbClass<EmulatedUsbBus> for ControlProbe {
+    fn control_in(&mut self, _xfer: ControlIn<EmulatedUsbBus>) {
+        self.requests += 1;
+    }
+
+    fn control_out(&mut self, _xfer: ControlOut<EmulatedUsbBus>) {
+        self.requests += 1;
+    }
+}
+
+/// EP0 Control transfer queued with `Device::ep0_queue()`.
+struct QueuedTransfer {
+    setup: [u8; 8],
+    data: Option<Vec<u8>>,
+    queued: usize,
+    polls: usize,
+}
+
+/// Returns `AnyUsbError::HostReadBufferTooSmall` for IN data of
+/// `needed` bytes on `ep` which doesn't fit into the Host buffer.
+fn host_buffer_too_small(ep: EndpointAddress, needed: usize, available: usize) -> AnyUsbError {
+    AnyUsbError::HostReadBufferTooSmall {
+        ep,
+        needed,
+        available,
+    }
+}
+
+/// Replaces response size errors of `Device::control_read_as()`
+/// with a request-specific error.
+fn bad_size(error: AnyUsbError) -> impl FnOnce(AnyUsbError) -> AnyUsbError {
+    move |e| match e {
+        AnyUsbError::ResponseTooShort { .. } => error,
+        e => e,
+    }
+}
+
+/// Returns GET_STATUS `status` if only `defined` bits are
+/// set, `AnyUsbError::ReservedStatusBits` otherwise.
+fn check_reserved_status(status: u16, defined: u16) -> core::result::Result<u16, AnyUsbError> {
+    let reserved = status & !defined;
+    if reserved != 0 {
+        return Err(AnyUsbError::ReservedStatusBits { status, reserved });
+    }
+    Ok(status)
+}
+
+/// Returns wTotalLength of a Configuration descriptor
+/// header, `None` if the header is too short.
+fn config_total_length(descr: &[u8]) -> Option<u16> {
+    Some(u16::from_le_bytes([*descr.get(2)?, *descr.get(3)?]))
+}
+
 /// Holds results for endpoint read/write operations
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct RWRes {
     /// If there was a read operation returns number of data bytes
     /// that were read.
@@ -234,11 +676,103 @@ pub struct RWRes {
     /// that were written.
     /// Setup packet is not included.
     pub wrote: Option<usize>,
+    /// Number of polls during OUT data stage that didn't
+    /// consume any data, including the one that ended the
+    /// data stage.
+    pub idle_polls: usize,
+    /// IN data stage was terminated by a zero-length
+    /// packet from the Device.
+    pub zlp: bool,
+    /// Status stage of a Control transfer completed:
+    /// the Device sent a zero-length packet for Host-to-device
+    /// transfers or accepted one from the Host for Device-to-host
+    /// transfers without a stall.
+    ///
+    /// Always `false` if there was no Setup packet or if the
+    /// status stage is skipped with `UsbDeviceCtx::SKIP_STATUS_STAGE`.
+    pub status_ok: bool,
+    /// Number of `poll()` calls during the transfer,
+    /// including the ones forced by `HookAction::ForcePoll`.
+    pub polls: usize,
+    /// Number of OUT data packets the data was split into
+    /// according to the endpoint maximum packet size.
+    /// Setup packet is not included.
+    pub packets_out: usize,
+    /// Number of IN data packets received from the Device,
+    /// including a zero-length packet.
+    pub packets_in: usize,
+    /// Stall that didn't fail the transfer and the poll
+    /// after which it was observed. This is a stall of
+    /// the status stage of a Device-to-host Control transfer,
+    /// other stalls are returned as errors.
+    pub stall: Option<HookWhen>,
+    /// The Device has IN data on the endpoint which wasn't
+    /// read because the transfer had no IN stage.
+    /// Only set by `Device::ep_raw_write()` without
+    /// a Setup packet.
+    pub in_pending: bool,
+    /// Number of OUT data bytes the Device didn't consume
+    /// when OUT data stage ended because polls didn't consume
+    /// any data.
+    pub remaining: usize,
 }
 
 impl RWRes {
     fn new(read: Option<usize>, wrote: Option<usize>) -> Self {
-        Self { read, wrote }
+        Self {
+            read,
+            wrote,
+            ..Default::default()
+        }
+    }
+
+    /// Number of data bytes that were read, `0` if there
+    /// was no read operation.
+    pub fn read_len(&self) -> usize {
+        self.read.unwrap_or(0)
+    }
+
+    /// Number of data bytes that were written, `0` if there
+    /// was no write operation.
+    pub fn wrote_len(&self) -> usize {
+        self.wrote.unwrap_or(0)
+    }
+}
+
+impl core::fmt::Display for RWRes {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match (self.wrote, self.read) {
+            (None, None) => write!(f, "no transfer"),
+            (Some(wrote), None) => write!(f, "wrote {} bytes", wrote),
+            (None, Some(read)) => write!(f, "read {} bytes", read),
+            (Some(wrote), Some(read)) => write!(f, "wrote {} bytes, read {} bytes", wrote, read),
+        }
+    }
+}
+
+/// A transaction recorded by `Device` when tracing
+/// is enabled with `Device::enable_trace()`.
+#[derive(Debug, Clone)]
+pub struct Transaction {
+    /// Endpoint index.
+    pub ep_index: usize,
+    /// Setup packet, if it was sent.
+    pub setup: Option<SetupPacket>,
+    /// Data sent to the Device.
+    pub data_out: Vec<u8>,
+    /// Data received from the Device.
+    pub data_in: Vec<u8>,
+    /// Transaction result.
+    pub result: AnyResult<RWRes>,
+}
+
+impl Transaction {
+    /// Returns `true` if the Device stalled the transaction.
+    pub fn stalled(&self) -> bool {
+        matches!(
+            self.result,
+            Err(AnyUsbError::EP0Stalled) | Err(AnyUsbError::EPStalled)
+        )
     }
 }
 
@@ -249,14 +783,15 @@ pub type AnyResult<T> = core::result::Result<T, AnyUsbError>;
 /// configuration values, initialization,
 /// and some customization.
 pub trait UsbDeviceCtx: Sized {
-    /// Class under the test.
+    /// Class under the test, or several classes of
+    /// a composite device combined with `Composite`.
     /// # Examples
     /// ```ignore
     /// type C<'c> = SimpleUsbClass;
     /// type C<'c> = ComplexUsbClass<'c, EmulatedUsbBus>;
+    /// type C<'c> = Composite<SimpleUsbClass, ComplexUsbClass<'c, EmulatedUsbBus>>;
     /// ```
-
-    type C<'c>: UsbClass<EmulatedUsbBus> + 'c;
+    type C<'c>: UsbClassSet + 'c;
 
     /// EP0 size used by `build_usb_device()` when creating
     /// `UsbDevice`.
@@ -271,6 +806,167 @@ pub trait UsbDeviceCtx: Sized {
     /// a non-zero address.
     const ADDRESS: u8 = DEFAULT_ADDRESS;
 
+    /// Maximum number of `poll()` calls `Device` performs
+    /// during a data stage of a single transaction, for each
+    /// direction. Default is 128.
+    ///
+    /// If the Device still consumes OUT data after this many
+    /// polls, the transaction fails with `AnyUsbError::OutDataStuck`.
+    /// If it still produces full-size IN packets, the transaction
+    /// fails with `AnyUsbError::EPReadFailed`.
+    const MAX_TRANSFER_POLLS: usize = DEFAULT_MAX_TRANSFER_POLLS;
+
+    /// Number of consecutive polls that didn't consume any
+    /// OUT data `Device` tolerates before it considers the
+    /// data stage finished. Default is 0, the data stage
+    /// ends after the first such poll.
+    ///
+    /// Useful for classes which consume data only after
+    /// some internal state change, which, for example,
+    /// can be triggered from `hook()`. These polls are
+    /// counted against `MAX_TRANSFER_POLLS`.
+    const MAX_IDLE_POLLS: usize = 0;
+
+    /// Number of consecutive polls without IN data `Device`
+    /// tolerates before it considers the IN data stage
+    /// finished. Default is 0, the data stage ends after
+    /// the first such poll.
+    ///
+    /// Useful for classes which stage IN data only after
+    /// a few polls. These polls are counted against
+    /// `MAX_TRANSFER_POLLS`.
+    const MAX_IN_EMPTY_POLLS: usize = 0;
+
+    /// If `true`, OUT data stage of a Control transfer is
+    /// put into EP0 buffer together with the Setup packet
+    /// before the first `poll()`, like some USB controllers
+    /// do. Otherwise, the data is sent after the Device
+    /// polls the Setup packet. Default is `false`.
+    const SETUP_WITH_DATA: bool = false;
+
+    /// If `true`, OUT data is sent in packets of the
+    /// endpoint's maximum packet size, the next packet
+    /// is sent after the Device consumes the previous one,
+    /// like a real Host does. Otherwise, all the data is
+    /// put into the endpoint buffer at once. Default is `false`.
+    const CHUNKED_DATA: bool = false;
+
+    /// If `true`, IN data stage of a Control transfer fails
+    /// with `AnyUsbError::ResponseTooLong` when the Device
+    /// sends more data than `wLength` of the Setup packet.
+    /// Default is `true`.
+    ///
+    /// Can be disabled for tests which check how the Device
+    /// behaves in this case.
+    const STRICT_RESPONSE_LENGTH: bool = true;
+
+    /// If `true`, IN data stage of a Control transfer fails
+    /// with `AnyUsbError::MissingZlp` when the Device sends
+    /// less data than `wLength`, the data is a multiple
+    /// of the maximum packet size, and the Device doesn't
+    /// send a zero-length packet after it. Default is `false`,
+    /// the data stage ends once the Device stops sending data.
+    const STRICT_ZLP: bool = false;
+
+    /// If `true`, Control transfers end after the data stage
+    /// without the status stage, like a Host which never
+    /// completes the transfer. Zero-length status packet
+    /// the Device sends for Host-to-device transfers stays
+    /// in EP0 buffer, `Device::ep0_abort_with()` can be used
+    /// to discard it. Default is `false`.
+    ///
+    /// Note that SET_ADDRESS request takes effect only after
+    /// the status stage, so Device setup in `with_usb()` fails
+    /// unless `skip_setup()` returns `true`.
+    const SKIP_STATUS_STAGE: bool = false;
+
+    /// If `true`, operations on non-zero endpoints fail with
+    /// `AnyUsbError::NotConfigured` unless the Device is in
+    /// `Configured` state, like on a real Host. Default is `true`.
+    ///
+    /// Can be disabled for tests which check how the class
+    /// behaves before SET_CONFIGURATION.
+    const REQUIRE_CONFIGURED: bool = true;
+
+    /// If `true`, a transfer fails with
+    /// `AnyUsbError::PacketSizeViolation` if, since the previous
+    /// transfer, the class tried to write more than endpoint's
+    /// maximum packet size with a single `write()`. Such writes
+    /// fail with `UsbError::BufferOverflow`, which classes often
+    /// ignore. Default is `false`.
+    ///
+    /// Reads never return more than the maximum packet size.
+    const STRICT_PACKET_SIZE: bool = false;
+
+    /// If `true`, a transfer fails with
+    /// `AnyUsbError::DataToggleMismatch` if, since the previous
+    /// transfer, a packet was transferred with a data toggle
+    /// its receiver didn't expect. Default is `false`, such
+    /// packets are delivered anyway.
+    ///
+    /// The Host resets its data toggles when it sends standard
+    /// requests which reset them, the Device side is reset
+    /// when `usb-device` clears an endpoint halt.
+    const STRICT_DATA_TOGGLE: bool = false;
+
+    /// If `true`, new data is not put into an OUT endpoint
+    /// buffer while it has data the Device didn't consume, like
+    /// a Device which NAKs OUT packets. The Host polls the Device
+    /// and retries up to `MAX_NAK_RETRIES` times, then the transfer
+    /// fails with `AnyUsbError::EndpointBusy`. Otherwise, the data
+    /// is appended to the buffer. Default is `false`.
+    ///
+    /// Doesn't apply to Setup packets and data stages of
+    /// Control transfers.
+    const OUT_NAK: bool = false;
+
+    /// Number of retries of a NAKed OUT transfer when
+    /// `OUT_NAK` is enabled, each retry polls the Device
+    /// once. Default is 0.
+    const MAX_NAK_RETRIES: usize = 0;
+
+    /// If `true`, a transfer without a Setup packet fails
+    /// with `AnyUsbError::EPDataRemaining` when the Device
+    /// didn't consume all OUT data sent to the endpoint.
+    /// Otherwise, the data is left in the endpoint buffer
+    /// and `RWRes::remaining` is set. Default is `false`.
+    const STRICT_OUT_DATA: bool = false;
+
+    /// Order in which the emulated bus reports endpoints
+    /// with pending events on `poll()`. Default is
+    /// `PollOrder::Ascending`.
+    ///
+    /// Can be changed to catch class bugs which depend
+    /// on the order endpoints are serviced in.
+    const POLL_ORDER: PollOrder = PollOrder::Ascending;
+
+    /// When packets written by the Device to IN endpoints
+    /// are reported as transmitted. Default is
+    /// `InCompleteMode::OnHostRead`.
+    const IN_COMPLETE: InCompleteMode = InCompleteMode::OnHostRead;
+
+    /// Number of endpoints in each direction the emulated
+    /// bus provides, including EP0. Values above 16, the maximum
+    /// allowed by USB, are limited to 16. Default is 8.
+    const NUM_ENDPOINTS: usize = 8;
+
+    /// Size of each emulated endpoint buffer in bytes, at
+    /// least one maximum size packet. OUT data that doesn't fit
+    /// is queued as the Device reads it, IN data written by the
+    /// Device that doesn't fit fails with `WouldBlock`.
+    /// Default is 1024.
+    const EP_BUFFER_SIZE: usize = 1024;
+
+    /// If `true`, endpoint allocation fails with
+    /// `UsbError::Unsupported` if the maximum packet size is not
+    /// allowed for the endpoint type at Full Speed: 8, 16, 32 or
+    /// 64 bytes for Control and Bulk, up to 64 for Interrupt and
+    /// up to 1023 for Isochronous endpoints. It fails with
+    /// `UsbError::EndpointMemoryOverflow` if the packet doesn't
+    /// fit into `EP_BUFFER_SIZE`. Otherwise, any size is
+    /// accepted. Default is `false`.
+    const STRICT_EP_SIZE: bool = false;
+
     /// Create `UsbClass` object.
     /// # Example
     /// ```
@@ -332,8 +1028,40 @@ pub trait UsbDeviceCtx: Sized {
         false
     }
 
+    /// Optional. Returns Vendor ID and Product ID used by
+    /// the default `build_usb_device()`.
+    ///
+    /// Default implementation returns `UsbVidPid(0x1234, 0x5678)`.
+    fn vid_pid(&mut self) -> UsbVidPid {
+        UsbVidPid(0x1234, 0x5678)
+    }
+
+    /// Optional. Returns string descriptors used by
+    /// the default `build_usb_device()`.
+    ///
+    /// Default implementation returns "TestManufacturer",
+    /// "TestProduct" and "TestSerial" strings in English (US).
+    fn string_descriptors(&mut self) -> StringDescriptors<'static> {
+        StringDescriptors::default()
+            .manufacturer("TestManufacturer")
+            .product("TestProduct")
+            .serial_number("TestSerial")
+    }
+
+    /// Optional. Returns `FaultInjector` which can fail Device
+    /// side endpoint reads and writes on the emulated bus, for
+    /// example, with `UsbError::WouldBlock` to check how the
+    /// class retries. It's used during the Device setup too.
+    ///
+    /// Default implementation returns `None`.
+    fn fault_injector(&mut self) -> Option<Box<dyn FaultInjector>> {
+        None
+    }
+
     /// Optional. Implementation overrides the creation of `UsbDevice`
     /// if the default implementation needs changing.
+    /// `vid_pid()` and `string_descriptors()` can be used
+    /// instead for common customizations.
     /// # Example
     /// ```
     /// # use usb_device::prelude::*;
@@ -354,11 +1082,8 @@ pub trait UsbDeviceCtx: Sized {
         &mut self,
         alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
     ) -> AnyResult<UsbDevice<'a, EmulatedUsbBus>> {
-        let usb_dev = UsbDeviceBuilder::new(alloc, UsbVidPid(0x1234, 0x5678))
-            .strings(&[StringDescriptors::default()
-                .manufacturer("TestManufacturer")
-                .product("TestProduct")
-                .serial_number("TestSerial")])
+        let usb_dev = UsbDeviceBuilder::new(alloc, self.vid_pid())
+            .strings(&[self.string_descriptors()])
             .map_err(AnyUsbError::UsbDeviceBuilder)?
             .device_release(0x0200)
             .self_powered(true)
@@ -417,8 +1142,15 @@ pub trait UsbDeviceCtx: Sized {
 
         warn!("#### with_usb start");
 
-        let stio: UsbBusImpl = UsbBusImpl::new();
-        let io = Rc::new(RefCell::new(stio));
+        let stio: UsbBusImpl = UsbBusImpl::new(
+            Self::POLL_ORDER,
+            Self::NUM_ENDPOINTS,
+            Self::EP_BUFFER_SIZE,
+            Self::STRICT_EP_SIZE,
+            Self::IN_COMPLETE,
+        )
+        .with_fault_injector(self.fault_injector());
+        let io = Arc::new(Mutex::new(stio));
         let bus = EmulatedUsbBus::new(&io);
 
         let alloc: usb_device::bus::UsbBusAllocator<EmulatedUsbBus> = UsbBusAllocator::new(bus);
@@ -450,25 +1182,39 @@ pub trait UsbDeviceCtx: Sized {
 /// USB bus.
 pub struct Device<'a, C, X>
 where
-    C: UsbClass<EmulatedUsbBus>,
+    C: UsbClassSet,
     X: UsbDeviceCtx<C<'a> = C>,
 {
     ctx: X,
-    usb: &'a RefCell<UsbBusImpl>,
+    usb: &'a Mutex<UsbBusImpl>,
     dev: UsbDevice<'a, EmulatedUsbBus>,
+    trace: Option<Vec<Transaction>>,
+    polls: usize,
+    queued: Option<QueuedTransfer>,
+    frame: u32,
+    iso_used: Vec<EndpointAddress>,
+    configured: bool,
+    control_handled: bool,
     _cls: PhantomData<C>,
 }
 
 impl<'a, C, X> Device<'a, C, X>
 where
-    C: UsbClass<EmulatedUsbBus>,
+    C: UsbClassSet,
     X: UsbDeviceCtx<C<'a> = C>,
 {
-    fn new(usb: &'a RefCell<UsbBusImpl>, ctx: X, dev: UsbDevice<'a, EmulatedUsbBus>) -> Self {
+    fn new(usb: &'a Mutex<UsbBusImpl>, ctx: X, dev: UsbDevice<'a, EmulatedUsbBus>) -> Self {
         Device {
             usb,
             ctx,
             dev,
+            trace: None,
+            polls: 0,
+            queued: None,
+            frame: 0,
+            iso_used: Vec::new(),
+            configured: false,
+            control_handled: false,
             _cls: PhantomData,
         }
     }
@@ -478,10 +1224,256 @@ where
         &mut self.dev
     }
 
+    /// Returns USB Address assigned to the Device by the Host,
+    /// 0 before SET_ADDRESS.
+    pub fn assigned_address(&self) -> u8 {
+        self.dev.bus().get_address()
+    }
+
+    /// Checks that the Device in `Default` state, for example,
+    /// after a bus reset, has no address assigned.
+    ///
+    /// Returns `AnyUsbError::AddressNotCleared` otherwise.
+    /// Does nothing in other states.
+    pub fn check_default_address(&self) -> core::result::Result<(), AnyUsbError> {
+        let address = self.assigned_address();
+        if self.dev.state() == UsbDeviceState::Default && address != 0 {
+            return Err(AnyUsbError::AddressNotCleared { address });
+        }
+        Ok(())
+    }
+
+    /// Stalls endpoint `ep_index` in direction `dir` directly
+    /// on the emulated bus, without polling the Device.
+    ///
+    /// This models a stall condition set by the Device side,
+    /// for example, by the hardware, not a Host action: a real
+    /// Host can only halt an endpoint with SET_FEATURE. Allows
+    /// testing how the class notices and clears the condition.
+    /// Isochronous endpoints can't be stalled.
+    ///
+    /// Returns `AnyUsbError::EndpointNotAllocated` if the
+    /// endpoint is not allocated.
+    pub fn force_endpoint_stall(
+        &mut self,
+        ep_index: usize,
+        dir: UsbDirection,
+    ) -> core::result::Result<(), AnyUsbError> {
+        self.force_stall(EndpointAddress::from_parts(ep_index, dir), true)
+    }
+
+    /// Sets or clears stall condition of endpoint `ep` directly
+    /// on the emulated bus, without polling the Device, the same
+    /// as `force_endpoint_stall()` does.
+    ///
+    /// Clearing the condition also resets Device side data
+    /// toggle, the same as the Device does when it clears a halt.
+    ///
+    /// Returns `AnyUsbError::EndpointNotAllocated` if the
+    /// endpoint is not allocated.
+    pub fn force_stall(
+        &mut self,
+        ep: EndpointAddress,
+        stalled: bool,
+    ) -> core::result::Result<(), AnyUsbError> {
+        if !self.usb.lock().ep_allocated(ep) {
+            return Err(AnyUsbError::EndpointNotAllocated(ep));
+        }
+        info!(
+            "#### EP {} {:?} forced stall: {}",
+            ep.index(),
+            ep.direction(),
+            stalled
+        );
+        self.usb.lock().set_stalled(ep, stalled);
+        Ok(())
+    }
+
+    /// Returns transfer statistics of endpoint `ep`
+    /// collected since the Device was created or since
+    /// the last bus reset or `reset_stats()`.
+    pub fn ep_stats(&self, ep: EndpointAddress) -> EpStats {
+        self.usb.lock().ep_stats(ep)
+    }
+
+    /// Returns the total number of bytes the Device read from
+    /// or wrote to endpoint `ep_index` in direction `dir`
+    /// since the Device was created or since the last bus
+    /// reset or `reset_stats()`. Same as `EpStats::bytes`.
+    pub fn endpoint_bytes_transferred(&self, ep_index: usize, dir: UsbDirection) -> usize {
+        let ep = EndpointAddress::from_parts(ep_index, dir);
+        self.ep_stats(ep).bytes
+    }
+
+    /// Resets transfer statistics of all endpoints.
+    pub fn reset_stats(&mut self) {
+        self.usb.lock().reset_stats();
+    }
+
+    /// Returns data toggle of the next packet endpoint
+    /// `ep_index` in direction `dir` sends or expects to
+    /// receive, as tracked by the Device side of the
+    /// emulated bus. Returns `DataToggle::Data0` for
+    /// endpoints which are not allocated.
+    pub fn data_toggle(&self, ep_index: usize, dir: UsbDirection) -> DataToggle {
+        let ep = EndpointAddress::from_parts(ep_index, dir);
+        let usb = self.usb.lock();
+        (usb.ep_allocated(ep) && usb.data_toggle(ep)).into()
+    }
+
+    /// Returns `true` if endpoint `ep_index` in direction
+    /// `dir` is stalled on the emulated bus, no requests are
+    /// sent to the Device.
+    ///
+    /// Unlike `endpoint_get_status()`, works in any Device
+    /// state and doesn't poll. Returns `false` for endpoints
+    /// which are not allocated.
+    pub fn is_endpoint_stalled(&self, ep_index: usize, dir: UsbDirection) -> bool {
+        let ep = EndpointAddress::from_parts(ep_index, dir);
+        self.usb.lock().ep_stalled(ep)
+    }
+
+    /// Returns data the Device has written to IN endpoint
+    /// `ep_index` and the Host didn't read yet. The data
+    /// is not consumed. Returns an empty buffer for endpoints
+    /// which are not allocated.
+    ///
+    /// This is a white-box helper for inspecting the emulated
+    /// bus state, a real Host can't do this.
+    pub fn peek_in_buffer(&self, ep_index: usize) -> Vec<u8> {
+        let in_ep = EndpointAddress::from_parts(ep_index, UsbDirection::In);
+        let usb = self.usb.lock();
+        if !usb.ep_allocated(in_ep) {
+            return Vec::new();
+        }
+        usb.peek_write(in_ep)
+    }
+
+    /// Returns the number of bytes in OUT endpoint `ep_index`
+    /// buffer which the Device didn't read yet. The data
+    /// is not consumed. Returns 0 for endpoints which are
+    /// not allocated.
+    ///
+    /// This is a white-box helper for inspecting the emulated
+    /// bus state, a real Host can't do this.
+    pub fn peek_out_buffer_len(&self, ep_index: usize) -> usize {
+        let out_ep = EndpointAddress::from_parts(ep_index, UsbDirection::Out);
+        let usb = self.usb.lock();
+        if !usb.ep_allocated(out_ep) {
+            return 0;
+        }
+        usb.ep_data_len(out_ep)
+    }
+
+    /// Returns data in OUT endpoint `ep_index` buffer which
+    /// the Device didn't read yet. The data is not consumed.
+    /// Returns an empty buffer for endpoints which are not
+    /// allocated.
+    ///
+    /// This is a white-box helper for inspecting the emulated
+    /// bus state, a real Host can't do this.
+    pub fn peek_out_buffer(&self, ep_index: usize) -> Vec<u8> {
+        let out_ep = EndpointAddress::from_parts(ep_index, UsbDirection::Out);
+        let usb = self.usb.lock();
+        if !usb.ep_allocated(out_ep) {
+            return Vec::new();
+        }
+        usb.peek_read(out_ep)
+    }
+
+    /// Returns the size of endpoint `ep` buffer on the emulated
+    /// bus, `UsbDeviceCtx::EP_BUFFER_SIZE` or the maximum packet
+    /// size if it's larger. Buffers are allocated together with
+    /// the endpoint, returns 0 for endpoints which are not
+    /// allocated.
+    ///
+    /// This is a white-box helper for inspecting the emulated
+    /// bus state, a real Host can't do this.
+    pub fn ep_buffer_size(&self, ep: EndpointAddress) -> usize {
+        let usb = self.usb.lock();
+        if !usb.ep_allocated(ep) {
+            return 0;
+        }
+        usb.ep_buffer_size(ep)
+    }
+
+    /// Returns endpoints allocated by `usb-device` and the class
+    /// with their types and maximum packet sizes, including EP0.
+    pub fn allocated_endpoints(&self) -> Vec<(EndpointAddress, EndpointType, u16)> {
+        self.usb
+            .lock()
+            .allocated_endpoints()
+            .into_iter()
+            .map(|ep| (ep.address, ep.ep_type, ep.max_packet_size))
+            .collect()
+    }
+
+    /// Returns endpoints allocated by the Class,
+    /// ordered by index, OUT before IN. Unlike
+    /// `allocated_endpoints()`, EP0 is not included.
+    pub fn endpoints(&self) -> Vec<EndpointInfo> {
+        let mut eps = self.usb.lock().allocated_endpoints();
+        eps.retain(|ep| ep.address.index() != 0);
+        eps
+    }
+
+    /// Start recording transactions performed on the Device.
+    ///
+    /// Recorded transactions can be retrieved with `take_trace()`.
+    pub fn enable_trace(&mut self) {
+        if self.trace.is_none() {
+            self.trace = Some(Vec::new());
+        }
+    }
+
+    /// Returns transactions recorded since tracing was enabled
+    /// or since the last call to `take_trace()`.
+    ///
+    /// Returns an empty `Vec` if tracing is not enabled.
+    pub fn take_trace(&mut self) -> Vec<Transaction> {
+        self.trace.as_mut().map(core::mem::take).unwrap_or_default()
+    }
+
+    /// Writes transactions recorded since tracing was enabled
+    /// or since the last call to `take_trace()` to `w` as a pcap
+    /// stream with Linux usbmon link type (`DLT_USB_LINUX_MMAPPED`),
+    /// which can be opened with Wireshark.
+    ///
+    /// Every transaction is written as URB Submit and URB Complete
+    /// records with synthetic timestamps.
+    #[cfg(feature = "pcap")]
+    pub fn write_pcap<W: std::io::Write>(&self, w: &mut W) -> std::io::Result<()> {
+        let endpoints: Vec<pcap::PcapEndpoint> = self
+            .allocated_endpoints()
+            .into_iter()
+            .map(|(addr, ep_type, _)| pcap::PcapEndpoint {
+                index: addr.index(),
+                ep_type,
+            })
+            .collect();
+
+        pcap::write_pcap(
+            w,
+            self.trace.as_deref().unwrap_or_default(),
+            self.assigned_address(),
+            &endpoints,
+        )
+    }
+
     fn do_poll(&mut self, d: &mut C, when: HookWhen) -> bool {
         let mut res;
         loop {
-            res = self.dev.poll(&mut [d]);
+            let mut first = ControlProbe::default();
+            let mut last = ControlProbe::default();
+            let mut classes: Vec<&mut dyn UsbClass<EmulatedUsbBus>> = vec![&mut first];
+            d.collect_classes(&mut classes);
+            classes.push(&mut last);
+            res = self.dev.poll(&mut classes);
+            if first.requests > 0 {
+                self.control_handled = last.requests == 0;
+            }
+            self.polls += 1;
+            self.update_configured();
             match self.ctx.hook(d, when) {
                 HookAction::Default => return res,
                 HookAction::ForcePoll => continue,
@@ -490,6 +1482,31 @@ where
         }
     }
 
+    /// Resets non-control endpoints when the Device
+    /// leaves `Configured` state.
+    fn update_configured(&mut self) {
+        let configured = self.dev.state() == UsbDeviceState::Configured;
+        if self.configured && !configured {
+            debug!("#### Device is not configured anymore, reset endpoints");
+            self.usb.lock().reset_endpoints();
+        }
+        self.configured = configured;
+    }
+
+    /// Returns `true` if a class accepted or rejected the last
+    /// Control request `usb-device` passed to the classes, and
+    /// `false` if the request fell through all of them, for
+    /// example, to be stalled by `usb-device` as unsupported.
+    ///
+    /// `usb-device` passes every request to the classes before
+    /// handling standard requests itself, so standard requests
+    /// handled by `usb-device` also return `false`, as well as
+    /// requests a class sees, but neither accepts nor rejects.
+    /// Returns `false` before the first Control request.
+    pub fn last_control_handled(&self) -> bool {
+        self.control_handled
+    }
+
     /// Call `usb-device` poll().
     ///
     /// Most `Device` operations call poll() automatically
@@ -503,12 +1520,82 @@ where
         self.do_poll(d, HookWhen::ManualPoll)
     }
 
+    /// Call `usb-device` poll() `n` times like `poll()` does.
+    ///
+    /// Returns the number of polls which returned `true`.
+    /// Note that `usb-device` calls `UsbClass::poll()` only
+    /// if the emulated bus has events to report.
+    pub fn poll_n(&mut self, d: &mut C, n: usize) -> usize {
+        (0..n)
+            .filter(|_| self.do_poll(d, HookWhen::ManualPoll))
+            .count()
+    }
+
+    /// Suspend the bus like a Host does when it stops
+    /// sending Start-of-Frame packets, and poll the Device
+    /// once. `usb-device` enters `Suspend` state.
+    ///
+    /// Transfers fail with `AnyUsbError::Suspended` until
+    /// the bus is resumed with `resume()`.
+    pub fn suspend(&mut self, d: &mut C) {
+        info!("#### Bus suspend");
+        self.usb.lock().request_event(BusEvent::Suspend);
+        self.do_poll(d, HookWhen::ManualPoll);
+    }
+
+    /// Resume the suspended bus and poll the Device once.
+    /// `usb-device` returns to the state it was in before
+    /// it was suspended.
+    pub fn resume(&mut self, d: &mut C) {
+        info!("#### Bus resume");
+        self.usb.lock().request_event(BusEvent::Resume);
+        self.do_poll(d, HookWhen::ManualPoll);
+    }
+
+    /// Returns `true` if `usb-device` suspended
+    /// the emulated bus.
+    pub fn is_suspended(&self) -> bool {
+        self.usb.lock().suspended()
+    }
+
+    /// Reset the bus like a Host does before enumeration
+    /// and poll the Device once to process the reset.
+    ///
+    /// Data in all endpoint buffers is discarded, stall
+    /// conditions, data toggles and transfer statistics are
+    /// cleared, and the Device address is set to 0. `usb-device` returns
+    /// to `Default` state and resets the classes, the
+    /// Device can be enumerated again with `setup()`.
+    pub fn bus_reset(&mut self, d: &mut C) {
+        info!("#### Bus reset");
+        self.queued = None;
+        self.usb.lock().request_event(BusEvent::Reset);
+        self.do_poll(d, HookWhen::ManualPoll);
+    }
+
+    /// Returns the number of times the Device requested a bus
+    /// reset with `UsbDevice::force_reset()`.
+    ///
+    /// The reset is processed on the next poll the same way
+    /// as `bus_reset()` does it.
+    pub fn forced_resets(&self) -> usize {
+        self.usb.lock().forced_resets()
+    }
+
     /// Perform EP0 Control transfer. `setup` is `SetupPacket`.
     /// If transfer is Host-to-device and
     /// `data` is `Some`, then it's sent after the Setup packet
     /// and Device can receive it as a payload. For Device-to-host
     /// transfers `data` should be `None` and `out` must have
     /// enough space to store the response.
+    ///
+    /// Returns `AnyUsbError::DataLengthMismatch` if `data` length
+    /// of a Host-to-device transfer differs from the Setup packet's
+    /// `wLength`, `None` counts as zero-length data.
+    /// `ep0_unchecked()` or `ep_raw()` can be used to send such
+    /// transfers anyway. Note that `usb-device` passes the request to the
+    /// class only after it receives `wLength` bytes, the rest of
+    /// the data stage can be sent later with `ep_raw()`.
     pub fn ep0(
         &mut self,
         d: &mut C,
@@ -516,129 +1603,1473 @@ where
         data: Option<&[u8]>,
         out: &mut [u8],
     ) -> core::result::Result<RWRes, AnyUsbError> {
-        let setup_bytes: [u8; 8] = setup.into();
-        self.ep_raw(d, 0, Some(&setup_bytes), data, out)
+        let len = data.map_or(0, |data| data.len());
+        if setup.is_to_device() && len != setup.length() as usize {
+            return Err(AnyUsbError::DataLengthMismatch);
+        }
+
+        self.ep0_unchecked(d, setup, data, out)
     }
 
-    /// Perform Endpoint Device-to-host data transfer
-    /// on a given endpoint index `ep_index` of a
-    /// maximum size `length`.
-    ///
-    /// Returns a Vec[u8] with data.
-    pub fn ep_read(
+    /// Same as `ep0()`, but the error includes
+    /// the endpoint and the Setup packet.
+    pub fn ep0_detailed(
         &mut self,
-        cls: &mut C,
-        ep_index: usize,
-        length: u16,
-    ) -> core::result::Result<Vec<u8>, AnyUsbError> {
-        let mut buf: Vec<u8> = vec![0; length as usize];
-
-        let len = self.ep_raw(cls, ep_index, None, None, buf.as_mut_slice())?;
-
-        if let Some(len) = len.read {
-            buf.truncate(len);
-            Ok(buf)
-        } else {
-            Err(AnyUsbError::EPReadError)
-        }
+        d: &mut C,
+        setup: SetupPacket,
+        data: Option<&[u8]>,
+        out: &mut [u8],
+    ) -> DetailedResult<RWRes> {
+        self.ep0(d, setup, data, out)
+            .map_err(|e| DetailedError::new(e, 0, Some(setup)))
     }
 
-    /// Perform Endpoint Host-to-device data transfer
-    /// on a given endpoint index `ep_index` and
-    /// with `data`.
+    /// Perform EP0 Control transfer like `ep0()`, but send
+    /// `setup` and `data` as is, even if they are inconsistent.
     ///
-    /// Returns number of bytes that was loaded into
-    /// Endpoint buffer.
-    pub fn ep_write(
+    /// The emulation can represent:
+    /// * Device-to-host Setup packet followed by OUT `data`,
+    ///   the data is sent to EP0 after the Setup packet.
+    /// * Host-to-device Setup packet with `wLength` that differs
+    ///   from `data` length, including `data` of `None`.
+    /// * reserved Type and Recipient values, see `CtrRequestType::raw()`.
+    ///
+    /// IN data stage is always performed, so a Host-to-device
+    /// Setup packet may get IN data from the Device. Setup
+    /// packets of wrong length can be sent with `ep0_malformed_setup()`.
+    pub fn ep0_unchecked(
         &mut self,
-        cls: &mut C,
-        ep_index: usize,
-        data: &[u8],
-    ) -> core::result::Result<usize, AnyUsbError> {
-        let len = self.ep_raw(cls, ep_index, None, Some(data), &mut [])?;
-        len.wrote.ok_or(AnyUsbError::EPWriteError)
+        d: &mut C,
+        setup: SetupPacket,
+        data: Option<&[u8]>,
+        out: &mut [u8],
+    ) -> core::result::Result<RWRes, AnyUsbError> {
+        let setup_bytes: [u8; 8] = setup.into();
+        self.ep_raw(d, 0, Some(&setup_bytes), data, out)
     }
 
-    /// Perform raw EP0 Control transfer. `setup_bytes` is a
-    /// 8-byte Setup packet. If transfer is Host-to-device and
-    /// `data` is `Some`, then it's sent after the Setup packet
-    /// and Device can receive it as a payload. For Device-to-host
-    /// transfers `data` should be `None` and `out` must have
-    /// enough space to store the response.
-    pub fn ep_raw(
+    /// Perform EP0 Control transfer like `ep0()` with
+    /// a Setup packet crafted from arbitrary bytes, e.g. with
+    /// reserved `bmRequestType` bits or a huge `wLength`.
+    ///
+    /// `out` must have enough space to store the response.
+    pub fn ep0_raw_setup(
         &mut self,
         d: &mut C,
-        ep_index: usize,
-        setup_bytes: Option<&[u8]>,
+        setup_bytes: &[u8; 8],
         data: Option<&[u8]>,
         out: &mut [u8],
     ) -> core::result::Result<RWRes, AnyUsbError> {
-        let mut sent = None;
-        let out0 = EndpointAddress::from_parts(ep_index, UsbDirection::Out);
-        let in0 = EndpointAddress::from_parts(ep_index, UsbDirection::In);
+        self.ep_raw(d, 0, Some(setup_bytes), data, out)
+    }
 
-        info!("#### EP {} transaction", ep_index);
+    /// Put EP0 Control transfer `setup` into EP0 buffer without
+    /// polling the Device. The transfer is completed with
+    /// `ep0_collect()`.
+    ///
+    /// This allows running application code and calling `poll()`
+    /// manually while the Setup packet is pending. `data` of
+    /// a Host-to-device transfer is sent together with the Setup
+    /// packet if `UsbDeviceCtx::SETUP_WITH_DATA` is enabled,
+    /// otherwise it's sent by `ep0_collect()`.
+    ///
+    /// Returns `AnyUsbError::DataLengthMismatch` like `ep0()`.
+    pub fn ep0_queue(
+        &mut self,
+        setup: SetupPacket,
+        data: Option<&[u8]>,
+    ) -> core::result::Result<(), AnyUsbError> {
+        let len = data.map_or(0, |data| data.len());
+        if setup.is_to_device() && len != setup.length() as usize {
+            return Err(AnyUsbError::DataLengthMismatch);
+        }
+
+        let out0 = EndpointAddress::from_parts(0, UsbDirection::Out);
+        let setup_bytes: [u8; 8] = setup.into();
+
+        info!("#### EP 0 transaction queued");
+        let queued = self.send_setup(out0, &setup_bytes, data)?;
+        self.queued = Some(QueuedTransfer {
+            setup: setup_bytes,
+            data: data.map(Vec::from),
+            queued,
+            polls: self.polls,
+        });
+        Ok(())
+    }
+
+    /// Complete EP0 Control transfer queued with `ep0_queue()`
+    /// and return its result like `ep0()` does.
+    ///
+    /// If the Device wasn't polled after `ep0_queue()`,
+    /// it's polled once for the Setup packet first. Data and
+    /// status stages are performed with polls as usual.
+    ///
+    /// Returns `AnyUsbError::NoQueuedTransfer` if there is
+    /// no queued transfer, or if another EP0 transfer was
+    /// performed after it was queued.
+    pub fn ep0_collect(
+        &mut self,
+        d: &mut C,
+        out: &mut [u8],
+    ) -> core::result::Result<RWRes, AnyUsbError> {
+        let q = self.queued.take().ok_or(AnyUsbError::NoQueuedTransfer)?;
+        let out0 = EndpointAddress::from_parts(0, UsbDirection::Out);
+
+        if q.polls == self.polls {
+            self.do_poll(d, HookWhen::AfterSetup(out0));
+        }
+
+        let data = q.data.as_deref();
+        let res = self.finish_transfer(d, 0, 0, Some(&q.setup), data, Some(out), q.queued, q.polls);
+        self.record(0, Some(q.setup.into()), data.unwrap_or_default(), out, &res);
+        res
+    }
+
+    /// Perform EP0 Control transfer like `ep0()`, but
+    /// abort a Control transfer which is still in progress,
+    /// like a Host that abandons a transfer in the middle of
+    /// its data stage.
+    ///
+    /// Data of the previous transfer which is still in EP0
+    /// buffers is discarded: OUT data not consumed by
+    /// the Device and IN data not read by the Host.
+    pub fn ep0_abort_with(
+        &mut self,
+        d: &mut C,
+        setup: SetupPacket,
+        data: Option<&[u8]>,
+        out: &mut [u8],
+    ) -> core::result::Result<RWRes, AnyUsbError> {
+        let out0 = EndpointAddress::from_parts(0, UsbDirection::Out);
+        let in0 = EndpointAddress::from_parts(0, UsbDirection::In);
+
+        let discarded = {
+            let usb = self.usb.lock();
+            usb.ep_discard(out0) + usb.ep_discard(in0)
+        };
+        info!(
+            "#### EP 0 transaction aborted, {} bytes discarded",
+            discarded
+        );
+
+        self.ep0(d, setup, data, out)
+    }
+
+    /// Send `first` Setup packet and poll the Device once,
+    /// then abort the transfer and perform `setup` Control
+    /// transfer with `ep0_abort_with()`, like a Host that
+    /// times out and issues a new request.
+    ///
+    /// The Device sees the first request, but its data
+    /// and status stages never happen.
+    pub fn ep0_abort_and_restart(
+        &mut self,
+        d: &mut C,
+        first: SetupPacket,
+        setup: SetupPacket,
+        data: Option<&[u8]>,
+        out: &mut [u8],
+    ) -> core::result::Result<RWRes, AnyUsbError> {
+        let out0 = EndpointAddress::from_parts(0, UsbDirection::Out);
+        let first_bytes: [u8; 8] = first.into();
+
+        info!("#### EP 0 Setup to be aborted");
+        self.set_read(out0, &first_bytes, true)?;
+        self.do_poll(d, HookWhen::AfterSetup(out0));
+
+        self.ep0_abort_with(d, setup, data, out)
+    }
+
+    /// Perform EP0 Control transfer like `ep0()`, but send
+    /// `first` Setup packet right before `setup` without
+    /// polling the Device in between, like a Host that
+    /// retries a Setup.
+    ///
+    /// A Setup packet replaces any data in EP0 OUT buffer,
+    /// including a previous Setup packet, so the Device
+    /// should only see the second request.
+    pub fn ep0_back_to_back(
+        &mut self,
+        d: &mut C,
+        first: SetupPacket,
+        setup: SetupPacket,
+        data: Option<&[u8]>,
+        out: &mut [u8],
+    ) -> core::result::Result<RWRes, AnyUsbError> {
+        let out0 = EndpointAddress::from_parts(0, UsbDirection::Out);
+        let first_bytes: [u8; 8] = first.into();
+
+        info!("#### EP 0 back-to-back Setup");
+        self.set_read(out0, &first_bytes, true)?;
+
+        self.ep0(d, setup, data, out)
+    }
+
+    /// Send `bytes` of any length as a Setup packet to EP0
+    /// and check how the Device reacts to it.
+    ///
+    /// The emulated bus delivers the whole packet with a single
+    /// read, even if it's longer than EP0 maximum packet size.
+    /// Packets which don't fit into the Device's read buffer are
+    /// dropped with `UsbError::BufferOverflow`. `usb-device`
+    /// silently ignores packets which are not exactly 8 bytes long.
+    /// Bytes which don't fit into the emulated endpoint buffer
+    /// are not sent.
+    ///
+    /// The Device is polled until it reads the packet, at most
+    /// `UsbDeviceCtx::MAX_TRANSFER_POLLS` times. Data left in
+    /// EP0 buffers is discarded afterwards.
+    pub fn ep0_malformed_setup(&mut self, d: &mut C, bytes: &[u8]) -> SetupOutcome {
+        let out0 = EndpointAddress::from_parts(0, UsbDirection::Out);
+        let in0 = EndpointAddress::from_parts(0, UsbDirection::In);
+
+        info!("#### EP 0 malformed Setup, {} bytes", bytes.len());
+        let len = bytes.len().min(self.usb.lock().ep_buffer_size(out0));
+        self.set_read(out0, &bytes[..len], true).ok();
+
+        for _ in 0..X::MAX_TRANSFER_POLLS {
+            self.do_poll(d, HookWhen::AfterSetup(out0));
+            if !self.usb.lock().ep_read_pending(out0) {
+                break;
+            }
+        }
+
+        let usb = self.usb.lock();
+        let res = if usb.ep_read_pending(out0) {
+            SetupOutcome::Wedged
+        } else if usb.stalled(0) {
+            SetupOutcome::Stalled
+        } else if usb.pending_write_len(in0).is_some() {
+            SetupOutcome::Accepted
+        } else {
+            SetupOutcome::Ignored
+        };
+
+        usb.ep_discard(out0);
+        usb.ep_discard(in0);
+
+        debug!("#### EP 0 malformed Setup: {:?}", res);
+        res
+    }
+
+    /// Perform Endpoint Device-to-host data transfer
+    /// on a given endpoint index `ep_index` of a
+    /// maximum size `length`.
+    ///
+    /// Returns a Vec[u8] with data.
+    pub fn ep_read(
+        &mut self,
+        cls: &mut C,
+        ep_index: usize,
+        length: u16,
+    ) -> core::result::Result<Vec<u8>, AnyUsbError> {
+        let mut buf: Vec<u8> = vec![0; length as usize];
+
+        let len = self.ep_raw(cls, ep_index, None, None, buf.as_mut_slice())?;
+
+        if let Some(len) = len.read {
+            buf.truncate(len);
+            Ok(buf)
+        } else {
+            Err(AnyUsbError::EPReadError)
+        }
+    }
+
+    /// Perform Endpoint Device-to-host data transfer
+    /// on a given endpoint index `ep_index` of exactly
+    /// `length` bytes.
+    ///
+    /// Returns `AnyUsbError::EPShortRead` if the Device
+    /// sends less data.
+    pub fn ep_read_exact(
+        &mut self,
+        cls: &mut C,
+        ep_index: usize,
+        length: u16,
+    ) -> core::result::Result<Vec<u8>, AnyUsbError> {
+        let buf = self.ep_read(cls, ep_index, length)?;
+        if buf.len() < length as usize {
+            return Err(AnyUsbError::EPShortRead {
+                expected: length as usize,
+                got: buf.len(),
+            });
+        }
+        Ok(buf)
+    }
+
+    /// Read a single packet written by the Device to
+    /// endpoint `ep_index` and poll the Device.
+    ///
+    /// Unlike `ep_read()`, which concatenates all data,
+    /// keeps the boundaries of the packets: each `write()`
+    /// by the class is a separate packet.
+    ///
+    /// Returns `None` if the Device has nothing to send.
+    pub fn ep_read_packet(
+        &mut self,
+        cls: &mut C,
+        ep_index: usize,
+    ) -> core::result::Result<Option<Vec<u8>>, AnyUsbError> {
+        let in_ep = EndpointAddress::from_parts(ep_index, UsbDirection::In);
+        let polls = self.polls;
+
+        info!("#### EP {} read packet", ep_index);
+
+        self.check_endpoint(in_ep)?;
+
+        let mut buf = vec![0; self.usb.lock().next_packet_len(in_ep).unwrap_or(0)];
+        let one = self
+            .usb
+            .lock()
+            .get_write_packet(in_ep, &mut buf)
+            .map_err(|needed| host_buffer_too_small(in_ep, needed, buf.len()))?;
+        self.do_poll(cls, HookWhen::DataOut(in_ep));
+
+        let res = if self.usb.lock().stalled(ep_index) {
+            Err(AnyUsbError::EPStalled)
+        } else {
+            let mut res = RWRes::new(one, None);
+            res.zlp = one == Some(0);
+            res.polls = self.polls - polls;
+            res.packets_in = one.map_or(0, |_| 1);
+            Ok(res)
+        };
+
+        self.record(ep_index, None, &[], &buf, &res);
+        res?;
+        Ok(one.map(|_| buf))
+    }
+
+    /// Read exactly `count` packets written by the Device to
+    /// endpoint `ep_index` with `ep_read_packet()`, polling the
+    /// Device between reads.
+    ///
+    /// Returns `AnyUsbError::PacketCountMismatch` if the Device
+    /// doesn't write `count` packets within
+    /// `UsbDeviceCtx::MAX_TRANSFER_POLLS` polls, or if more
+    /// packets are pending after `count` packets were read.
+    /// Extra packets are not consumed.
+    pub fn ep_read_packets(
+        &mut self,
+        cls: &mut C,
+        ep_index: usize,
+        count: usize,
+    ) -> core::result::Result<Vec<Vec<u8>>, AnyUsbError> {
+        let in_ep = EndpointAddress::from_parts(ep_index, UsbDirection::In);
+        let mut packets = Vec::new();
+
+        for _ in 0..X::MAX_TRANSFER_POLLS {
+            if packets.len() == count {
+                break;
+            }
+            if let Some(packet) = self.ep_read_packet(cls, ep_index)? {
+                packets.push(packet);
+            }
+        }
+
+        let got = packets.len() + self.usb.lock().pending_packets(in_ep);
+        if got != count {
+            return Err(AnyUsbError::PacketCountMismatch {
+                expected: count,
+                got,
+            });
+        }
+
+        Ok(packets)
+    }
+
+    /// Read and discard all data the Device has written to
+    /// IN endpoint `ep_index`, polling the Device between
+    /// packets until the endpoint is empty, but no more than
+    /// `UsbDeviceCtx::MAX_TRANSFER_POLLS` times.
+    ///
+    /// Returns the data, which can be empty. Allows to start
+    /// the next phase of a test without stale IN data.
+    pub fn drain_endpoint(
+        &mut self,
+        cls: &mut C,
+        ep_index: usize,
+    ) -> core::result::Result<Vec<u8>, AnyUsbError> {
+        let mut data = Vec::new();
+        for _ in 0..X::MAX_TRANSFER_POLLS {
+            match self.ep_read_packet(cls, ep_index)? {
+                Some(packet) => data.extend_from_slice(&packet),
+                None => break,
+            }
+        }
+        info!("#### EP {} drained {} bytes", ep_index, data.len());
+        Ok(data)
+    }
+
+    /// Discard data in buffers of all non-control endpoints in
+    /// both directions without polling the Device. Unlike
+    /// `bus_reset()`, stall conditions, data toggles and the
+    /// Device state are kept.
+    ///
+    /// Returns the number of bytes discarded.
+    pub fn reset_buffers(&mut self) -> usize {
+        let discarded = self.usb.lock().discard_endpoints();
+        info!("#### Endpoint buffers reset, {} bytes discarded", discarded);
+        discarded
+    }
+
+    /// Poll the Device up to `max_polls` times until it
+    /// writes data to IN endpoint `ep_index`, then read
+    /// a single packet with `ep_read_packet()`.
+    ///
+    /// An empty endpoint is not an error. `UsbDeviceCtx::hook()`
+    /// is called after each poll with `HookWhen::DataOut`, so
+    /// it can change the state of the class during the wait.
+    /// No polls are done if the data is already available.
+    ///
+    /// Returns `AnyUsbError::InDataTimeout` if there is no
+    /// data after `max_polls` polls.
+    pub fn wait_for_in_data(
+        &mut self,
+        cls: &mut C,
+        ep_index: usize,
+        max_polls: usize,
+    ) -> core::result::Result<Vec<u8>, AnyUsbError> {
+        let in_ep = EndpointAddress::from_parts(ep_index, UsbDirection::In);
+
+        info!("#### EP {} wait for data, {} polls", ep_index, max_polls);
+
+        self.check_endpoint(in_ep)?;
+
+        for polls in 0..=max_polls {
+            if self.usb.lock().stalled(ep_index) {
+                return Err(AnyUsbError::EPStalled);
+            }
+            if self.usb.lock().next_packet_len(in_ep).is_some() {
+                debug!("#### EP {} has data after {} polls", ep_index, polls);
+                return self
+                    .ep_read_packet(cls, ep_index)?
+                    .ok_or(AnyUsbError::EPReadError);
+            }
+            if polls < max_polls {
+                self.do_poll(cls, HookWhen::DataOut(in_ep));
+            }
+        }
+
+        Err(AnyUsbError::InDataTimeout { polls: max_polls })
+    }
+
+    /// Perform Endpoint Host-to-device data transfer
+    /// on a given endpoint index `ep_index` and
+    /// with `data`.
+    ///
+    /// Returns number of bytes that was loaded into
+    /// Endpoint buffer.
+    pub fn ep_write(
+        &mut self,
+        cls: &mut C,
+        ep_index: usize,
+        data: &[u8],
+    ) -> core::result::Result<usize, AnyUsbError> {
+        let len = self.ep_raw_write(cls, ep_index, None, data)?;
+        len.wrote.ok_or(AnyUsbError::EPWriteError)
+    }
+
+    /// Perform Endpoint Host-to-device data transfer on
+    /// endpoint `ep_index` one maximum size packet at a time.
+    /// The next packet is sent only after the Device consumed
+    /// the previous one, within `UsbDeviceCtx::MAX_TRANSFER_POLLS`
+    /// and `UsbDeviceCtx::MAX_IDLE_POLLS` limits.
+    ///
+    /// No zero-length packet is sent after the data, even if
+    /// its length is a multiple of the packet size.
+    ///
+    /// Returns the number of bytes consumed by the Device.
+    /// Sending stops at the first packet the Device didn't
+    /// consume completely and the rest of the packet is left
+    /// in the endpoint buffer.
+    pub fn ep_write_chunked(
+        &mut self,
+        cls: &mut C,
+        ep_index: usize,
+        data: &[u8],
+    ) -> core::result::Result<usize, AnyUsbError> {
+        let out_ep = EndpointAddress::from_parts(ep_index, UsbDirection::Out);
+        self.check_endpoint(out_ep)?;
+        let packet = self.usb.lock().ep_max_size(out_ep).max(1);
+        let mut accepted = 0;
+
+        for chunk in data.chunks(packet) {
+            let remaining = match self.ep_raw_write(cls, ep_index, None, chunk) {
+                Ok(res) => res.remaining,
+                Err(AnyUsbError::OutDataStuck { remaining, .. }) => remaining,
+                Err(e) => return Err(e),
+            };
+            accepted += chunk.len() - remaining;
+            if remaining > 0 {
+                break;
+            }
+        }
+
+        Ok(accepted)
+    }
+
+    /// Same as `ep_read()`, but the error includes
+    /// the endpoint.
+    pub fn ep_read_detailed(
+        &mut self,
+        cls: &mut C,
+        ep_index: usize,
+        length: u16,
+    ) -> DetailedResult<Vec<u8>> {
+        self.ep_read(cls, ep_index, length)
+            .map_err(|e| DetailedError::new(e, ep_index, None))
+    }
+
+    /// Same as `ep_write()`, but the error includes
+    /// the endpoint.
+    pub fn ep_write_detailed(
+        &mut self,
+        cls: &mut C,
+        ep_index: usize,
+        data: &[u8],
+    ) -> DetailedResult<usize> {
+        self.ep_write(cls, ep_index, data)
+            .map_err(|e| DetailedError::new(e, ep_index, None))
+    }
+
+    /// Perform raw EP0 Control transfer. `setup_bytes` is a
+    /// 8-byte Setup packet. If transfer is Host-to-device and
+    /// `data` is `Some`, then it's sent after the Setup packet
+    /// and Device can receive it as a payload. For Device-to-host
+    /// transfers `data` should be `None` and `out` must have
+    /// enough space to store the response, otherwise
+    /// `AnyUsbError::EP0Overrun` is returned for EP0 and
+    /// `AnyUsbError::HostReadBufferTooSmall` for other endpoints.
+    pub fn ep_raw(
+        &mut self,
+        d: &mut C,
+        ep_index: usize,
+        setup_bytes: Option<&[u8]>,
+        data: Option<&[u8]>,
+        out: &mut [u8],
+    ) -> core::result::Result<RWRes, AnyUsbError> {
+        let res = self.transfer(d, ep_index, ep_index, setup_bytes, data, Some(out));
+        let setup = setup_bytes.and_then(|b| <[u8; 8]>::try_from(b).ok().map(Into::into));
+        self.record(ep_index, setup, data.unwrap_or_default(), out, &res);
+        Self::check_out_remaining(setup_bytes, res)
+    }
+
+    /// Same as `ep_raw()`, but for transfers which don't
+    /// expect IN data from the Device.
+    ///
+    /// With a Setup packet, only a zero-length status packet
+    /// is accepted from the Device, `AnyUsbError::UnexpectedInData`
+    /// is returned if it sends data. Without a Setup packet,
+    /// IN stage is skipped and IN data the Device may have
+    /// queued on the endpoint is left for a later read,
+    /// `RWRes::in_pending` is set then.
+    pub fn ep_raw_write(
+        &mut self,
+        d: &mut C,
+        ep_index: usize,
+        setup_bytes: Option<&[u8]>,
+        data: &[u8],
+    ) -> core::result::Result<RWRes, AnyUsbError> {
+        let res = self.transfer(d, ep_index, ep_index, setup_bytes, Some(data), None);
+        let setup = setup_bytes.and_then(|b| <[u8; 8]>::try_from(b).ok().map(Into::into));
+        self.record(ep_index, setup, data, &[], &res);
+        Self::check_out_remaining(setup_bytes, res)
+    }
+
+    /// Turns OUT data left in the endpoint buffer after a
+    /// transfer without a Setup packet into an error if
+    /// `UsbDeviceCtx::STRICT_OUT_DATA` is enabled.
+    fn check_out_remaining(setup_bytes: Option<&[u8]>, res: AnyResult<RWRes>) -> AnyResult<RWRes> {
+        match res {
+            Ok(res) if X::STRICT_OUT_DATA && setup_bytes.is_none() && res.remaining > 0 => {
+                Err(AnyUsbError::EPDataRemaining {
+                    remaining: res.remaining,
+                })
+            }
+            res => res,
+        }
+    }
+
+    /// Adds a transaction to the trace if it's enabled.
+    fn record(
+        &mut self,
+        ep_index: usize,
+        setup: Option<SetupPacket>,
+        data_out: &[u8],
+        data_in: &[u8],
+        res: &AnyResult<RWRes>,
+    ) {
+        if let Some(trace) = self.trace.as_mut() {
+            let read = res.as_ref().map_or(0, RWRes::read_len);
+            trace.push(Transaction {
+                ep_index,
+                setup,
+                data_out: Vec::from(data_out),
+                data_in: Vec::from(&data_in[..read]),
+                result: res.clone(),
+            });
+        }
+    }
+
+    /// Same as `ep_raw()`, but the error includes
+    /// the endpoint and the Setup packet, if `setup_bytes`
+    /// is a valid 8-byte Setup packet.
+    pub fn ep_raw_detailed(
+        &mut self,
+        d: &mut C,
+        ep_index: usize,
+        setup_bytes: Option<&[u8]>,
+        data: Option<&[u8]>,
+        out: &mut [u8],
+    ) -> DetailedResult<RWRes> {
+        self.ep_raw(d, ep_index, setup_bytes, data, out)
+            .map_err(|e| {
+                let setup = setup_bytes.and_then(|b| <[u8; 8]>::try_from(b).ok().map(Into::into));
+                DetailedError::new(e, ep_index, setup)
+            })
+    }
+
+    /// Write `out_data` to `out_ep` endpoint and read whatever
+    /// the Device sends on `in_ep` endpoint to `in_buf`.
+    ///
+    /// Unlike `ep_raw()`, data is not interpreted in any way:
+    /// there is no Setup packet and no status stage, even for
+    /// EP0. OUT stage is skipped if `out_data` is empty. IN data
+    /// is read until a short or a zero-length packet, or until
+    /// the Device has nothing to send.
+    pub fn ep_transfer_raw(
+        &mut self,
+        d: &mut C,
+        out_ep: usize,
+        in_ep: usize,
+        out_data: &[u8],
+        in_buf: &mut [u8],
+    ) -> core::result::Result<RWRes, AnyUsbError> {
+        let data = Some(out_data).filter(|data| !data.is_empty());
+        let res = self.transfer(d, out_ep, in_ep, None, data, Some(in_buf));
+        self.record(out_ep, None, out_data, in_buf, &res);
+        res
+    }
+
+    /// Write `data` of any length to a Bulk OUT endpoint
+    /// `ep_index`, one maximum size packet at a time, polling
+    /// the Device until it consumes each packet.
+    ///
+    /// Unlike `ep_write()`, the amount of data is not limited by
+    /// the endpoint buffer size and `UsbDeviceCtx::MAX_TRANSFER_POLLS`
+    /// applies to each packet. A zero-length packet is not sent
+    /// after the data.
+    ///
+    /// Returns `AnyUsbError::OutDataStuck` if the Device stops
+    /// consuming the data.
+    pub fn bulk_write(
+        &mut self,
+        d: &mut C,
+        ep_index: usize,
+        data: &[u8],
+    ) -> core::result::Result<usize, AnyUsbError> {
+        let out_ep = EndpointAddress::from_parts(ep_index, UsbDirection::Out);
+        let polls = self.polls;
+        let mut sent = 0;
+
+        info!("#### EP {} bulk write, {} bytes", ep_index, data.len());
+
+        self.check_endpoint(out_ep)?;
+        let packet = self.usb.lock().ep_max_size(out_ep).max(1);
+
+        let stuck = |dev: &Self, end: usize, remaining: usize| AnyUsbError::OutDataStuck {
+            ep: out_ep,
+            queued: end,
+            remaining: data.len() - end + remaining,
+            polls: dev.polls - polls,
+        };
+
+        let res = loop {
+            if sent == data.len() {
+                let mut res = RWRes::new(None, Some(sent));
+                res.polls = self.polls - polls;
+                res.packets_out = sent.div_ceil(packet);
+                break Ok(res);
+            }
+
+            let end = data.len().min(sent + packet);
+            match self.transfer(d, ep_index, ep_index, None, Some(&data[sent..end]), None) {
+                Ok(res) if res.remaining == 0 => sent = end,
+                Ok(res) => break Err(stuck(self, end, res.remaining)),
+                Err(AnyUsbError::OutDataStuck { remaining, .. }) => {
+                    break Err(stuck(self, end, remaining))
+                }
+                Err(e) => break Err(e),
+            }
+        };
+
+        self.record(ep_index, None, data, &[], &res);
+        res.map(|_| sent)
+    }
+
+    /// Write `data` to an Interrupt OUT endpoint `ep_index`,
+    /// one maximum size packet per poll, like a Host sending
+    /// a report once per frame.
+    ///
+    /// A packet the Device doesn't consume is retried on the
+    /// next `UsbDeviceCtx::MAX_IDLE_POLLS` polls. Sending stops
+    /// at the first packet the Device didn't consume completely
+    /// and the rest of the packet is left in the endpoint buffer.
+    /// A zero-length packet is never sent.
+    ///
+    /// Returns the number of bytes consumed by the Device, or
+    /// `AnyUsbError::EPStalled` if the endpoint is stalled.
+    pub fn ep_write_interrupt(
+        &mut self,
+        d: &mut C,
+        ep_index: usize,
+        data: &[u8],
+    ) -> core::result::Result<usize, AnyUsbError> {
+        let out_ep = EndpointAddress::from_parts(ep_index, UsbDirection::Out);
+        let polls = self.polls;
+        let mut consumed = 0;
+        let mut packets = 0;
+
+        info!("#### EP {} interrupt write, {} bytes", ep_index, data.len());
+
+        self.check_endpoint(out_ep)?;
+        let packet = self.usb.lock().ep_max_size(out_ep).max(1);
+
+        let res = 'packets: {
+            for chunk in data.chunks(packet) {
+                if let Err(e) = self.wait_out_ready(d, out_ep) {
+                    break 'packets Err(e);
+                }
+                if let Err(e) = self.set_read(out_ep, chunk, false) {
+                    break 'packets Err(e);
+                }
+                packets += 1;
+
+                let mut remaining = chunk.len();
+                for _ in 0..=X::MAX_IDLE_POLLS {
+                    if self.usb.lock().stalled(ep_index) {
+                        break 'packets Err(AnyUsbError::EPStalled);
+                    }
+                    self.do_poll(d, HookWhen::DataIn(out_ep));
+                    remaining = self.usb.lock().ep_data_len(out_ep);
+                    if remaining < chunk.len() {
+                        break;
+                    }
+                }
+
+                consumed += chunk.len() - remaining;
+                if remaining > 0 {
+                    break;
+                }
+            }
+
+            let mut res = RWRes::new(None, Some(consumed));
+            res.polls = self.polls - polls;
+            res.packets_out = packets;
+            Ok(res)
+        };
+
+        self.record(ep_index, None, data, &[], &res);
+        res.map(|_| consumed)
+    }
+
+    /// Read up to `max_len` bytes from a Bulk IN endpoint
+    /// `ep_index`, one packet at a time, polling the Device
+    /// after each packet.
+    ///
+    /// Reading ends with a short or a zero-length packet, when
+    /// the Device has nothing to send, or when `max_len` bytes
+    /// are read. The amount of data is not limited by the
+    /// endpoint buffer size or `UsbDeviceCtx::MAX_TRANSFER_POLLS`.
+    ///
+    /// Returns `AnyUsbError::HostReadBufferTooSmall` if a packet
+    /// doesn't fit into `max_len`.
+    pub fn bulk_read(
+        &mut self,
+        d: &mut C,
+        ep_index: usize,
+        max_len: usize,
+    ) -> core::result::Result<Vec<u8>, AnyUsbError> {
+        let in_ep = EndpointAddress::from_parts(ep_index, UsbDirection::In);
+        let polls = self.polls;
+        let mut buf = vec![0; max_len];
+        let mut len = 0;
+        let mut packets_in = 0;
+
+        info!("#### EP {} bulk read, up to {} bytes", ep_index, max_len);
+
+        self.check_endpoint(in_ep)?;
+        let packet = self.usb.lock().ep_max_size(in_ep).max(1);
+
+        let res = loop {
+            let one = match self.usb.lock().get_write_packet(in_ep, &mut buf[len..]) {
+                Ok(one) => one,
+                Err(pending) => break Err(host_buffer_too_small(in_ep, len + pending, max_len)),
+            };
+            self.do_poll(d, HookWhen::DataOut(in_ep));
+            if self.usb.lock().stalled(ep_index) {
+                break Err(AnyUsbError::EPStalled);
+            }
+
+            let Some(one) = one else {
+                break Ok(false);
+            };
+            packets_in += 1;
+            len += one;
+            if one < packet {
+                break Ok(one == 0);
+            }
+            if len == max_len {
+                break Ok(false);
+            }
+        };
+
+        let res = res.map(|zlp| {
+            let mut res = RWRes::new(Some(len), None);
+            res.zlp = zlp;
+            res.polls = self.polls - polls;
+            res.packets_in = packets_in;
+            res
+        });
+
+        self.record(ep_index, None, &[], &buf, &res);
+        res?;
+        buf.truncate(len);
+        Ok(buf)
+    }
+
+    /// Poll Interrupt IN endpoint `ep_index` for `frames`
+    /// frames and return all reports sent by the Device.
+    ///
+    /// Each frame the Host makes one IN attempt followed by
+    /// a Device poll, the same as with bInterval of 1. An empty
+    /// endpoint is treated as NAK and is not an error, so the
+    /// Device may produce reports at any time, for example,
+    /// from `UsbDeviceCtx::hook()`. Zero-length reports are
+    /// returned as empty vectors.
+    ///
+    /// Returns `AnyUsbError::EPStalled` if the endpoint
+    /// is stalled.
+    pub fn interrupt_poll(
+        &mut self,
+        d: &mut C,
+        ep_index: usize,
+        frames: usize,
+    ) -> core::result::Result<Vec<Vec<u8>>, AnyUsbError> {
+        let in_ep = EndpointAddress::from_parts(ep_index, UsbDirection::In);
+        let mut reports = Vec::new();
+
+        info!("#### EP {} interrupt poll, {} frames", ep_index, frames);
+
+        self.check_endpoint(in_ep)?;
+        let packet = self.usb.lock().ep_max_size(in_ep);
+
+        for _ in 0..frames {
+            let mut buf = vec![0; packet];
+            let polls = self.polls;
+
+            let one = self
+                .usb
+                .lock()
+                .get_write_packet(in_ep, &mut buf)
+                .map_err(|needed| host_buffer_too_small(in_ep, needed, packet))?;
+            self.do_poll(d, HookWhen::DataOut(in_ep));
+            if self.usb.lock().stalled(ep_index) {
+                return Err(AnyUsbError::EPStalled);
+            }
+
+            if let Some(len) = one {
+                let mut res = RWRes::new(Some(len), None);
+                res.zlp = len == 0;
+                res.polls = self.polls - polls;
+                res.packets_in = 1;
+                self.record(ep_index, None, &[], &buf, &Ok(res));
+
+                buf.truncate(len);
+                reports.push(buf);
+            }
+        }
+
+        Ok(reports)
+    }
+
+    /// Returns the number of the current frame for
+    /// Isochronous transfers. Starts with `0`.
+    pub fn iso_frame(&self) -> u32 {
+        self.frame
+    }
+
+    /// Ends the current frame and polls the Device once.
+    ///
+    /// Data that wasn't consumed by the Device during the frame
+    /// is dropped from all Isochronous OUT endpoints. A packet
+    /// on an Isochronous IN endpoint which the Host didn't read
+    /// during the frame is dropped and reported to the Device
+    /// as transmitted.
+    ///
+    /// Returns the number of bytes dropped.
+    pub fn iso_next_frame(&mut self, d: &mut C) -> usize {
+        let read: Vec<EndpointAddress> = self
+            .iso_used
+            .drain(..)
+            .filter(|ep| ep.direction() == UsbDirection::In)
+            .collect();
+        let dropped = self.usb.lock().iso_end_frame(&read);
+        self.frame = self.frame.wrapping_add(1);
+
+        info!("#### frame {}, dropped {} bytes", self.frame, dropped);
 
+        self.do_poll(d, HookWhen::ManualPoll);
+        dropped
+    }
+
+    /// Checks that the Device is not suspended and
+    /// is configured if `ep` is a non-zero endpoint.
+    fn check_configured(&self, ep: EndpointAddress) -> core::result::Result<(), AnyUsbError> {
+        if self.dev.state() == UsbDeviceState::Suspend {
+            return Err(AnyUsbError::Suspended);
+        }
+        if X::REQUIRE_CONFIGURED
+            && ep.index() != 0
+            && self.dev.state() != UsbDeviceState::Configured
+        {
+            return Err(AnyUsbError::NotConfigured(ep));
+        }
+        Ok(())
+    }
+
+    /// Checks that `ep` is allocated, usable in the current
+    /// Device state, and is not Isochronous.
+    fn check_endpoint(&self, ep: EndpointAddress) -> core::result::Result<(), AnyUsbError> {
+        let usb = self.usb.lock();
+        if !usb.ep_allocated(ep) {
+            return Err(AnyUsbError::EndpointNotAllocated(ep));
+        }
+        self.check_configured(ep)?;
+        if usb.ep_is_iso(ep) {
+            return Err(AnyUsbError::IsoEndpoint);
+        }
+        Ok(())
+    }
+
+    /// Checks that `ep` is Isochronous and wasn't used
+    /// during the current frame, and marks it used.
+    fn iso_use(&mut self, ep: EndpointAddress) -> core::result::Result<(), AnyUsbError> {
+        if !self.usb.lock().ep_allocated(ep) {
+            return Err(AnyUsbError::EndpointNotAllocated(ep));
+        }
+        self.check_configured(ep)?;
+        if !self.usb.lock().ep_is_iso(ep) {
+            return Err(AnyUsbError::NotIsoEndpoint);
+        }
+        if self.iso_used.contains(&ep) {
+            return Err(AnyUsbError::IsoFrameUsed);
+        }
+        self.iso_used.push(ep);
+        Ok(())
+    }
+
+    /// Send a single packet to an Isochronous OUT endpoint
+    /// `ep_index` during the current frame and poll the Device.
+    ///
+    /// There is no handshake: the packet is dropped if the
+    /// Device doesn't read it before `iso_next_frame()`.
+    ///
+    /// Returns `AnyUsbError::IsoFrameUsed` if a packet
+    /// was already sent during the current frame.
+    pub fn iso_write_frame(
+        &mut self,
+        d: &mut C,
+        ep_index: usize,
+        data: &[u8],
+    ) -> core::result::Result<usize, AnyUsbError> {
+        let out_ep = EndpointAddress::from_parts(ep_index, UsbDirection::Out);
+        let polls = self.polls;
+
+        let res = self.iso_use(out_ep).and_then(|_| {
+            let max_size = self.usb.lock().ep_max_size(out_ep);
+            if data.len() > max_size {
+                return Err(AnyUsbError::IsoPacketTooLarge {
+                    max_size,
+                    got: data.len(),
+                });
+            }
+
+            info!(
+                "#### EP {} iso write, frame {}, {} bytes",
+                ep_index,
+                self.frame,
+                data.len()
+            );
+
+            if data.is_empty() {
+                self.usb.lock().set_read_zlp(out_ep);
+            } else {
+                self.set_read(out_ep, data, false)?;
+            }
+            self.do_poll(d, HookWhen::DataIn(out_ep));
+
+            let mut res = RWRes::new(None, Some(data.len()));
+            res.polls = self.polls - polls;
+            res.packets_out = 1;
+            Ok(res)
+        });
+
+        self.record(ep_index, None, data, &[], &res);
+        res.map(|_| data.len())
+    }
+
+    /// Read a single packet from an Isochronous IN endpoint
+    /// `ep_index` during the current frame and poll the Device.
+    ///
+    /// Returns `None` if the Device has nothing to send
+    /// during the frame.
+    ///
+    /// Returns `AnyUsbError::IsoFrameUsed` if a packet
+    /// was already read during the current frame.
+    pub fn iso_read_frame(
+        &mut self,
+        d: &mut C,
+        ep_index: usize,
+    ) -> core::result::Result<Option<Vec<u8>>, AnyUsbError> {
+        let in_ep = EndpointAddress::from_parts(ep_index, UsbDirection::In);
+        let polls = self.polls;
+        let mut buf = Vec::new();
+
+        let res = self.iso_use(in_ep).and_then(|_| {
+            info!("#### EP {} iso read, frame {}", ep_index, self.frame);
+
+            buf.resize(self.usb.lock().ep_max_size(in_ep), 0);
+            let one = self
+                .usb
+                .lock()
+                .get_write_packet(in_ep, &mut buf)
+                .map_err(|needed| host_buffer_too_small(in_ep, needed, buf.len()))?;
+            self.do_poll(d, HookWhen::DataOut(in_ep));
+
+            let mut res = RWRes::new(one, None);
+            res.polls = self.polls - polls;
+            res.packets_in = one.map_or(0, |_| 1);
+            Ok(res)
+        });
+
+        self.record(ep_index, None, &[], &buf, &res);
+        let one = res?.read;
+        Ok(one.map(|len| {
+            buf.truncate(len);
+            buf
+        }))
+    }
+
+    /// If `OUT_NAK` is enabled, waits until the Device consumes
+    /// data already in OUT endpoint buffer, polling it up to
+    /// `MAX_NAK_RETRIES` times.
+    ///
+    /// Returns `AnyUsbError::EndpointBusy` if the data is
+    /// still there.
+    fn wait_out_ready(
+        &mut self,
+        d: &mut C,
+        ep_addr: EndpointAddress,
+    ) -> core::result::Result<(), AnyUsbError> {
+        if !X::OUT_NAK {
+            return Ok(());
+        }
+
+        for retry in 0..=X::MAX_NAK_RETRIES {
+            if !self.usb.lock().ep_read_pending(ep_addr) {
+                return Ok(());
+            }
+            if retry < X::MAX_NAK_RETRIES {
+                debug!("#### EP {} NAK, retrying", ep_addr.index());
+                self.do_poll(d, HookWhen::DataIn(ep_addr));
+            }
+        }
+
+        Err(AnyUsbError::EndpointBusy {
+            ep: ep_addr,
+            retries: X::MAX_NAK_RETRIES,
+            remaining: self.usb.lock().ep_data_len(ep_addr),
+        })
+    }
+
+    /// Appends `data` after `queued` bytes to the OUT endpoint
+    /// buffer, one packet at a time if `CHUNKED_DATA` is enabled.
+    ///
+    /// Returns the number of bytes queued so far.
+    fn queue_data(&self, ep_addr: EndpointAddress, data: &[u8], queued: usize) -> usize {
+        let usb = self.usb.lock();
+        let end = if X::CHUNKED_DATA {
+            let packet = usb.ep_max_size(ep_addr).max(1);
+            data.len().min(queued + packet)
+        } else {
+            data.len()
+        };
+        queued + usb.append_read(ep_addr, &data[queued..end])
+    }
+
+    /// IN data is read from `in_index` endpoint which is
+    /// the same as `ep_index` except for `ep_transfer_raw()`.
+    fn transfer(
+        &mut self,
+        d: &mut C,
+        ep_index: usize,
+        in_index: usize,
+        setup_bytes: Option<&[u8]>,
+        data: Option<&[u8]>,
+        out: Option<&mut [u8]>,
+    ) -> core::result::Result<RWRes, AnyUsbError> {
+        let polls = self.polls;
+        let out0 = EndpointAddress::from_parts(ep_index, UsbDirection::Out);
+        let in0 = EndpointAddress::from_parts(in_index, UsbDirection::In);
+
+        info!("#### EP {} transaction", ep_index);
+
+        if data.is_some() || setup_bytes.is_some() {
+            self.check_endpoint(out0)?;
+        }
+        if out.is_some() {
+            self.check_endpoint(in0)?;
+        }
+
+        let mut queued = 0;
         if let Some(setup_bytes) = setup_bytes {
-            self.usb.borrow().set_read(out0, setup_bytes, true);
+            if ep_index == 0 {
+                self.queued = None;
+            }
+            queued = self.send_setup(out0, setup_bytes, data)?;
             self.do_poll(d, HookWhen::AfterSetup(out0));
-            if self.usb.borrow().stalled(ep_index) {
+        }
+
+        let res =
+            self.finish_transfer(d, ep_index, in_index, setup_bytes, data, out, queued, polls);
+        if let (Ok(_), Some(setup_bytes)) = (&res, setup_bytes) {
+            self.track_data_toggles(setup_bytes);
+        }
+        self.check_packet_size()?;
+        self.check_data_toggle()?;
+        res
+    }
+
+    /// Resets data toggles after standard requests
+    /// which reset them according to the specification.
+    ///
+    /// SET_CONFIGURATION and SET_INTERFACE reset toggles of
+    /// all non-control endpoints on both sides. CLEAR_FEATURE
+    /// ENDPOINT_HALT resets only the Host side, `usb-device`
+    /// must clear the halt, which resets the Device side.
+    fn track_data_toggles(&self, setup_bytes: &[u8]) {
+        let usb = self.usb.lock();
+        match setup_bytes {
+            [0x00, 0x09, ..] | [0x01, 0x0b, ..] => usb.reset_toggles(),
+            [0x02, 0x01, 0, 0, ep, ..] => usb.reset_host_toggle(EndpointAddress::from(*ep)),
+            _ => {}
+        }
+    }
+
+    /// Returns `AnyUsbError::DataToggleMismatch` if
+    /// `STRICT_DATA_TOGGLE` is enabled and a packet
+    /// with a wrong data toggle was transferred.
+    fn check_data_toggle(&self) -> core::result::Result<(), AnyUsbError> {
+        match self.usb.lock().take_toggle_mismatch() {
+            Some((ep, expected)) if X::STRICT_DATA_TOGGLE => Err(AnyUsbError::DataToggleMismatch {
+                ep,
+                expected: expected.into(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns `AnyUsbError::PacketSizeViolation` if
+    /// `STRICT_PACKET_SIZE` is enabled and the class tried
+    /// to write more than endpoint's maximum packet size.
+    fn check_packet_size(&self) -> core::result::Result<(), AnyUsbError> {
+        match self.usb.lock().take_size_violation() {
+            Some((ep, max_size, got)) if X::STRICT_PACKET_SIZE => {
+                Err(AnyUsbError::PacketSizeViolation { ep, max_size, got })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Puts Setup packet into `out0` endpoint buffer followed
+    /// by `data` if `SETUP_WITH_DATA` is enabled.
+    ///
+    /// Returns the number of data bytes queued.
+    fn send_setup(
+        &self,
+        out0: EndpointAddress,
+        setup_bytes: &[u8],
+        data: Option<&[u8]>,
+    ) -> core::result::Result<usize, AnyUsbError> {
+        self.set_read(out0, setup_bytes, true)?;
+        Ok(match data {
+            Some(val) if X::SETUP_WITH_DATA => self.queue_data(out0, val, 0),
+            _ => 0,
+        })
+    }
+
+    /// Replaces data in `ep_addr` endpoint buffer with `data`.
+    ///
+    /// Returns `AnyUsbError::HostBufferOverflow` if `data`
+    /// doesn't fit into the buffer.
+    fn set_read(
+        &self,
+        ep_addr: EndpointAddress,
+        data: &[u8],
+        setup: bool,
+    ) -> core::result::Result<usize, AnyUsbError> {
+        self.usb
+            .lock()
+            .set_read(ep_addr, data, setup)
+            .map_err(|capacity| AnyUsbError::HostBufferOverflow {
+                ep: ep_addr,
+                capacity,
+                attempted: data.len(),
+            })
+    }
+
+    /// Continues a transfer after the Setup packet, if any,
+    /// was sent and polled: performs OUT and IN data stages
+    /// and the status stage.
+    ///
+    /// `queued` is the number of `data` bytes already queued,
+    /// `polls` is the value of the poll counter when
+    /// the transfer started.
+    #[allow(clippy::too_many_arguments)]
+    fn finish_transfer(
+        &mut self,
+        d: &mut C,
+        ep_index: usize,
+        in_index: usize,
+        setup_bytes: Option<&[u8]>,
+        data: Option<&[u8]>,
+        mut out: Option<&mut [u8]>,
+        mut queued: usize,
+        polls: usize,
+    ) -> core::result::Result<RWRes, AnyUsbError> {
+        let mut idle_polls = 0;
+        let mut remaining = 0;
+        let out0 = EndpointAddress::from_parts(ep_index, UsbDirection::Out);
+        let in0 = EndpointAddress::from_parts(in_index, UsbDirection::In);
+
+        if setup_bytes.is_some() {
+            if self.usb.lock().stalled(ep_index) {
                 return Err(AnyUsbError::EP0Stalled);
             }
-            if self.usb.borrow().ep_data_len(out0) != 0 {
+            if self.usb.lock().ep_data_len(out0) != queued {
                 return Err(AnyUsbError::EP0NotEmptyAfterSetup);
             }
         }
 
         if let Some(val) = data {
-            sent = Some(self.usb.borrow().append_read(out0, val));
-            for i in 1..129 {
-                let before_bytes = self.usb.borrow().ep_data_len(out0);
+            if let Some(w_length) = setup_bytes.and_then(setup_w_length) {
+                if val.len() != w_length as usize {
+                    debug!(
+                        "#### EP {} data stage has {} bytes, wLength is {}",
+                        ep_index,
+                        val.len(),
+                        w_length
+                    );
+                }
+            }
+
+            if queued == 0 {
+                if setup_bytes.is_none() {
+                    self.wait_out_ready(d, out0)?;
+                }
+                queued = self.queue_data(out0, val, queued);
+            }
+            let mut idle_in_row = 0;
+            for i in 1..=X::MAX_TRANSFER_POLLS {
+                let before_bytes = self.usb.lock().ep_data_len(out0);
                 let res = self.do_poll(d, HookWhen::DataIn(out0));
-                let after_bytes = self.usb.borrow().ep_data_len(out0);
+                let after_bytes = self.usb.lock().ep_data_len(out0);
 
                 if !res {
                     debug!("#### EP {} class has no data to consume", ep_index);
                     break;
                 }
-                if self.usb.borrow().ep_is_empty(out0) {
-                    debug!("#### EP {} consumed all data", ep_index);
-                    break;
-                }
-                if before_bytes == after_bytes {
+                if self.usb.lock().ep_is_empty(out0) {
+                    if queued == val.len() {
+                        debug!("#### EP {} consumed all data", ep_index);
+                        break;
+                    }
+                    queued = self.queue_data(out0, val, queued);
+                    idle_in_row = 0;
+                } else if before_bytes == after_bytes {
+                    idle_polls += 1;
+                    idle_in_row += 1;
+                    if idle_in_row > X::MAX_IDLE_POLLS {
+                        debug!(
+                            "#### EP {} poll didn't consume any data, have {} bytes",
+                            ep_index, after_bytes
+                        );
+                        break;
+                    }
                     debug!(
-                        "#### EP {} poll didn't consume any data, have {} bytes",
-                        ep_index, after_bytes
+                        "#### EP {} poll didn't consume any data, retrying",
+                        ep_index
                     );
-                    break;
+                } else {
+                    idle_in_row = 0;
                 }
-                if i >= 128 {
-                    return Err(AnyUsbError::EPReadFailed);
+                if i >= X::MAX_TRANSFER_POLLS {
+                    return Err(AnyUsbError::OutDataStuck {
+                        ep: out0,
+                        queued,
+                        remaining: val.len() - queued + self.usb.lock().ep_data_len(out0),
+                        polls: self.polls - polls,
+                    });
                 }
             }
-            if self.usb.borrow().stalled(ep_index) {
+            if self.usb.lock().stalled(ep_index) {
                 return Err(AnyUsbError::EPStalled);
             }
+            remaining = val.len() - queued + self.usb.lock().ep_data_len(out0);
         }
 
         let mut len = 0;
-        let max_ep_size = self.usb.borrow().ep_max_size(in0);
+        let mut zlp = false;
+        let mut packets_in = 0;
+        let max_ep_size = self.usb.lock().ep_max_size(in0);
+        let w_length = setup_bytes.and_then(setup_in_w_length).map(usize::from);
+        let expected = w_length.filter(|_| X::STRICT_RESPONSE_LENGTH);
+        let setup = setup_bytes
+            .and_then(|b| <[u8; 8]>::try_from(b).ok())
+            .map(SetupPacket::from);
+        // for Host-to-device transfers IN stage is the status stage
+        let in_polls = match setup {
+            Some(setup) if X::SKIP_STATUS_STAGE && setup.is_to_device() => 0,
+            None if out.is_none() => 0,
+            _ => X::MAX_TRANSFER_POLLS,
+        };
 
-        loop {
-            let one = self.usb.borrow().get_write(in0, &mut out[len..]);
+        let mut empty_in_row = 0;
+        for i in 1..=in_polls {
+            if let Some(expected) = expected {
+                let pending = self.usb.lock().pending_write_len(in0).unwrap_or(0);
+                if len + pending > expected {
+                    return Err(AnyUsbError::ResponseTooLong {
+                        expected,
+                        got: len + pending,
+                    });
+                }
+            }
+
+            let one = match out.as_deref_mut() {
+                Some(out) => {
+                    self.usb
+                        .lock()
+                        .get_write(in0, &mut out[len..])
+                        .map_err(|pending| match in_index {
+                            0 => AnyUsbError::EP0Overrun {
+                                buffer: out.len(),
+                                got: len + pending,
+                            },
+                            _ => host_buffer_too_small(in0, len + pending, out.len()),
+                        })?
+                }
+                None => self
+                    .usb
+                    .lock()
+                    .get_write(in0, &mut [])
+                    .map_err(|_| AnyUsbError::UnexpectedInData)?,
+            };
             self.do_poll(d, HookWhen::DataOut(in0));
-            if self.usb.borrow().stalled(ep_index) {
+            if self.usb.lock().stalled(in_index) {
                 return Err(AnyUsbError::EPStalled);
             }
 
-            len += one;
-            if one < max_ep_size {
-                // short read - last block
-                break;
+            match one {
+                None if empty_in_row < X::MAX_IN_EMPTY_POLLS => {
+                    debug!("#### EP {} no IN data yet, retrying", ep_index);
+                    empty_in_row += 1;
+                    continue;
+                }
+                None => {
+                    // no data - nothing more to read
+                    if let Some(w_length) = w_length.filter(|_| X::STRICT_ZLP) {
+                        if len > 0 && len < w_length && len.is_multiple_of(max_ep_size.max(1)) {
+                            return Err(AnyUsbError::MissingZlp);
+                        }
+                    }
+                    break;
+                }
+                Some(0) => {
+                    // zero-length packet - end of data
+                    packets_in += 1;
+                    zlp = true;
+                    break;
+                }
+                Some(one) => {
+                    packets_in += 1;
+                    len += one;
+                    empty_in_row = 0;
+                    if one < max_ep_size {
+                        // short read - last block
+                        break;
+                    }
+                }
+            }
+            if i >= X::MAX_TRANSFER_POLLS {
+                return Err(AnyUsbError::EPReadFailed);
             }
         }
 
-        Ok(RWRes::new(Some(len), sent))
+        let mut stall = None;
+        let status_ok = match setup {
+            Some(_) if X::SKIP_STATUS_STAGE => {
+                debug!("#### EP {} status stage skipped", ep_index);
+                false
+            }
+            Some(setup) if setup.is_to_device() => zlp && len == 0,
+            Some(_) => {
+                let ok = self.status_out(d, ep_index);
+                if self.usb.lock().stalled(ep_index) {
+                    stall = Some(HookWhen::StatusOut(out0));
+                }
+                ok
+            }
+            None => false,
+        };
+
+        let max_out_size = self.usb.lock().ep_max_size(out0).max(1);
+        let mut res = RWRes::new(out.as_ref().map(|_| len), data.map(|_| queued));
+        res.idle_polls = idle_polls;
+        res.zlp = zlp;
+        res.status_ok = status_ok;
+        res.polls = self.polls - polls;
+        res.packets_out = queued.div_ceil(max_out_size);
+        res.packets_in = packets_in;
+        res.stall = stall;
+        res.remaining = remaining;
+        res.in_pending =
+            setup.is_none() && out.is_none() && self.usb.lock().pending_write_len(in0).is_some();
+        Ok(res)
+    }
+
+    /// Sends a zero-length status packet to the Device
+    /// after IN data stage.
+    ///
+    /// Returns `true` if the Device consumed the packet
+    /// without a stall.
+    fn status_out(&mut self, d: &mut C, ep_index: usize) -> bool {
+        let out0 = EndpointAddress::from_parts(ep_index, UsbDirection::Out);
+
+        debug!("#### EP {} status stage", ep_index);
+        self.usb.lock().set_read_zlp(out0);
+        self.do_poll(d, HookWhen::StatusOut(out0));
+
+        let usb = self.usb.lock();
+        if usb.ep_read_pending(out0) {
+            debug!("#### EP {} status packet wasn't consumed", ep_index);
+            usb.ep_discard(out0);
+            return false;
+        }
+        !usb.stalled(ep_index)
     }
 
     /// Perform EP0 Control transfer.
@@ -672,11 +3103,230 @@ where
         }
     }
 
-    /// Perform Device-to-host EP0 Control transfer.
-    /// The response is returned in a result `Vec`.
-    ///
-    /// `reqt` is passed as is. It should be `to_host()`.
-    pub fn control_read(
+    /// Perform Device-to-host EP0 Control transfer.
+    /// The response is returned in a result `Vec`.
+    ///
+    /// The response buffer is `length` bytes long, which
+    /// is enough since `usb-device` truncates responses
+    /// to `wLength`.
+    ///
+    /// `reqt` is passed as is. It should be `to_host()`.
+    pub fn control_read(
+        &mut self,
+        cls: &mut C,
+        reqt: CtrRequestType,
+        req: u8,
+        value: u16,
+        index: u16,
+        length: u16,
+    ) -> core::result::Result<Vec<u8>, AnyUsbError> {
+        self.ep_io_control(cls, reqt, req, value, index, length, None)
+    }
+
+    /// Perform Device-to-host EP0 Control transfer like
+    /// `control_read()`, but return how the Device completed
+    /// it. A stall is reported as `ControlOutcome::Stalled`
+    /// instead of `AnyUsbError::EP0Stalled`.
+    pub fn control_in_outcome(
+        &mut self,
+        cls: &mut C,
+        reqt: CtrRequestType,
+        req: u8,
+        value: u16,
+        index: u16,
+        length: u16,
+    ) -> core::result::Result<ControlOutcome, AnyUsbError> {
+        match self.control_read(cls, reqt, req, value, index, length) {
+            Ok(data) if data.is_empty() => Ok(ControlOutcome::Empty),
+            Ok(data) => Ok(ControlOutcome::Data(data)),
+            Err(AnyUsbError::EP0Stalled) => Ok(ControlOutcome::Stalled),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Perform Device-to-host EP0 Control transfer like
+    /// `control_read()`, but fail if the Device needs more
+    /// than `max_polls` polls to complete it, including
+    /// the status stage.
+    ///
+    /// Returns `AnyUsbError::PollLimitExceeded` with the
+    /// actual number of polls in this case.
+    #[allow(clippy::too_many_arguments)]
+    pub fn control_read_bounded(
+        &mut self,
+        cls: &mut C,
+        reqt: CtrRequestType,
+        req: u8,
+        value: u16,
+        index: u16,
+        length: u16,
+        max_polls: usize,
+    ) -> core::result::Result<Vec<u8>, AnyUsbError> {
+        let polls = self.polls;
+        let data = self.control_read(cls, reqt, req, value, index, length)?;
+
+        let polls = self.polls - polls;
+        if polls > max_polls {
+            return Err(AnyUsbError::PollLimitExceeded { max_polls, polls });
+        }
+        Ok(data)
+    }
+
+    /// Perform Device-to-host EP0 Control transfer and convert
+    /// the response to `T`. `wLength` is `T::LENGTH`.
+    ///
+    /// Returns `AnyUsbError::ResponseTooShort` with expected
+    /// and actual sizes if the response is too short for `T`.
+    pub fn control_read_as<T: FromControlData>(
+        &mut self,
+        cls: &mut C,
+        reqt: CtrRequestType,
+        req: u8,
+        value: u16,
+        index: u16,
+    ) -> core::result::Result<T, AnyUsbError> {
+        let data = self.control_read(cls, reqt, req, value, index, T::LENGTH)?;
+        T::from_control_data(&data)
+    }
+
+    /// Perform Device-to-host EP0 Control transfer in two steps,
+    /// the way Host reads variable-length responses.
+    ///
+    /// First, `prefix` bytes are requested and `total_len`
+    /// extracts the full response length from them. Then the
+    /// request is repeated with the full length and the complete
+    /// response is returned. The second request is not sent if
+    /// the first response is already complete.
+    ///
+    /// `total_len` returns `None` if the length can't be
+    /// extracted from the data, `AnyUsbError::ResponseTooShort`
+    /// is returned then. It is also returned if the Device sends
+    /// less data than announced.
+    ///
+    /// ```ignore
+    /// // wTotalLength of a configuration descriptor
+    /// let conf = dev.control_read_full(&mut cls,
+    ///     CtrRequestType::to_host(), 6, 0x0200, 0, 9,
+    ///     |d| Some(u16::from_le_bytes([*d.get(2)?, *d.get(3)?])))?;
+    /// ```
+    #[allow(clippy::too_many_arguments)]
+    pub fn control_read_full<F>(
+        &mut self,
+        cls: &mut C,
+        reqt: CtrRequestType,
+        req: u8,
+        value: u16,
+        index: u16,
+        prefix: u16,
+        total_len: F,
+    ) -> core::result::Result<Vec<u8>, AnyUsbError>
+    where
+        F: FnOnce(&[u8]) -> Option<u16>,
+    {
+        let mut data = self.control_read(cls, reqt, req, value, index, prefix)?;
+
+        let total = total_len(&data).ok_or(AnyUsbError::ResponseTooShort {
+            expected: prefix as usize,
+            got: data.len(),
+        })?;
+
+        if (total as usize) > data.len() {
+            data = self.control_read(cls, reqt, req, value, index, total)?;
+        }
+
+        if data.len() < total as usize {
+            return Err(AnyUsbError::ResponseTooShort {
+                expected: total as usize,
+                got: data.len(),
+            });
+        }
+
+        data.truncate(total as usize);
+        Ok(data)
+    }
+
+    /// Perform EP0 Control transfer `stall` which the Device
+    /// must reject, then Device-to-host EP0 Control transfer
+    /// `setup` and return its response.
+    ///
+    /// Checks that EP0 stall is cleared by the next Setup
+    /// packet. Returns `AnyUsbError::NotStalled` if the Device
+    /// didn't stall the first request.
+    pub fn control_read_after_stall(
+        &mut self,
+        cls: &mut C,
+        stall: SetupPacket,
+        setup: SetupPacket,
+    ) -> core::result::Result<Vec<u8>, AnyUsbError> {
+        let mut buf: Vec<u8> = vec![0; stall.length() as usize];
+        match self.ep0_unchecked(cls, stall, None, &mut buf) {
+            Err(AnyUsbError::EP0Stalled) | Err(AnyUsbError::EPStalled) => {}
+            Err(e) => return Err(e),
+            Ok(_) => return Err(AnyUsbError::NotStalled),
+        }
+
+        let mut buf: Vec<u8> = vec![0; setup.length() as usize];
+        let res = self.ep0(cls, setup, None, &mut buf)?;
+        buf.truncate(res.read.ok_or(AnyUsbError::EPReadError)?);
+        Ok(buf)
+    }
+
+    /// Perform Host-to-device EP0 Control transfer.
+    /// `data` is sent after the Setup packet
+    /// and Device can receive it as a payload.
+    /// `wLength` is the length of `data`.
+    /// The response is returned in a result `Vec`
+    /// and normally it should be empty.
+    ///
+    /// `reqt` is passed as is. It should be `to_device()`.
+    pub fn control_write(
+        &mut self,
+        cls: &mut C,
+        reqt: CtrRequestType,
+        req: u8,
+        value: u16,
+        index: u16,
+        data: &[u8],
+    ) -> core::result::Result<Vec<u8>, AnyUsbError> {
+        let length = data
+            .len()
+            .try_into()
+            .map_err(|_| AnyUsbError::DataConversion)?;
+        self.ep_io_control(cls, reqt, req, value, index, length, Some(data))
+    }
+
+    /// Same as `control_write()`, but also checks that the
+    /// Device consumed all of `data` and ignores the response.
+    ///
+    /// Returns `AnyUsbError::EP0DataRemaining` if some of
+    /// the OUT data stage is left in the EP0 OUT buffer.
+    pub fn control_write_all(
+        &mut self,
+        cls: &mut C,
+        reqt: CtrRequestType,
+        req: u8,
+        value: u16,
+        index: u16,
+        data: &[u8],
+    ) -> core::result::Result<(), AnyUsbError> {
+        let length = data
+            .len()
+            .try_into()
+            .map_err(|_| AnyUsbError::DataConversion)?;
+        let setup = SetupPacket::new(reqt, req, value, index, length);
+
+        let res = self.ep0(cls, setup, Some(data), &mut [])?;
+        if res.remaining > 0 {
+            return Err(AnyUsbError::EP0DataRemaining {
+                remaining: res.remaining,
+            });
+        }
+        Ok(())
+    }
+
+    /// Same as `control_read()`, but the error includes
+    /// the endpoint and the Setup packet.
+    pub fn control_read_detailed(
         &mut self,
         cls: &mut C,
         reqt: CtrRequestType,
@@ -684,59 +3334,77 @@ where
         value: u16,
         index: u16,
         length: u16,
-    ) -> core::result::Result<Vec<u8>, AnyUsbError> {
-        self.ep_io_control(cls, reqt, req, value, index, length, None)
+    ) -> DetailedResult<Vec<u8>> {
+        self.control_read(cls, reqt, req, value, index, length)
+            .map_err(|e| {
+                let setup = SetupPacket::new(reqt, req, value, index, length);
+                DetailedError::new(e, 0, Some(setup))
+            })
     }
 
-    /// Perform Host-to-device EP0 Control transfer.
-    /// `data` is sent after the Setup packet
-    /// and Device can receive it as a payload.
-    /// The response is returned in a result `Vec`
-    /// and normally it should be empty.
-    ///
-    /// `reqt` is passed as is. It should be `to_device()`.
-    #[allow(clippy::too_many_arguments)]
-    pub fn control_write(
+    /// Same as `control_write()`, but the error includes
+    /// the endpoint and the Setup packet.
+    pub fn control_write_detailed(
         &mut self,
         cls: &mut C,
         reqt: CtrRequestType,
         req: u8,
         value: u16,
         index: u16,
-        length: u16,
         data: &[u8],
-    ) -> core::result::Result<Vec<u8>, AnyUsbError> {
-        self.ep_io_control(cls, reqt, req, value, index, length, Some(data))
+    ) -> DetailedResult<Vec<u8>> {
+        self.control_write(cls, reqt, req, value, index, data)
+            .map_err(|e| {
+                let setup = data
+                    .len()
+                    .try_into()
+                    .ok()
+                    .map(|length| SetupPacket::new(reqt, req, value, index, length));
+                DetailedError::new(e, 0, setup)
+            })
     }
 
     /// Standard Device Request: GET_STATUS (0x00)
     pub fn device_get_status(&mut self, cls: &mut C) -> core::result::Result<u16, AnyUsbError> {
-        let data = self.control_read(cls, CtrRequestType::to_host(), 0, 0, 0, 2)?;
-        if data.len() != 2 {
-            return Err(AnyUsbError::EP0BadGetStatusSize);
-        }
+        self.control_read_as(cls, CtrRequestType::to_host(), 0, 0, 0)
+            .map_err(bad_size(AnyUsbError::EP0BadGetStatusSize))
+    }
 
-        let res = data.try_into().map_err(|_| AnyUsbError::DataConversion)?;
-        Ok(u16::from_le_bytes(res))
+    /// Same as `device_get_status()`, but decodes
+    /// the status bits.
+    ///
+    /// Standard Device Request: GET_STATUS (0x00)
+    pub fn device_get_status_typed(
+        &mut self,
+        cls: &mut C,
+    ) -> core::result::Result<DeviceStatus, AnyUsbError> {
+        self.control_read_as(cls, CtrRequestType::to_host(), 0, 0, 0)
+            .map_err(bad_size(AnyUsbError::EP0BadGetStatusSize))
     }
 
     /// Standard Device Request: CLEAR_FEATURE (0x01)
+    ///
+    /// `feature` is a feature selector, for example,
+    /// `Feature::DeviceRemoteWakeup.into()`.
     pub fn device_clear_feature(
         &mut self,
         cls: &mut C,
         feature: u16,
     ) -> core::result::Result<(), AnyUsbError> {
-        self.control_write(cls, CtrRequestType::to_device(), 1, feature, 0, 0, &[])
+        self.control_write(cls, CtrRequestType::to_device(), 1, feature, 0, &[])
             .and(Ok(()))
     }
 
     /// Standard Device Request: SET_FEATURE (0x03)
+    ///
+    /// `feature` is a feature selector, for example,
+    /// `Feature::DeviceRemoteWakeup.into()`.
     pub fn device_set_feature(
         &mut self,
         cls: &mut C,
         feature: u16,
     ) -> core::result::Result<(), AnyUsbError> {
-        self.control_write(cls, CtrRequestType::to_device(), 3, feature, 0, 0, &[])
+        self.control_write(cls, CtrRequestType::to_device(), 3, feature, 0, &[])
             .and(Ok(()))
     }
 
@@ -746,16 +3414,8 @@ where
         cls: &mut C,
         address: u8,
     ) -> core::result::Result<(), AnyUsbError> {
-        self.control_write(
-            cls,
-            CtrRequestType::to_device(),
-            5,
-            address as u16,
-            0,
-            0,
-            &[],
-        )
-        .and(Ok(()))
+        self.control_write(cls, CtrRequestType::to_device(), 5, address as u16, 0, &[])
+            .and(Ok(()))
     }
 
     /// Standard Device Request: GET_DESCRIPTOR (0x06)
@@ -778,6 +3438,132 @@ where
         )
     }
 
+    /// Get descriptor `dtype` at index `dindex` like
+    /// `device_get_descriptor()` with `wLength` of 255
+    /// and parse it with `descriptors::parse()`.
+    ///
+    /// String descriptor zero is parsed into
+    /// `Descriptor::LanguageIds`.
+    ///
+    /// Standard Device Request: GET_DESCRIPTOR (0x06)
+    pub fn device_get_descriptor_parsed(
+        &mut self,
+        cls: &mut C,
+        dtype: u8,
+        dindex: u8,
+        lang_id: u16,
+    ) -> core::result::Result<Descriptor, AnyUsbError> {
+        let descr = self.device_get_descriptor(cls, dtype, dindex, lang_id, 255)?;
+        if dtype == descriptors::STRING && dindex == 0 {
+            return descriptors::parse_language_ids(&descr).map(Descriptor::LanguageIds);
+        }
+        descriptors::parse(&descr)
+    }
+
+    /// Get Configuration descriptor `config_index`,
+    /// returns up to `length` bytes with a single request.
+    /// Use `device_get_configuration_descriptor()` to read
+    /// the complete descriptor.
+    ///
+    /// `config_index` is the zero-based index of the
+    /// configuration, not its bConfigurationValue. Note that
+    /// `usb-device` ignores it and always returns the only
+    /// configuration it supports.
+    ///
+    /// Standard Device Request: GET_DESCRIPTOR (0x06)
+    pub fn device_get_config_descriptor_raw(
+        &mut self,
+        cls: &mut C,
+        config_index: u8,
+        length: u16,
+    ) -> core::result::Result<Vec<u8>, AnyUsbError> {
+        self.device_get_descriptor(cls, 2, config_index, 0, length)
+    }
+
+    /// Get complete Configuration descriptor `index`,
+    /// including interface, endpoint and class descriptors.
+    ///
+    /// Reads the descriptor header first to get
+    /// wTotalLength, the same way `setup()` does. Use
+    /// `device_get_config_descriptor_raw()` to request
+    /// a specific number of bytes.
+    ///
+    /// Standard Device Request: GET_DESCRIPTOR (0x06)
+    pub fn device_get_configuration_descriptor(
+        &mut self,
+        cls: &mut C,
+        index: u8,
+    ) -> core::result::Result<Vec<u8>, AnyUsbError> {
+        let typeindex: u16 = (2u16 << 8) | index as u16;
+        self.control_read_full(
+            cls,
+            CtrRequestType::to_host(),
+            6,
+            typeindex,
+            0,
+            9,
+            config_total_length,
+        )
+    }
+
+    /// Get complete Configuration descriptor as it was
+    /// written by the class, without parsing or validating it.
+    ///
+    /// Reads the descriptor header first to get wTotalLength.
+    /// Unlike `device_get_configuration_descriptor`, returns
+    /// whatever the Device sent even if it's shorter than
+    /// wTotalLength.
+    ///
+    /// Standard Device Request: GET_DESCRIPTOR (0x06)
+    pub fn raw_configuration_descriptor(
+        &mut self,
+        cls: &mut C,
+    ) -> core::result::Result<Vec<u8>, AnyUsbError> {
+        let header = self.device_get_config_descriptor_raw(cls, 0, 9)?;
+        let Some(total) = config_total_length(&header) else {
+            return Ok(header);
+        };
+
+        if (total as usize) <= header.len() {
+            return Ok(header);
+        }
+        self.device_get_config_descriptor_raw(cls, 0, total)
+    }
+
+    /// Checks that bInterval of every Endpoint descriptor
+    /// in Configuration descriptor 0 is the same as the
+    /// interval the class allocated the endpoint with.
+    ///
+    /// Returns `AnyUsbError::EndpointIntervalMismatch` for
+    /// the first endpoint which differs and
+    /// `AnyUsbError::EndpointNotAllocated` if a descriptor
+    /// refers to an endpoint which wasn't allocated.
+    pub fn check_endpoint_intervals(
+        &mut self,
+        cls: &mut C,
+    ) -> core::result::Result<(), AnyUsbError> {
+        let descr = self.device_get_configuration_descriptor(cls, 0)?;
+        let endpoints = self.endpoints();
+        for descr in descriptors::parse_all(&descr)? {
+            let Descriptor::Endpoint(descr) = descr else {
+                continue;
+            };
+            let ep = EndpointAddress::from(descr.address);
+            let info = endpoints
+                .iter()
+                .find(|info| info.address == ep)
+                .ok_or(AnyUsbError::EndpointNotAllocated(ep))?;
+            if info.interval != descr.interval {
+                return Err(AnyUsbError::EndpointIntervalMismatch {
+                    ep,
+                    allocated: info.interval,
+                    descriptor: descr.interval,
+                });
+            }
+        }
+        Ok(())
+    }
+
     /// Get String descriptor from the device and return
     /// unicode string.
     ///
@@ -788,33 +3574,88 @@ where
         index: u8,
         lang_id: u16,
     ) -> core::result::Result<String, AnyUsbError> {
-        let typeindex: u16 = (3u16 << 8) | index as u16;
-        let descr =
-            self.control_read(cls, CtrRequestType::to_host(), 6, typeindex, lang_id, 255)?;
-
-        if descr.len() < 2 {
-            return Err(AnyUsbError::InvalidDescriptorLength);
-        }
-
-        if descr[0] as usize != descr.len() {
-            return Err(AnyUsbError::InvalidDescriptorLength);
-        }
+        self.device_get_string_raw(cls, index, lang_id)
+            .map(|(string, _)| string)
+    }
 
-        if descr[1] != 3 {
-            return Err(AnyUsbError::InvalidDescriptorType);
-        }
+    /// Get String descriptor from the device and return
+    /// unicode string together with the raw descriptor.
+    ///
+    /// Standard Device Request: GET_DESCRIPTOR (0x06)
+    pub fn device_get_string_raw(
+        &mut self,
+        cls: &mut C,
+        index: u8,
+        lang_id: u16,
+    ) -> core::result::Result<(String, Vec<u8>), AnyUsbError> {
+        let descr = self.device_get_descriptor(cls, 3, index, lang_id, 255)?;
+        let string = parse_string_descriptor(&descr)?;
+        Ok((string, descr))
+    }
 
-        if descr[0] % 2 != 0 {
-            return Err(AnyUsbError::InvalidStringLength);
+    /// Get Microsoft OS 1.0 String Descriptor, String
+    /// descriptor at index 0xEE with LANGID 0.
+    ///
+    /// Returns `None` if the Device stalls the request.
+    /// Returns `AnyUsbError::InvalidMsOsSignature` if the
+    /// descriptor doesn't have "MSFT100" signature.
+    ///
+    /// Standard Device Request: GET_DESCRIPTOR (0x06)
+    pub fn device_get_ms_os_string_descriptor(
+        &mut self,
+        cls: &mut C,
+    ) -> core::result::Result<Option<MsOsStringDescriptor>, AnyUsbError> {
+        let length = MsOsStringDescriptor::LENGTH;
+        match self.device_get_descriptor(cls, 3, 0xee, 0, length) {
+            Ok(descr) => MsOsStringDescriptor::from_control_data(&descr).map(Some),
+            Err(AnyUsbError::EP0Stalled) => Ok(None),
+            Err(e) => Err(e),
         }
+    }
 
-        let vu16: Vec<u16> = descr[2..]
-            .chunks(2)
-            .map(|c| u16::from_le_bytes([c[0], c[1]]))
-            .collect();
-        let res = String::from_utf16(&vu16).map_err(|_| AnyUsbError::DataConversion)?;
+    /// Get Microsoft OS 2.0 descriptor set, returns up
+    /// to `length` bytes.
+    ///
+    /// `vendor_code` is bMS_VendorCode from the Microsoft OS 2.0
+    /// platform capability descriptor in the BOS descriptor.
+    ///
+    /// Vendor Device Request: `vendor_code`,
+    /// wIndex MS_OS_20_DESCRIPTOR_INDEX (0x07)
+    pub fn get_ms_os_2_descriptor_set(
+        &mut self,
+        cls: &mut C,
+        vendor_code: u8,
+        length: u16,
+    ) -> core::result::Result<Vec<u8>, AnyUsbError> {
+        self.control_read(
+            cls,
+            CtrRequestType::to_host().vendor().device(),
+            vendor_code,
+            0,
+            7,
+            length,
+        )
+    }
 
-        Ok(res)
+    /// Get WebUSB URL descriptor `url_index`.
+    ///
+    /// `vendor_code` is bVendorCode from the WebUSB platform
+    /// capability descriptor in the BOS descriptor.
+    ///
+    /// Vendor Device Request: `vendor_code`, wIndex GET_URL (0x02)
+    pub fn get_webusb_url(
+        &mut self,
+        cls: &mut C,
+        vendor_code: u8,
+        url_index: u8,
+    ) -> core::result::Result<WebUsbUrl, AnyUsbError> {
+        self.control_read_as(
+            cls,
+            CtrRequestType::to_host().vendor().device(),
+            vendor_code,
+            url_index as u16,
+            2,
+        )
     }
 
     /// Standard Device Request: SET_DESCRIPTOR (0x07)
@@ -828,14 +3669,14 @@ where
         data: &[u8],
     ) -> core::result::Result<(), AnyUsbError> {
         let typeindex: u16 = ((dtype as u16) << 8) | dindex as u16;
-        self.control_write(
+        self.ep_io_control(
             cls,
             CtrRequestType::to_device(),
             7,
             typeindex,
             lang_id,
             length,
-            data,
+            Some(data),
         )
         .and(Ok(()))
     }
@@ -845,11 +3686,8 @@ where
         &mut self,
         cls: &mut C,
     ) -> core::result::Result<u8, AnyUsbError> {
-        let res = self.control_read(cls, CtrRequestType::to_host(), 8, 0, 0, 1)?;
-        if res.len() != 1 {
-            return Err(AnyUsbError::EP0BadGetConfigSize);
-        }
-        Ok(res[0])
+        self.control_read_as(cls, CtrRequestType::to_host(), 8, 0, 0)
+            .map_err(bad_size(AnyUsbError::EP0BadGetConfigSize))
     }
 
     /// Standard Device Request: SET_CONFIGURATION (0x09)
@@ -864,32 +3702,83 @@ where
             9,
             configuration as u16,
             0,
-            0,
             &[],
         )
         .and(Ok(()))
     }
 
+    /// Return the Device to `Addressed` state with
+    /// SET_CONFIGURATION (0x09) request for configuration 0.
+    ///
+    /// Data in non-control endpoint buffers is discarded
+    /// and stall conditions are cleared.
+    ///
+    /// Returns `AnyUsbError::ConfigurationMismatch` if the
+    /// Device is still configured after the request.
+    pub fn device_unconfigure(&mut self, cls: &mut C) -> core::result::Result<(), AnyUsbError> {
+        self.set_configuration_checked(cls, 0)
+    }
+
+    /// Standard Device Request: SET_CONFIGURATION (0x09)
+    /// followed by GET_CONFIGURATION (0x08).
+    ///
+    /// Returns `AnyUsbError::ConfigurationMismatch` if
+    /// GET_CONFIGURATION doesn't return `configuration`
+    /// or if `usb-device` is not in `Configured` state
+    /// for a non-zero `configuration` (`Addressed` for zero).
+    pub fn set_configuration_checked(
+        &mut self,
+        cls: &mut C,
+        configuration: u8,
+    ) -> core::result::Result<(), AnyUsbError> {
+        self.device_set_configuration(cls, configuration)?;
+
+        let actual = self.device_get_configuration(cls)?;
+        let state = self.dev.state();
+        let expected_state = if configuration != 0 {
+            UsbDeviceState::Configured
+        } else {
+            UsbDeviceState::Addressed
+        };
+
+        if actual != configuration || state != expected_state {
+            return Err(AnyUsbError::ConfigurationMismatch {
+                requested: configuration,
+                actual,
+                state,
+            });
+        }
+        Ok(())
+    }
+
     /// Standard Interface Request: GET_STATUS (0x00)
     pub fn interface_get_status(
         &mut self,
         cls: &mut C,
         interface: u8,
     ) -> core::result::Result<u16, AnyUsbError> {
-        let data = self.control_read(
+        self.control_read_as(
             cls,
             CtrRequestType::to_host().interface(),
             0,
             0,
             interface as u16,
-            2,
-        )?;
-        if data.len() != 2 {
-            return Err(AnyUsbError::EP0BadGetStatusSize);
-        }
+        )
+        .map_err(bad_size(AnyUsbError::EP0BadGetStatusSize))
+    }
 
-        let res = data.try_into().map_err(|_| AnyUsbError::DataConversion)?;
-        Ok(u16::from_le_bytes(res))
+    /// Same as `interface_get_status()`, but returns
+    /// `AnyUsbError::ReservedStatusBits` if any status
+    /// bit is set, all of them are reserved.
+    ///
+    /// Standard Interface Request: GET_STATUS (0x00)
+    pub fn interface_get_status_strict(
+        &mut self,
+        cls: &mut C,
+        interface: u8,
+    ) -> core::result::Result<u16, AnyUsbError> {
+        let status = self.interface_get_status(cls, interface)?;
+        check_reserved_status(status, 0)
     }
 
     /// Standard Interface Request: CLEAR_FEATURE (0x01)
@@ -905,7 +3794,6 @@ where
             1,
             feature,
             interface as u16,
-            0,
             &[],
         )
         .and(Ok(()))
@@ -924,7 +3812,6 @@ where
             3,
             feature,
             interface as u16,
-            0,
             &[],
         )
         .and(Ok(()))
@@ -935,11 +3822,8 @@ where
         &mut self,
         cls: &mut C,
     ) -> core::result::Result<u8, AnyUsbError> {
-        let res = self.control_read(cls, CtrRequestType::to_host().interface(), 10, 0, 0, 1)?;
-        if res.len() != 1 {
-            return Err(AnyUsbError::EP0BadGetConfigSize);
-        }
-        Ok(res[0])
+        self.control_read_as(cls, CtrRequestType::to_host().interface(), 10, 0, 0)
+            .map_err(bad_size(AnyUsbError::EP0BadGetConfigSize))
     }
 
     /// Standard Interface Request: SET_INTERFACE (0x0b)
@@ -955,35 +3839,122 @@ where
             11,
             alt_setting as u16,
             interface as u16,
-            0,
             &[],
         )
         .and(Ok(()))
     }
 
+    /// Standard Interface Request: SET_INTERFACE (0x0b)
+    /// followed by GET_INTERFACE (0x0a) for the same
+    /// `interface`.
+    ///
+    /// Returns `AnyUsbError::AltSettingMismatch` if
+    /// GET_INTERFACE doesn't return `alt_setting`.
+    pub fn interface_set_interface_checked(
+        &mut self,
+        cls: &mut C,
+        interface: u8,
+        alt_setting: u8,
+    ) -> core::result::Result<(), AnyUsbError> {
+        self.interface_set_interface(cls, interface, alt_setting)?;
+
+        let actual: u8 = self
+            .control_read_as(
+                cls,
+                CtrRequestType::to_host().interface(),
+                10,
+                0,
+                interface as u16,
+            )
+            .map_err(bad_size(AnyUsbError::EP0BadGetConfigSize))?;
+
+        if actual != alt_setting {
+            return Err(AnyUsbError::AltSettingMismatch {
+                requested: alt_setting,
+                actual,
+            });
+        }
+        Ok(())
+    }
+
     /// Standard Endpoint Request: GET_STATUS (0x00)
+    ///
+    /// `endpoint` is put into `wIndex` as is, it's the endpoint
+    /// address including the direction bit, for example, 0x81
+    /// for IN endpoint 1. `EndpointAddress` converts into it.
     pub fn endpoint_get_status(
         &mut self,
         cls: &mut C,
         endpoint: u8,
     ) -> core::result::Result<u16, AnyUsbError> {
-        let data = self.control_read(
+        self.control_read_as(
             cls,
             CtrRequestType::to_host().endpoint(),
             0,
             0,
             endpoint as u16,
-            2,
-        )?;
-        if data.len() != 2 {
-            return Err(AnyUsbError::EP0BadGetStatusSize);
-        }
+        )
+        .map_err(bad_size(AnyUsbError::EP0BadGetStatusSize))
+    }
 
-        let res = data.try_into().map_err(|_| AnyUsbError::DataConversion)?;
-        Ok(u16::from_le_bytes(res))
+    /// Same as `endpoint_get_status()`, but returns
+    /// `AnyUsbError::ReservedStatusBits` if any status
+    /// bit except Halt (D0) is set.
+    ///
+    /// Standard Endpoint Request: GET_STATUS (0x00)
+    pub fn endpoint_get_status_strict(
+        &mut self,
+        cls: &mut C,
+        endpoint: u8,
+    ) -> core::result::Result<u16, AnyUsbError> {
+        let status = self.endpoint_get_status(cls, endpoint)?;
+        check_reserved_status(status, 0x1)
+    }
+
+    /// Same as `endpoint_get_status()`, but decodes
+    /// the status bits.
+    ///
+    /// Standard Endpoint Request: GET_STATUS (0x00)
+    pub fn endpoint_get_status_typed(
+        &mut self,
+        cls: &mut C,
+        endpoint: u8,
+    ) -> core::result::Result<EndpointStatus, AnyUsbError> {
+        self.control_read_as(
+            cls,
+            CtrRequestType::to_host().endpoint(),
+            0,
+            0,
+            endpoint as u16,
+        )
+        .map_err(bad_size(AnyUsbError::EP0BadGetStatusSize))
+    }
+
+    /// Standard Endpoint Request: GET_STATUS (0x00) for
+    /// every endpoint allocated by the class, EP0 is skipped.
+    ///
+    /// Returns endpoint addresses with their statuses.
+    pub fn endpoint_get_status_all(
+        &mut self,
+        cls: &mut C,
+    ) -> core::result::Result<Vec<(EndpointAddress, u16)>, AnyUsbError> {
+        let mut res = Vec::new();
+        for (addr, _, _) in self.allocated_endpoints() {
+            if addr.index() == 0 {
+                continue;
+            }
+            let status = self.endpoint_get_status(cls, addr.into())?;
+            res.push((addr, status));
+        }
+        Ok(res)
     }
 
     /// Standard Endpoint Request: CLEAR_FEATURE (0x01)
+    ///
+    /// `endpoint` is the endpoint address including the
+    /// direction bit, as in `endpoint_get_status()`.
+    /// `feature` is a feature selector, for example,
+    /// `Feature::EndpointHalt.into()`.
     pub fn endpoint_clear_feature(
         &mut self,
         cls: &mut C,
@@ -996,13 +3967,17 @@ where
             1,
             feature,
             endpoint as u16,
-            0,
             &[],
         )
         .and(Ok(()))
     }
 
     /// Standard Endpoint Request: SET_FEATURE (0x03)
+    ///
+    /// `endpoint` is the endpoint address including the
+    /// direction bit, as in `endpoint_get_status()`.
+    /// `feature` is a feature selector, for example,
+    /// `Feature::EndpointHalt.into()`.
     pub fn endpoint_set_feature(
         &mut self,
         cls: &mut C,
@@ -1015,13 +3990,36 @@ where
             3,
             feature,
             endpoint as u16,
-            0,
             &[],
         )
         .and(Ok(()))
     }
 
+    /// Halt endpoint `ep` with SET_FEATURE (0x03)
+    /// ENDPOINT_HALT Standard Endpoint Request.
+    pub fn endpoint_halt(
+        &mut self,
+        cls: &mut C,
+        ep: EndpointAddress,
+    ) -> core::result::Result<(), AnyUsbError> {
+        self.endpoint_set_feature(cls, ep.into(), Feature::EndpointHalt.into())
+    }
+
+    /// Clear halt condition of endpoint `ep` with
+    /// CLEAR_FEATURE (0x01) ENDPOINT_HALT Standard
+    /// Endpoint Request.
+    pub fn endpoint_unhalt(
+        &mut self,
+        cls: &mut C,
+        ep: EndpointAddress,
+    ) -> core::result::Result<(), AnyUsbError> {
+        self.endpoint_clear_feature(cls, ep.into(), Feature::EndpointHalt.into())
+    }
+
     /// Standard Endpoint Request: SYNCH_FRAME (0x0c)
+    ///
+    /// `endpoint` is the endpoint address including the
+    /// direction bit, as in `endpoint_get_status()`.
     pub fn endpoint_synch_frame(
         &mut self,
         cls: &mut C,
@@ -1045,40 +4043,64 @@ where
 
     /// Setup device approximately as Host would do.
     ///
-    /// This gets some standard descriptors from the device
-    /// and performs standard configuration - sets
+    /// This gets some standard descriptors from the device,
+    /// including all Configuration descriptors reported in
+    /// bNumConfigurations, and performs standard configuration - sets
     /// Device address and sets Device configuration
     /// to `1`.
     ///
     /// This is performed automatically unless disabled
     /// by `UsbDeviceCtx`.
     ///
-    /// USB reset during enumeration is not performed.
+    /// Returns `AnyUsbError::EP0SizeMismatch` if bMaxPacketSize0
+    /// of the Device descriptor differs from `UsbDeviceCtx::EP0_SIZE`,
+    /// for example, if `build_usb_device()` is overridden.
+    ///
+    /// Can be called manually if `UsbDeviceCtx::skip_setup()`
+    /// returns `true`, for example, after testing the Device
+    /// in `Default` state. Returns `AnyUsbError::AlreadySetUp`
+    /// if the Device is not in `Default` state, that is, it was
+    /// already set up, and `AnyUsbError::AddressNotCleared` if
+    /// it still has an address assigned.
+    ///
+    /// USB reset during enumeration is not performed,
+    /// `bus_reset()` returns the Device to `Default` state.
     pub fn setup(&mut self, cls: &mut C) -> core::result::Result<(), AnyUsbError> {
         let mut vec;
 
+        let state = self.dev.state();
+        if state != UsbDeviceState::Default {
+            return Err(AnyUsbError::AlreadySetUp { state });
+        }
+        self.check_default_address()?;
+
         // get device descriptor for max ep0 size
-        // we ignore result.
-        self.device_get_descriptor(cls, 1, 0, 0, 64)?;
+        vec = self.device_get_descriptor(cls, 1, 0, 0, 64)?;
+        let max_packet_size_0 = *vec.get(7).ok_or(AnyUsbError::InvalidDescriptorLength)?;
+        if max_packet_size_0 != X::EP0_SIZE {
+            return Err(AnyUsbError::EP0SizeMismatch {
+                descriptor: max_packet_size_0,
+                expected: X::EP0_SIZE,
+            });
+        }
 
         // todo: reset device
 
         // set address
         self.device_set_address(cls, X::ADDRESS)?;
-        if self.dev.bus().get_address() != X::ADDRESS {
+        if self.assigned_address() != X::ADDRESS {
             return Err(AnyUsbError::SetAddressFailed);
         }
 
         // get device descriptor again
         let devd = self.device_get_descriptor(cls, 1, 0, 0, 18)?;
 
-        // get configuration descriptor for size
-        vec = self.device_get_descriptor(cls, 2, 0, 0, 9)?;
-        let conf_desc_len = u16::from_le_bytes([vec[2], vec[3]]);
-
-        // get configuration descriptor
+        // get all configuration descriptors, first for size
         // we ignore result.
-        self.device_get_descriptor(cls, 2, 0, 0, conf_desc_len)?;
+        let configurations = *devd.get(17).ok_or(AnyUsbError::InvalidDescriptorLength)?;
+        for index in 0..configurations {
+            self.device_get_configuration_descriptor(cls, index)?;
+        }
 
         // get string languages
         vec = self.device_get_descriptor(cls, 3, 0, 0, 255)?;
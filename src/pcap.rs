@@ -0,0 +1,184 @@
+//! Export of recorded transactions as a pcap stream
+//! with Linux usbmon (`DLT_USB_LINUX_MMAPPED`) link type.
+//!
+//! Each `Transaction` is written as a pair of URB Submit
+//! and URB Complete records, the same way usbmon captures
+//! them on a real host.
+//!
+
+use std::io::{Result, Write};
+
+use usb_device::endpoint::EndpointType;
+
+use crate::{AnyUsbError, RWRes, Transaction};
+
+/// LINKTYPE_USB_LINUX_MMAPPED, 64-byte usbmon header.
+const LINKTYPE_USB_LINUX_MMAPPED: u32 = 220;
+const SNAPLEN: u32 = 65535;
+const USBMON_HEADER_LEN: usize = 64;
+
+const URB_SUBMIT: u8 = b'S';
+const URB_COMPLETE: u8 = b'C';
+
+const XFER_ISOCHRONOUS: u8 = 0;
+const XFER_INTERRUPT: u8 = 1;
+const XFER_CONTROL: u8 = 2;
+const XFER_BULK: u8 = 3;
+
+const EINPROGRESS: i32 = 115;
+const EPIPE: i32 = 32;
+const EPROTO: i32 = 71;
+
+/// Bus number reported in usbmon headers.
+const BUS_NUMBER: u16 = 1;
+
+/// Endpoint information required to describe a `Transaction`.
+pub(crate) struct PcapEndpoint {
+    pub(crate) index: usize,
+    pub(crate) ep_type: EndpointType,
+}
+
+/// A single usbmon record.
+struct UrbRecord<'a> {
+    id: u64,
+    kind: u8,
+    xfer_type: u8,
+    epnum: u8,
+    devnum: u8,
+    setup: Option<[u8; 8]>,
+    status: i32,
+    length: u32,
+    data: &'a [u8],
+    timestamp: u64,
+}
+
+impl UrbRecord<'_> {
+    fn write<W: Write>(&self, w: &mut W) -> Result<()> {
+        let ts_sec = self.timestamp / 1_000_000;
+        let ts_usec = (self.timestamp % 1_000_000) as u32;
+        let incl_len = (USBMON_HEADER_LEN + self.data.len()) as u32;
+
+        // pcap record header
+        w.write_all(&(ts_sec as u32).to_le_bytes())?;
+        w.write_all(&ts_usec.to_le_bytes())?;
+        w.write_all(&incl_len.to_le_bytes())?;
+        w.write_all(&incl_len.to_le_bytes())?;
+
+        // usbmon header
+        let flag_setup = if self.setup.is_some() { 0 } else { b'-' };
+        let flag_data = match (self.data.is_empty(), self.epnum & 0x80 != 0) {
+            (false, _) => 0,
+            (true, true) => b'<',
+            (true, false) => b'>',
+        };
+
+        w.write_all(&self.id.to_le_bytes())?;
+        w.write_all(&[self.kind, self.xfer_type, self.epnum, self.devnum])?;
+        w.write_all(&BUS_NUMBER.to_le_bytes())?;
+        w.write_all(&[flag_setup, flag_data])?;
+        w.write_all(&(ts_sec as i64).to_le_bytes())?;
+        w.write_all(&(ts_usec as i32).to_le_bytes())?;
+        w.write_all(&self.status.to_le_bytes())?;
+        w.write_all(&self.length.to_le_bytes())?;
+        w.write_all(&(self.data.len() as u32).to_le_bytes())?;
+        w.write_all(&self.setup.unwrap_or_default())?;
+        // interval, start_frame, xfer_flags, ndesc
+        w.write_all(&[0; 16])?;
+
+        w.write_all(self.data)
+    }
+}
+
+fn xfer_type(ep_type: EndpointType) -> u8 {
+    match ep_type {
+        EndpointType::Control => XFER_CONTROL,
+        EndpointType::Isochronous { .. } => XFER_ISOCHRONOUS,
+        EndpointType::Bulk => XFER_BULK,
+        EndpointType::Interrupt => XFER_INTERRUPT,
+    }
+}
+
+fn status(tr: &Transaction) -> i32 {
+    match tr.result {
+        Ok(_) => 0,
+        Err(AnyUsbError::EP0Stalled) | Err(AnyUsbError::EPStalled) => -EPIPE,
+        Err(_) => -EPROTO,
+    }
+}
+
+/// Writes pcap file header followed by Submit and Complete
+/// records for every transaction in `trace`.
+pub(crate) fn write_pcap<W: Write>(
+    w: &mut W,
+    trace: &[Transaction],
+    devnum: u8,
+    endpoints: &[PcapEndpoint],
+) -> Result<()> {
+    // pcap file header
+    w.write_all(&0xa1b2c3d4u32.to_le_bytes())?;
+    w.write_all(&2u16.to_le_bytes())?;
+    w.write_all(&4u16.to_le_bytes())?;
+    w.write_all(&0i32.to_le_bytes())?;
+    w.write_all(&0u32.to_le_bytes())?;
+    w.write_all(&SNAPLEN.to_le_bytes())?;
+    w.write_all(&LINKTYPE_USB_LINUX_MMAPPED.to_le_bytes())?;
+
+    for (n, tr) in trace.iter().enumerate() {
+        let ep_type = endpoints
+            .iter()
+            .find(|ep| ep.index == tr.ep_index)
+            .map_or(EndpointType::Bulk, |ep| ep.ep_type);
+
+        let dir_in = match tr.setup {
+            Some(setup) => !setup.is_to_device(),
+            None => tr.data_out.is_empty(),
+        };
+
+        let epnum = tr.ep_index as u8 | if dir_in { 0x80 } else { 0 };
+        let id = n as u64 + 1;
+        let timestamp = 2 * n as u64;
+
+        let (submit_len, submit_data): (u32, &[u8]) = match (dir_in, tr.setup) {
+            (true, Some(setup)) => (setup.length() as u32, &[]),
+            (true, None) => (tr.data_in.len() as u32, &[]),
+            (false, _) => (tr.data_out.len() as u32, &tr.data_out),
+        };
+
+        let (complete_len, complete_data): (u32, &[u8]) = if dir_in {
+            (tr.data_in.len() as u32, &tr.data_in)
+        } else {
+            let wrote = tr.result.as_ref().map_or(0, RWRes::wrote_len);
+            (wrote as u32, &[])
+        };
+
+        UrbRecord {
+            id,
+            kind: URB_SUBMIT,
+            xfer_type: xfer_type(ep_type),
+            epnum,
+            devnum,
+            setup: tr.setup.map(Into::into),
+            status: -EINPROGRESS,
+            length: submit_len,
+            data: submit_data,
+            timestamp,
+        }
+        .write(w)?;
+
+        UrbRecord {
+            id,
+            kind: URB_COMPLETE,
+            xfer_type: xfer_type(ep_type),
+            epnum,
+            devnum,
+            setup: None,
+            status: status(tr),
+            length: complete_len,
+            data: complete_data,
+            timestamp: timestamp + 1,
+        }
+        .write(w)?;
+    }
+
+    Ok(())
+}
@@ -3,6 +3,10 @@
 //! Must not use `usb-device` implementation to
 //! be able to test anything.
 //!
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::{AnyResult, AnyUsbError};
 
 /// `CtrRequestType` holds bmRequestType of SETUP
 /// packet.
@@ -35,6 +39,12 @@ impl CtrRequestType {
         }
     }
 
+    /// Create new `CtrRequestType` from a raw bmRequestType
+    /// value as is. Allows reserved Type and Recipient values.
+    pub fn raw(value: u8) -> Self {
+        value.into()
+    }
+
     /// Copy and set Type to Standard (0bx00xxxxx)
     pub fn standard(self) -> Self {
         CtrRequestType { rtype: 0, ..self }
@@ -99,6 +109,17 @@ impl From<u8> for CtrRequestType {
     }
 }
 
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for CtrRequestType {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(CtrRequestType::raw(u.arbitrary()?))
+    }
+
+    fn size_hint(depth: usize) -> (usize, Option<usize>) {
+        <u8 as arbitrary::Arbitrary>::size_hint(depth)
+    }
+}
+
 /// `SetupPacket` structure holds SETUP packet data for
 /// all Control transfers.
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
@@ -121,6 +142,14 @@ impl SetupPacket {
             w_length: length,
         }
     }
+
+    pub(crate) fn is_to_device(&self) -> bool {
+        self.bm_request_type.direction == 0
+    }
+
+    pub(crate) fn length(&self) -> u16 {
+        self.w_length
+    }
 }
 
 impl From<SetupPacket> for [u8; 8] {
@@ -137,3 +166,291 @@ impl From<SetupPacket> for [u8; 8] {
         ]
     }
 }
+
+impl From<[u8; 8]> for SetupPacket {
+    fn from(value: [u8; 8]) -> Self {
+        SetupPacket {
+            bm_request_type: value[0].into(),
+            b_request: value[1],
+            w_value: u16::from_le_bytes([value[2], value[3]]),
+            w_index: u16::from_le_bytes([value[4], value[5]]),
+            w_length: u16::from_le_bytes([value[6], value[7]]),
+        }
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for SetupPacket {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(SetupPacket::new(
+            u.arbitrary()?,
+            u.arbitrary()?,
+            u.arbitrary()?,
+            u.arbitrary()?,
+            u.arbitrary()?,
+        ))
+    }
+
+    fn size_hint(depth: usize) -> (usize, Option<usize>) {
+        <[u8; 8] as arbitrary::Arbitrary>::size_hint(depth)
+    }
+}
+
+/// Returns `wLength` of a raw Host-to-device Setup packet.
+pub(crate) fn setup_w_length(setup_bytes: &[u8]) -> Option<u16> {
+    match setup_bytes {
+        [reqt, _, _, _, _, _, lo, hi] if reqt & 0x80 == 0 => Some(u16::from_le_bytes([*lo, *hi])),
+        _ => None,
+    }
+}
+
+/// Returns `wLength` of a raw Device-to-host Setup packet.
+pub(crate) fn setup_in_w_length(setup_bytes: &[u8]) -> Option<u16> {
+    match setup_bytes {
+        [reqt, _, _, _, _, _, lo, hi] if reqt & 0x80 != 0 => Some(u16::from_le_bytes([*lo, *hi])),
+        _ => None,
+    }
+}
+
+/// Conversion of a Control transfer response
+/// into a value, see `Device::control_read_as()`.
+pub trait FromControlData: Sized {
+    /// `wLength` of the request.
+    const LENGTH: u16;
+
+    /// Converts the response. Returns
+    /// `AnyUsbError::ResponseTooShort` if `data` is
+    /// shorter than expected and `AnyUsbError::ResponseTooLong`
+    /// if it's longer.
+    fn from_control_data(data: &[u8]) -> AnyResult<Self>;
+}
+
+impl<const N: usize> FromControlData for [u8; N] {
+    const LENGTH: u16 = N as u16;
+
+    fn from_control_data(data: &[u8]) -> AnyResult<Self> {
+        data.try_into().map_err(|_| {
+            if data.len() > N {
+                AnyUsbError::ResponseTooLong {
+                    expected: N,
+                    got: data.len(),
+                }
+            } else {
+                AnyUsbError::ResponseTooShort {
+                    expected: N,
+                    got: data.len(),
+                }
+            }
+        })
+    }
+}
+
+macro_rules! from_control_data_le {
+    ($($t:ty),*) => {$(
+        impl FromControlData for $t {
+            const LENGTH: u16 = core::mem::size_of::<$t>() as u16;
+
+            fn from_control_data(data: &[u8]) -> AnyResult<Self> {
+                FromControlData::from_control_data(data).map(<$t>::from_le_bytes)
+            }
+        }
+    )*};
+}
+
+from_control_data_le!(u8, u16, u32);
+
+/// Device status returned by GET_STATUS (0x00)
+/// Standard Device Request.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct DeviceStatus {
+    /// Device is currently self-powered (D0).
+    pub self_powered: bool,
+    /// Remote Wakeup is enabled (D1).
+    pub remote_wakeup: bool,
+}
+
+impl From<u16> for DeviceStatus {
+    fn from(value: u16) -> Self {
+        DeviceStatus {
+            self_powered: value & 0x1 != 0,
+            remote_wakeup: value & 0x2 != 0,
+        }
+    }
+}
+
+impl FromControlData for DeviceStatus {
+    const LENGTH: u16 = 2;
+
+    fn from_control_data(data: &[u8]) -> AnyResult<Self> {
+        u16::from_control_data(data).map(Into::into)
+    }
+}
+
+/// Endpoint status returned by GET_STATUS (0x00)
+/// Standard Endpoint Request.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct EndpointStatus {
+    /// Endpoint is halted (D0).
+    pub halt: bool,
+}
+
+impl From<u16> for EndpointStatus {
+    fn from(value: u16) -> Self {
+        EndpointStatus {
+            halt: value & 0x1 != 0,
+        }
+    }
+}
+
+impl FromControlData for EndpointStatus {
+    const LENGTH: u16 = 2;
+
+    fn from_control_data(data: &[u8]) -> AnyResult<Self> {
+        u16::from_control_data(data).map(Into::into)
+    }
+}
+
+/// Standard feature selectors for SET_FEATURE (0x03)
+/// and CLEAR_FEATURE (0x01) requests.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Feature {
+    /// ENDPOINT_HALT (0), Endpoint recipient.
+    EndpointHalt,
+    /// DEVICE_REMOTE_WAKEUP (1), Device recipient.
+    DeviceRemoteWakeup,
+    /// TEST_MODE (2), Device recipient.
+    TestMode,
+}
+
+impl From<Feature> for u16 {
+    fn from(value: Feature) -> Self {
+        match value {
+            Feature::EndpointHalt => 0,
+            Feature::DeviceRemoteWakeup => 1,
+            Feature::TestMode => 2,
+        }
+    }
+}
+
+/// Data toggle of a data packet.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DataToggle {
+    /// DATA0 packet.
+    Data0,
+    /// DATA1 packet.
+    Data1,
+}
+
+impl From<bool> for DataToggle {
+    fn from(value: bool) -> Self {
+        if value {
+            DataToggle::Data1
+        } else {
+            DataToggle::Data0
+        }
+    }
+}
+
+/// String descriptor.
+impl FromControlData for String {
+    const LENGTH: u16 = 255;
+
+    fn from_control_data(data: &[u8]) -> AnyResult<Self> {
+        parse_string_descriptor(data)
+    }
+}
+
+/// Decodes String descriptor `descr`.
+pub(crate) fn parse_string_descriptor(descr: &[u8]) -> AnyResult<String> {
+    if descr.len() < 2 {
+        return Err(AnyUsbError::InvalidDescriptorLength);
+    }
+
+    if descr[0] as usize != descr.len() {
+        return Err(AnyUsbError::InvalidDescriptorLength);
+    }
+
+    if descr[1] != 3 {
+        return Err(AnyUsbError::InvalidDescriptorType);
+    }
+
+    if !descr[0].is_multiple_of(2) {
+        return Err(AnyUsbError::InvalidStringLength);
+    }
+
+    let vu16: Vec<u16> = descr[2..]
+        .chunks(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect();
+    String::from_utf16(&vu16).map_err(|_| AnyUsbError::DataConversion)
+}
+
+/// Microsoft OS 1.0 String Descriptor, String descriptor
+/// at index 0xEE.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct MsOsStringDescriptor {
+    /// bMS_VendorCode, bRequest of vendor requests which
+    /// retrieve Microsoft OS feature descriptors.
+    pub vendor_code: u8,
+    /// bPad, reserved.
+    pub pad: u8,
+}
+
+/// qwSignature of Microsoft OS 1.0 String Descriptor,
+/// "MSFT100" in UTF-16LE.
+const MS_OS_SIGNATURE: [u8; 14] = [
+    b'M', 0, b'S', 0, b'F', 0, b'T', 0, b'1', 0, b'0', 0, b'0', 0,
+];
+
+impl FromControlData for MsOsStringDescriptor {
+    const LENGTH: u16 = 18;
+
+    fn from_control_data(data: &[u8]) -> AnyResult<Self> {
+        if data.len() < 2 || data[0] as usize != data.len() || data.len() != 18 {
+            return Err(AnyUsbError::InvalidDescriptorLength);
+        }
+
+        if data[1] != 3 {
+            return Err(AnyUsbError::InvalidDescriptorType);
+        }
+
+        if data[2..16] != MS_OS_SIGNATURE {
+            return Err(AnyUsbError::InvalidMsOsSignature);
+        }
+
+        Ok(MsOsStringDescriptor {
+            vendor_code: data[16],
+            pad: data[17],
+        })
+    }
+}
+
+/// WebUSB URL descriptor.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct WebUsbUrl {
+    /// bScheme, URL scheme prefix: 0 for "http://",
+    /// 1 for "https://", 255 if `url` includes it.
+    pub scheme: u8,
+    /// URL without the scheme prefix.
+    pub url: String,
+}
+
+impl FromControlData for WebUsbUrl {
+    const LENGTH: u16 = 255;
+
+    fn from_control_data(data: &[u8]) -> AnyResult<Self> {
+        if data.len() < 3 || data[0] as usize != data.len() {
+            return Err(AnyUsbError::InvalidDescriptorLength);
+        }
+
+        if data[1] != 3 {
+            return Err(AnyUsbError::InvalidDescriptorType);
+        }
+
+        let url = String::from_utf8(data[3..].to_vec()).map_err(|_| AnyUsbError::DataConversion)?;
+        Ok(WebUsbUrl {
+            scheme: data[2],
+            url,
+        })
+    }
+}
@@ -0,0 +1,95 @@
+//! Example of fuzzing a class with random Control requests.
+//!
+//! `fuzz_control()` has the same shape as a `cargo-fuzz`
+//! target body, so it can be used as:
+//!
+//! ```ignore
+//! fuzz_target!(|data: &[u8]| fuzz_control(data));
+//! ```
+//!
+//! Here it is fed with pseudo-random data instead.
+mod test_device1;
+use test_device1::*;
+
+use arbitrary::{Arbitrary, Unstructured};
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+
+struct TestCtx {}
+
+impl UsbDeviceCtx for TestCtx {
+    type C<'c> = TestUsbClass;
+
+    fn create_class(&mut self, alloc: &UsbBusAllocator<EmulatedUsbBus>) -> AnyResult<TestUsbClass> {
+        Ok(TestUsbClass::new(alloc))
+    }
+}
+
+thread_local! {
+    static INPUT: std::cell::RefCell<Vec<u8>> = const { std::cell::RefCell::new(Vec::new()) };
+}
+
+/// Sends Control requests generated from `data` to the class.
+/// Any request may fail, but the Device must not panic and
+/// must still respond to standard requests afterwards.
+fn fuzz_control(data: &[u8]) {
+    INPUT.set(data.to_vec());
+
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let input = INPUT.take();
+            let mut u = Unstructured::new(&input);
+
+            // arbitrary fills missing data with zeroes, so stop explicitly
+            while !u.is_empty() {
+                let setup = SetupPacket::arbitrary(&mut u).expect("setup");
+                let bytes: [u8; 8] = setup.into();
+                let length = u16::from_le_bytes([bytes[6], bytes[7]]) as usize;
+                let mut buf = vec![0; length];
+
+                if bytes[0] & 0x80 == 0 {
+                    let Ok(data) = u.bytes(length.min(64)) else {
+                        break;
+                    };
+                    let data = data.to_vec();
+                    dev.ep0_unchecked(&mut cls, setup, Some(&data), &mut buf)
+                        .ok();
+                } else {
+                    dev.ep0(&mut cls, setup, None, &mut buf).ok();
+                }
+            }
+
+            dev.device_get_status(&mut cls).expect("status");
+        })
+        .expect("with_usb");
+}
+
+/// xorshift64 pseudo-random bytes.
+fn random_bytes(mut seed: u64, len: usize) -> Vec<u8> {
+    (0..len)
+        .map(|_| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed as u8
+        })
+        .collect()
+}
+
+#[test]
+fn test_arbitrary_setup_packet() {
+    let data = [0xa1, 0x01, 0x34, 0x12, 0x78, 0x56, 0x03, 0x00];
+    let setup = SetupPacket::arbitrary(&mut Unstructured::new(&data)).expect("setup");
+    assert_eq!(<[u8; 8]>::from(setup), data);
+
+    let reqt = CtrRequestType::arbitrary(&mut Unstructured::new(&[0xe3])).expect("reqt");
+    assert_eq!(u8::from(reqt), 0xe3);
+}
+
+#[test]
+fn test_arbitrary_fuzz_control() {
+    for seed in 1..=32 {
+        fuzz_control(&random_bytes(seed, 512));
+    }
+}
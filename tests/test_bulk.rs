@@ -0,0 +1,283 @@
+mod test_device4;
+use test_device4::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usb_device::endpoint::{EndpointAddress, EndpointType, Out};
+use usb_device::{UsbDirection, UsbError};
+
+struct TestCtx {}
+
+impl UsbDeviceCtx for TestCtx {
+    type C<'c> = LoopbackUsbClass<'c, EmulatedUsbBus>;
+
+    fn create_class<'a>(
+        &mut self,
+        alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<LoopbackUsbClass<'a, EmulatedUsbBus>> {
+        Ok(LoopbackUsbClass::new(alloc))
+    }
+}
+
+struct BufCtx<const SIZE: usize> {}
+
+impl<const SIZE: usize> UsbDeviceCtx for BufCtx<SIZE> {
+    type C<'c> = LoopbackUsbClass<'c, EmulatedUsbBus>;
+    const EP_BUFFER_SIZE: usize = SIZE;
+
+    fn create_class<'a>(
+        &mut self,
+        alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<LoopbackUsbClass<'a, EmulatedUsbBus>> {
+        Ok(LoopbackUsbClass::new(alloc))
+    }
+}
+
+struct WideCtx<const NUM: usize> {}
+
+impl<const NUM: usize> UsbDeviceCtx for WideCtx<NUM> {
+    type C<'c> = LoopbackUsbClass<'c, EmulatedUsbBus>;
+    const NUM_ENDPOINTS: usize = NUM;
+
+    fn create_class<'a>(
+        &mut self,
+        alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<LoopbackUsbClass<'a, EmulatedUsbBus>> {
+        LoopbackUsbClass::new_at(alloc, 15)
+            .map_err(|e| AnyUsbError::UserDefinedString(format!("{:?}", e)))
+    }
+}
+
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+#[test]
+fn test_bulk_loopback_64k() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_out.address().index();
+            let data = pattern(64 * 1024);
+
+            let len = dev.bulk_write(&mut cls, ep, &data).expect("write");
+            assert_eq!(len, data.len());
+
+            let read = dev.bulk_read(&mut cls, ep, data.len()).expect("read");
+            assert_eq!(read, data);
+
+            // nothing left
+            let read = dev.bulk_read(&mut cls, ep, 1024).expect("read");
+            assert!(read.is_empty());
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_bulk_loopback_interleaved() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_out.address().index();
+            let data = pattern(64 * 1024 + 10);
+
+            let mut received = Vec::new();
+            for chunk in data.chunks(4000) {
+                dev.bulk_write(&mut cls, ep, chunk).expect("write");
+                // ends on a short packet or when there is nothing to send
+                let read = dev.bulk_read(&mut cls, ep, 8192).expect("read");
+                assert_eq!(read.len(), chunk.len());
+                received.extend(read);
+            }
+            assert_eq!(received, data);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_bulk_read_overrun() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_out.address().index();
+
+            dev.bulk_write(&mut cls, ep, &[0x55; 100]).expect("write");
+            let res = dev.bulk_read(&mut cls, ep, 80);
+            assert_eq!(
+                res,
+                Err(AnyUsbError::HostReadBufferTooSmall {
+                    ep: cls.ep_in.address(),
+                    needed: 100,
+                    available: 80
+                })
+            );
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_bulk_write_stalled() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_out.address().index();
+            cls.ep_out.stall();
+
+            let res = dev.bulk_write(&mut cls, ep, &[0x55; 1000]);
+            assert_eq!(res, Err(AnyUsbError::EPStalled));
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_bulk_loopback_endpoint_15() {
+    WideCtx::<16> {}
+        .with_usb(|mut cls, mut dev| {
+            assert_eq!(cls.ep_out.address().index(), 15);
+            assert_eq!(cls.ep_in.address().index(), 15);
+
+            let data = pattern(1000);
+            dev.bulk_write(&mut cls, 15, &data).expect("write");
+            let read = dev.bulk_read(&mut cls, 15, 1024).expect("read");
+            assert_eq!(read, data);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_bulk_endpoint_15_not_available() {
+    let res = WideCtx::<8> {}.with_usb(|mut _cls, mut _dev| {
+        unreachable!("case should not run");
+    });
+    assert_eq!(
+        res,
+        Err(AnyUsbError::UserDefinedString("InvalidEndpoint".into()))
+    );
+}
+
+const EP3_OUT: u8 = 0x03;
+
+struct EpSizeCtx<const STRICT: bool, const SIZE: u16, const BUF: usize> {}
+
+impl<const STRICT: bool, const SIZE: u16, const BUF: usize> UsbDeviceCtx
+    for EpSizeCtx<STRICT, SIZE, BUF>
+{
+    type C<'c> = LoopbackUsbClass<'c, EmulatedUsbBus>;
+    const STRICT_EP_SIZE: bool = STRICT;
+    const EP_BUFFER_SIZE: usize = BUF;
+
+    fn create_class<'a>(
+        &mut self,
+        alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<LoopbackUsbClass<'a, EmulatedUsbBus>> {
+        alloc
+            .alloc::<Out>(Some(EP3_OUT.into()), EndpointType::Bulk, SIZE, 0)
+            .map_err(|e| AnyUsbError::UserDefinedString(format!("{:?}", e)))?;
+        Ok(LoopbackUsbClass::new(alloc))
+    }
+}
+
+#[test]
+fn test_bulk_ep_size_strict() {
+    // High Speed size, not allowed at Full Speed
+    let res = EpSizeCtx::<true, 512, 1024> {}.with_usb(|mut _cls, mut _dev| {
+        unreachable!("case should not run");
+    });
+    assert_eq!(
+        res,
+        Err(AnyUsbError::UserDefinedString("Unsupported".into()))
+    );
+
+    let res = EpSizeCtx::<true, 24, 1024> {}.with_usb(|mut _cls, mut _dev| {
+        unreachable!("case should not run");
+    });
+    assert_eq!(
+        res,
+        Err(AnyUsbError::UserDefinedString("Unsupported".into()))
+    );
+
+    let res = EpSizeCtx::<true, 64, 32> {}.with_usb(|mut _cls, mut _dev| {
+        unreachable!("case should not run");
+    });
+    assert_eq!(
+        res,
+        Err(AnyUsbError::UserDefinedString(
+            "EndpointMemoryOverflow".into()
+        ))
+    );
+
+    EpSizeCtx::<true, 32, 1024> {}
+        .with_usb(|_cls, dev| {
+            let ep = dev
+                .endpoints()
+                .into_iter()
+                .find(|ep| ep.address == EP3_OUT.into());
+            assert_eq!(ep.map(|ep| ep.max_packet_size), Some(32));
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_bulk_ep_size_lenient() {
+    EpSizeCtx::<false, 512, 64> {}
+        .with_usb(|_cls, dev| {
+            let ep = dev
+                .endpoints()
+                .into_iter()
+                .find(|ep| ep.address == EP3_OUT.into());
+            assert_eq!(ep.map(|ep| ep.max_packet_size), Some(512));
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_ep_buffer_size() {
+    EpSizeCtx::<false, 512, 256> {}
+        .with_usb(|cls, dev| {
+            // at least one packet
+            assert_eq!(dev.ep_buffer_size(EP3_OUT.into()), 512);
+            assert_eq!(dev.ep_buffer_size(cls.ep_out.address()), 256);
+            assert_eq!(dev.ep_buffer_size(cls.ep_in.address()), 256);
+
+            // not allocated
+            for index in [3, 7] {
+                let ep = EndpointAddress::from_parts(index, UsbDirection::In);
+                assert_eq!(dev.ep_buffer_size(ep), 0);
+            }
+            let ep = EndpointAddress::from_parts(
+                EpSizeCtx::<false, 512, 256>::NUM_ENDPOINTS,
+                UsbDirection::Out,
+            );
+            assert_eq!(dev.ep_buffer_size(ep), 0);
+        })
+        .expect("with_usb");
+}
+
+fn check_loopback_100k<const SIZE: usize>() {
+    BufCtx::<SIZE> {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_out.address().index();
+            let data = pattern(100 * 1024);
+
+            let len = dev.bulk_write(&mut cls, ep, &data).expect("write");
+            assert_eq!(len, data.len());
+            let read = dev.bulk_read(&mut cls, ep, data.len()).expect("read");
+            assert_eq!(read, data);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_bulk_loopback_100k_buffer_size() {
+    check_loopback_100k::<1024>();
+    check_loopback_100k::<{ EP_SIZE as usize }>();
+    check_loopback_100k::<{ 128 * 1024 }>();
+}
+
+#[test]
+fn test_bulk_buffer_full() {
+    BufCtx::<{ EP_SIZE as usize }> {}
+        .with_usb(|cls, _dev| {
+            cls.ep_in.write(&[0x55; EP_SIZE as usize]).expect("write");
+            let res = cls.ep_in.write(&[0x55; 1]);
+            assert_eq!(res, Err(UsbError::WouldBlock));
+        })
+        .expect("with_usb");
+}
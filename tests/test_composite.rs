@@ -0,0 +1,85 @@
+mod test_device1;
+use test_device1::*;
+
+mod test_device2;
+use test_device2::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+
+struct TestCtx {}
+
+impl UsbDeviceCtx for TestCtx {
+    type C<'c> = Composite<TestUsbClass, EpTestUsbClass<'c, EmulatedUsbBus>>;
+
+    fn create_class<'a>(
+        &mut self,
+        alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<Composite<TestUsbClass, EpTestUsbClass<'a, EmulatedUsbBus>>> {
+        Ok(Composite(
+            TestUsbClass::new(alloc),
+            EpTestUsbClass::new(alloc),
+        ))
+    }
+}
+
+#[test]
+fn test_composite_configuration_descriptor() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let desc = dev
+                .device_get_descriptor(&mut cls, 2, 0, 0, 255)
+                .expect("descriptor");
+
+            // bNumInterfaces
+            assert_eq!(desc[4], 2);
+
+            let mut interfaces = Vec::new();
+            let mut rest = &desc[..];
+            while !rest.is_empty() {
+                let (d, next) = rest.split_at(rest[0] as usize);
+                if d[1] == 4 {
+                    interfaces.push(d[2]);
+                }
+                rest = next;
+            }
+            assert_eq!(interfaces, [u8::from(cls.0.iface), u8::from(cls.1.iface)]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_composite_control_and_endpoints() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let iface = u8::from(cls.0.iface) as u16;
+            let reqt = CtrRequestType::to_device().class().interface();
+
+            dev.control_write(&mut cls, reqt, 2, 0, iface, &[0x42])
+                .expect("write");
+            assert_eq!(cls.0.byte, 0x42);
+
+            let vec = dev
+                .control_read(
+                    &mut cls,
+                    CtrRequestType::to_host().class().interface(),
+                    1,
+                    0,
+                    iface,
+                    8,
+                )
+                .expect("read");
+            assert_eq!(vec, [1, 2, 0x42]);
+
+            // the second class doesn't handle this request
+            let other = u8::from(cls.1.iface) as u16;
+            let res = dev.control_write(&mut cls, reqt, 2, 0, other, &[0x42]);
+            assert_eq!(res, Err(AnyUsbError::EPStalled));
+
+            let ep = cls.1.ep_out.address().index();
+            dev.ep_write(&mut cls, ep, &[1, 2, 3]).expect("write");
+            assert_eq!(cls.1.received, [1, 2, 3]);
+        })
+        .expect("with_usb");
+}
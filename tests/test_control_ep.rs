@@ -0,0 +1,245 @@
+mod test_device3;
+use test_device3::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+
+struct TestCtx {}
+
+impl UsbDeviceCtx for TestCtx {
+    type C<'c> = CtlTestUsbClass<'c, EmulatedUsbBus>;
+
+    fn create_class<'a>(
+        &mut self,
+        alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<CtlTestUsbClass<'a, EmulatedUsbBus>> {
+        Ok(CtlTestUsbClass::new(alloc))
+    }
+}
+
+struct NonStrictCtx {}
+
+impl UsbDeviceCtx for NonStrictCtx {
+    type C<'c> = CtlTestUsbClass<'c, EmulatedUsbBus>;
+    const STRICT_RESPONSE_LENGTH: bool = false;
+
+    fn create_class<'a>(
+        &mut self,
+        alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<CtlTestUsbClass<'a, EmulatedUsbBus>> {
+        Ok(CtlTestUsbClass::new(alloc))
+    }
+}
+
+struct StrictZlpCtx {}
+
+impl UsbDeviceCtx for StrictZlpCtx {
+    type C<'c> = CtlTestUsbClass<'c, EmulatedUsbBus>;
+    const STRICT_ZLP: bool = true;
+
+    fn create_class<'a>(
+        &mut self,
+        alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<CtlTestUsbClass<'a, EmulatedUsbBus>> {
+        Ok(CtlTestUsbClass::new(alloc))
+    }
+}
+
+fn get_status(length: u16) -> [u8; 8] {
+    SetupPacket::new(CtrRequestType::to_host().interface(), 0, 0, 0, length).into()
+}
+
+#[test]
+fn test_control_ep_response() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_out.address().index();
+            cls.reply = vec![1, 2];
+
+            let mut buf = [0; 16];
+            let res = dev
+                .ep_raw(&mut cls, ep, Some(&get_status(2)), None, &mut buf)
+                .expect("res");
+            assert_eq!(buf[..res.read.unwrap()], [1, 2]);
+
+            // the class doesn't handle the status stage
+            assert!(!res.status_ok);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_control_ep_response_too_long() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_out.address().index();
+            cls.reply = vec![1, 2, 3, 4];
+
+            let mut buf = [0; 16];
+            let res = dev.ep_raw(&mut cls, ep, Some(&get_status(2)), None, &mut buf);
+            assert_eq!(
+                res.err(),
+                Some(AnyUsbError::ResponseTooLong {
+                    expected: 2,
+                    got: 4
+                })
+            );
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_control_ep_response_too_long_second_packet() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_out.address().index();
+            cls.reply = (0..12).collect();
+
+            let mut buf = [0; 16];
+            let res = dev.ep_raw(&mut cls, ep, Some(&get_status(10)), None, &mut buf);
+            assert_eq!(
+                res.err(),
+                Some(AnyUsbError::ResponseTooLong {
+                    expected: 10,
+                    got: 12
+                })
+            );
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_control_ep_response_too_long_non_strict() {
+    NonStrictCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_out.address().index();
+            cls.reply = vec![1, 2, 3, 4];
+
+            let mut buf = [0; 16];
+            let res = dev
+                .ep_raw(&mut cls, ep, Some(&get_status(2)), None, &mut buf)
+                .expect("res");
+            assert_eq!(buf[..res.read.unwrap()], [1, 2, 3, 4]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_control_ep_response_overrun_non_strict() {
+    NonStrictCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_out.address().index();
+            cls.reply = (0..12).collect();
+
+            let mut buf = [0; 10];
+            let res = dev.ep_raw(&mut cls, ep, Some(&get_status(10)), None, &mut buf);
+            assert_eq!(
+                res.err(),
+                Some(AnyUsbError::HostReadBufferTooSmall {
+                    ep: cls.ep_in.address(),
+                    needed: 12,
+                    available: 10
+                })
+            );
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_control_ep_response_no_buffer() {
+    NonStrictCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_out.address().index();
+            cls.reply = (0..12).collect();
+
+            let res = dev.ep_raw(&mut cls, ep, Some(&get_status(10)), None, &mut []);
+            assert_eq!(
+                res.err(),
+                Some(AnyUsbError::HostReadBufferTooSmall {
+                    ep: cls.ep_in.address(),
+                    needed: 8,
+                    available: 0
+                })
+            );
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_control_ep0_response_no_buffer() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            // GET_DESCRIPTOR Device, 18 bytes
+            let setup: [u8; 8] =
+                SetupPacket::new(CtrRequestType::to_host(), 6, 0x0100, 0, 18).into();
+
+            let mut buf = [0; 8];
+            let res = dev.ep_raw(&mut cls, 0, Some(&setup), None, &mut buf);
+            assert_eq!(
+                res.err(),
+                Some(AnyUsbError::EP0Overrun { buffer: 8, got: 16 })
+            );
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_control_ep_missing_zlp() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_out.address().index();
+            cls.reply = (0..16).collect();
+
+            // class doesn't send a ZLP, data stage ends anyway
+            let mut buf = [0; 32];
+            let res = dev
+                .ep_raw(&mut cls, ep, Some(&get_status(32)), None, &mut buf)
+                .expect("res");
+            assert_eq!(res.read, Some(16));
+            assert!(!res.zlp);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_control_ep_missing_zlp_strict() {
+    StrictZlpCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_out.address().index();
+            let mut buf = [0; 32];
+
+            cls.reply = (0..16).collect();
+            let res = dev.ep_raw(&mut cls, ep, Some(&get_status(32)), None, &mut buf);
+            assert_eq!(res.err(), Some(AnyUsbError::MissingZlp));
+
+            // no ZLP is needed when wLength bytes were sent
+            let res = dev
+                .ep_raw(&mut cls, ep, Some(&get_status(16)), None, &mut buf)
+                .expect("res");
+            assert_eq!(res.read, Some(16));
+
+            // short packet ends the data stage
+            cls.reply = (0..12).collect();
+            let res = dev
+                .ep_raw(&mut cls, ep, Some(&get_status(32)), None, &mut buf)
+                .expect("res");
+            assert_eq!(res.read, Some(12));
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_control_ep_unexpected_in_data() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_out.address().index();
+            cls.reply = vec![1, 2];
+            cls.reply_to_device = true;
+
+            let setup: [u8; 8] = SetupPacket::new(CtrRequestType::to_device(), 1, 0, 0, 0).into();
+            let res = dev.ep_raw_write(&mut cls, ep, Some(&setup), &[]);
+            assert_eq!(res.err(), Some(AnyUsbError::UnexpectedInData));
+        })
+        .expect("with_usb");
+}
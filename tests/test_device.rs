@@ -1,3 +1,10 @@
+#![allow(
+    clippy::needless_lifetimes,
+    clippy::needless_borrow,
+    clippy::bool_assert_comparison,
+    clippy::map_flatten
+)]
+
 mod test_device1;
 use test_device1::*;
 
@@ -6,7 +13,9 @@ use usbd_class_tester::prelude::*;
 use usb_device::{
     bus::{UsbBus, UsbBusAllocator},
     class::UsbClass,
-    device::UsbDeviceState,
+    device::{StringDescriptors, UsbDevice, UsbDeviceBuilder, UsbDeviceState, UsbVidPid},
+    endpoint::EndpointAddress,
+    UsbDirection,
 };
 
 #[derive(Default)]
@@ -56,6 +65,38 @@ fn test_device_get_status_set_self_powered() {
         .expect("with_usb");
 }
 
+#[test]
+fn test_device_get_status_typed() {
+    TestCtx::new()
+        .with_usb(|mut cls, mut dev| {
+            let status = dev.device_get_status_typed(&mut cls).expect("status");
+            assert_eq!(status, DeviceStatus::default());
+
+            dev.usb_dev().set_self_powered(true);
+            let status = dev.device_get_status_typed(&mut cls).expect("status");
+            assert_eq!(
+                status,
+                DeviceStatus {
+                    self_powered: true,
+                    remote_wakeup: false
+                }
+            );
+
+            dev.usb_dev().set_self_powered(false);
+            dev.device_set_feature(&mut cls, Feature::DeviceRemoteWakeup.into())
+                .expect("remote wakeup");
+            let status = dev.device_get_status_typed(&mut cls).expect("status");
+            assert_eq!(
+                status,
+                DeviceStatus {
+                    self_powered: false,
+                    remote_wakeup: true
+                }
+            );
+        })
+        .expect("with_usb");
+}
+
 #[test]
 fn test_device_feature_remote_wakeup() {
     TestCtx::new()
@@ -73,11 +114,23 @@ fn test_device_feature_remote_wakeup() {
 fn test_device_address_set() {
     TestCtx::new()
         .with_usb(|mut _cls, mut dev| {
+            assert_eq!(dev.assigned_address(), TestCtx::ADDRESS);
             assert_eq!(dev.usb_dev().bus().get_address(), TestCtx::ADDRESS);
         })
         .expect("with_usb");
 }
 
+#[test]
+fn test_device_assigned_address() {
+    TestCtx::no_setup()
+        .with_usb(|mut cls, mut dev| {
+            assert_eq!(dev.assigned_address(), 0);
+            dev.device_set_address(&mut cls, 12).expect("address");
+            assert_eq!(dev.assigned_address(), 12);
+        })
+        .expect("with_usb");
+}
+
 #[test]
 fn test_device_configured() {
     TestCtx::new()
@@ -167,6 +220,157 @@ fn test_device_get_strings() {
         .expect("with_usb");
 }
 
+struct EmojiCtx {}
+
+impl UsbDeviceCtx for EmojiCtx {
+    type C<'c> = TestUsbClass;
+
+    fn create_class(&mut self, alloc: &UsbBusAllocator<EmulatedUsbBus>) -> AnyResult<TestUsbClass> {
+        Ok(TestUsbClass::new(alloc))
+    }
+
+    fn build_usb_device<'a>(
+        &mut self,
+        alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<UsbDevice<'a, EmulatedUsbBus>> {
+        Ok(UsbDeviceBuilder::new(alloc, UsbVidPid(0x1234, 0x5678))
+            .strings(&[StringDescriptors::default().product("Crab \u{1f980}")])
+            .map_err(AnyUsbError::UsbDeviceBuilder)?
+            .build())
+    }
+}
+
+#[test]
+fn test_device_get_string_raw() {
+    EmojiCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let (string, raw) = dev
+                .device_get_string_raw(&mut cls, 2, 0x409)
+                .expect("string");
+            assert_eq!(string, "Crab \u{1f980}");
+            assert_eq!(raw[..2], [16, 3]);
+            assert_eq!(raw[2..12], [b'C', 0, b'r', 0, b'a', 0, b'b', 0, b' ', 0]);
+            // surrogate pair
+            assert_eq!(raw[12..], [0x3e, 0xd8, 0x80, 0xdd]);
+        })
+        .expect("with_usb");
+}
+
+struct VidPidCtx {}
+
+impl UsbDeviceCtx for VidPidCtx {
+    type C<'c> = TestUsbClass;
+
+    fn create_class(&mut self, alloc: &UsbBusAllocator<EmulatedUsbBus>) -> AnyResult<TestUsbClass> {
+        Ok(TestUsbClass::new(alloc))
+    }
+
+    fn vid_pid(&mut self) -> UsbVidPid {
+        UsbVidPid(0xcafe, 0xbabe)
+    }
+
+    fn string_descriptors(&mut self) -> StringDescriptors<'static> {
+        StringDescriptors::default().product("Custom")
+    }
+}
+
+#[test]
+fn test_device_vid_pid_strings() {
+    VidPidCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let descr = dev
+                .device_get_descriptor(&mut cls, 1, 0, 0, 18)
+                .expect("descriptor");
+            assert_eq!(descr[8..12], [0xfe, 0xca, 0xbe, 0xba]);
+            // iManufacturer, iProduct, iSerialNumber
+            assert_eq!(descr[14..17], [0, 2, 0]);
+
+            let product = dev.device_get_string(&mut cls, 2, 0x409).expect("string");
+            assert_eq!(product, "Custom");
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_device_get_descriptor_parsed() {
+    VidPidCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let res = dev.device_get_descriptor_parsed(&mut cls, 1, 0, 0);
+            let Ok(Descriptor::Device(descr)) = res else {
+                panic!("unexpected {:?}", res);
+            };
+            assert_eq!(descr.vendor_id, 0xcafe);
+            assert_eq!(descr.product_id, 0xbabe);
+            assert_eq!(descr.max_packet_size_0, 8);
+            assert_eq!(descr.product, 2);
+            assert_eq!(descr.num_configurations, 1);
+
+            let res = dev.device_get_descriptor_parsed(&mut cls, 2, 0, 0);
+            let Ok(Descriptor::Configuration(conf)) = res else {
+                panic!("unexpected {:?}", res);
+            };
+            let raw = dev.raw_configuration_descriptor(&mut cls).expect("raw");
+            assert_eq!(conf.total_length as usize, raw.len());
+            assert_eq!(conf.num_interfaces, 1);
+            assert_eq!(conf.configuration_value, 1);
+
+            let res = dev.device_get_descriptor_parsed(&mut cls, 3, 0, 0);
+            assert_eq!(res, Ok(Descriptor::LanguageIds(vec![0x409])));
+
+            let res = dev.device_get_descriptor_parsed(&mut cls, 3, 2, 0x409);
+            assert_eq!(res, Ok(Descriptor::String("Custom".into())));
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_descriptors_parse() {
+    use usbd_class_tester::descriptors::{parse, EndpointDescriptor, InterfaceDescriptor};
+
+    let res = parse(&[9, 4, 1, 0, 2, 0xff, 0, 0, 5, 7, 5, 0x81, 2, 64, 0, 0]);
+    assert_eq!(
+        res,
+        Ok(Descriptor::Interface(InterfaceDescriptor {
+            interface_number: 1,
+            alternate_setting: 0,
+            num_endpoints: 2,
+            interface_class: 0xff,
+            interface_sub_class: 0,
+            interface_protocol: 0,
+            interface: 5,
+        }))
+    );
+
+    let res = parse(&[7, 5, 0x81, 3, 8, 0, 10]);
+    assert_eq!(
+        res,
+        Ok(Descriptor::Endpoint(EndpointDescriptor {
+            address: 0x81,
+            attributes: 3,
+            max_packet_size: 8,
+            interval: 10,
+        }))
+    );
+
+    // HID descriptor
+    let hid = [9, 0x21, 0x11, 0x01, 0, 1, 0x22, 0x34, 0];
+    let res = parse(&hid);
+    assert_eq!(
+        res,
+        Ok(Descriptor::Raw {
+            b_descriptor_type: 0x21,
+            data: hid.to_vec(),
+        })
+    );
+
+    assert_eq!(parse(&[]), Err(AnyUsbError::InvalidDescriptorLength));
+    assert_eq!(parse(&[9, 4, 1]), Err(AnyUsbError::InvalidDescriptorLength));
+    assert_eq!(
+        parse(&[4, 5, 0x81, 3]),
+        Err(AnyUsbError::InvalidDescriptorLength)
+    );
+}
+
 #[test]
 fn test_interface_get_status() {
     TestCtx::new()
@@ -177,6 +381,68 @@ fn test_interface_get_status() {
         .expect("with_usb");
 }
 
+struct BadStatusUsbClass {}
+
+impl<B: UsbBus> UsbClass<B> for BadStatusUsbClass {
+    fn control_in(&mut self, xfer: usb_device::class::ControlIn<B>) {
+        let req = xfer.request();
+        if req.request_type != usb_device::control::RequestType::Standard
+            || req.request != usb_device::control::Request::GET_STATUS
+            || req.index != 1
+        {
+            return;
+        }
+        match req.recipient {
+            usb_device::control::Recipient::Interface => {
+                xfer.accept_with(&[0x02, 0x80]).ok();
+            }
+            usb_device::control::Recipient::Endpoint => {
+                xfer.accept_with(&[0x03, 0x00]).ok();
+            }
+            _ => {}
+        }
+    }
+}
+
+struct BadStatusCtx {}
+
+impl UsbDeviceCtx for BadStatusCtx {
+    type C<'c> = Composite<BadStatusUsbClass, TestUsbClass>;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<Composite<BadStatusUsbClass, TestUsbClass>> {
+        Ok(Composite(BadStatusUsbClass {}, TestUsbClass::new(alloc)))
+    }
+}
+
+#[test]
+fn test_get_status_strict() {
+    BadStatusCtx {}
+        .with_usb(|mut cls, mut dev| {
+            assert_eq!(dev.interface_get_status_strict(&mut cls, 0), Ok(0));
+            assert_eq!(dev.endpoint_get_status_strict(&mut cls, 0), Ok(0));
+
+            assert_eq!(dev.interface_get_status(&mut cls, 1), Ok(0x8002));
+            assert_eq!(
+                dev.interface_get_status_strict(&mut cls, 1),
+                Err(AnyUsbError::ReservedStatusBits {
+                    status: 0x8002,
+                    reserved: 0x8002,
+                })
+            );
+            assert_eq!(
+                dev.endpoint_get_status_strict(&mut cls, 1),
+                Err(AnyUsbError::ReservedStatusBits {
+                    status: 0x0003,
+                    reserved: 0x0002,
+                })
+            );
+        })
+        .expect("with_usb");
+}
+
 #[test]
 fn test_interface_alt_interface() {
     TestCtx::new()
@@ -199,6 +465,34 @@ fn test_interface_alt_interface() {
         .expect("with_usb");
 }
 
+#[test]
+fn test_interface_set_interface_checked() {
+    TestCtx::new()
+        .with_usb(|mut cls, mut dev| {
+            let iface = u8::from(cls.iface);
+
+            dev.interface_set_interface_checked(&mut cls, iface, 1)
+                .expect("set_interface");
+            dev.interface_set_interface_checked(&mut cls, iface, 0)
+                .expect("set_interface");
+
+            cls.ignore_alt_setting = true;
+            let res = dev.interface_set_interface_checked(&mut cls, iface, 2);
+            assert_eq!(
+                res,
+                Err(AnyUsbError::AltSettingMismatch {
+                    requested: 2,
+                    actual: 0
+                })
+            );
+
+            // no such interface
+            let res = dev.interface_set_interface_checked(&mut cls, iface + 1, 1);
+            assert_eq!(res, Err(AnyUsbError::EP0Stalled));
+        })
+        .expect("with_usb");
+}
+
 #[test]
 fn test_interface_get_set_feature() {
     TestCtx::new()
@@ -235,7 +529,6 @@ fn test_device_custom_control_command() {
                 2,
                 0,
                 0,
-                0,
                 &[],
             )
             .expect_err("stall");
@@ -247,7 +540,6 @@ fn test_device_custom_control_command() {
                     2,
                     0,
                     0,
-                    1,
                     &[0xaa],
                 )
                 .expect("res");
@@ -268,31 +560,1366 @@ fn test_device_custom_control_command() {
         .expect("with_usb");
 }
 
-struct FailTestUsbClass {}
+#[test]
+fn test_device_get_configuration_descriptor() {
+    TestCtx::new()
+        .with_usb(|mut cls, mut dev| {
+            let conf = dev
+                .device_get_configuration_descriptor(&mut cls, 0)
+                .expect("conf");
+            let total = u16::from_le_bytes([conf[2], conf[3]]);
+            assert_eq!(conf.len(), total as usize);
+            assert_eq!(conf[1], 2);
+            // vendor descriptor written by the class is the last one
+            assert_eq!(conf[conf.len() - 12..conf.len() - 10], [12, 200]);
+        })
+        .expect("with_usb");
+}
 
-impl<B: UsbBus> UsbClass<B> for FailTestUsbClass {}
+#[test]
+fn test_control_read_bounded() {
+    TestCtx::new()
+        .with_usb(|mut cls, mut dev| {
+            let setup = SetupPacket::new(CtrRequestType::to_host(), 0, 0, 0, 2);
+            let mut buf = [0; 2];
+            let res = dev.ep0(&mut cls, setup, None, &mut buf).expect("ep0");
+            let polls = res.polls;
 
-struct FailTestCtx {}
+            let status = dev
+                .control_read_bounded(&mut cls, CtrRequestType::to_host(), 0, 0, 0, 2, polls)
+                .expect("status");
+            assert_eq!(status, [0, 0]);
 
-impl UsbDeviceCtx for FailTestCtx {
-    type C<'c> = FailTestUsbClass;
+            let res = dev.control_read_bounded(
+                &mut cls,
+                CtrRequestType::to_host(),
+                0,
+                0,
+                0,
+                2,
+                polls - 1,
+            );
+            assert_eq!(
+                res,
+                Err(AnyUsbError::PollLimitExceeded {
+                    max_polls: polls - 1,
+                    polls
+                })
+            );
+        })
+        .expect("with_usb");
+}
 
-    const ADDRESS: u8 = 55;
+#[test]
+fn test_manual_setup() {
+    TestCtx::no_setup()
+        .with_usb(|mut cls, mut dev| {
+            assert_eq!(dev.usb_dev().state(), UsbDeviceState::Default);
+            assert_eq!(dev.device_get_configuration(&mut cls), Ok(0));
 
-    fn create_class<'a>(
-        &mut self,
-        _alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
-    ) -> AnyResult<FailTestUsbClass> {
-        Err(AnyUsbError::UserDefined1)
-    }
+            dev.setup(&mut cls).expect("setup");
+            assert_eq!(dev.usb_dev().state(), UsbDeviceState::Configured);
+            assert_eq!(dev.device_get_configuration(&mut cls), Ok(1));
+
+            let res = dev.setup(&mut cls);
+            assert_eq!(
+                res,
+                Err(AnyUsbError::AlreadySetUp {
+                    state: UsbDeviceState::Configured
+                })
+            );
+        })
+        .expect("with_usb");
 }
 
 #[test]
-#[should_panic(expected = "with_usb: UserDefined1")]
-fn test_create_class_fails() {
-    FailTestCtx {}
-        .with_usb(|mut _cls, mut _dev| {
-            unreachable!("case should not run");
+fn test_setup_twice() {
+    TestCtx::new()
+        .with_usb(|mut cls, mut dev| {
+            dev.device_unconfigure(&mut cls).expect("unconfigure");
+            let res = dev.setup(&mut cls);
+            assert_eq!(
+                res,
+                Err(AnyUsbError::AlreadySetUp {
+                    state: UsbDeviceState::Addressed
+                })
+            );
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_bus_reset() {
+    TestCtx::new()
+        .with_usb(|mut cls, mut dev| {
+            assert_eq!(dev.assigned_address(), TestCtx::ADDRESS);
+            dev.device_set_feature(&mut cls, Feature::DeviceRemoteWakeup.into())
+                .expect("remote wakeup");
+
+            dev.bus_reset(&mut cls);
+            assert_eq!(dev.usb_dev().state(), UsbDeviceState::Default);
+            assert_eq!(dev.assigned_address(), 0);
+            assert_eq!(dev.check_default_address(), Ok(()));
+            assert!(!dev.usb_dev().remote_wakeup_enabled());
+            assert_eq!(dev.device_get_configuration(&mut cls), Ok(0));
+
+            dev.setup(&mut cls).expect("setup");
+            assert_eq!(dev.usb_dev().state(), UsbDeviceState::Configured);
+            assert_eq!(dev.assigned_address(), TestCtx::ADDRESS);
+            assert_eq!(dev.device_get_configuration(&mut cls), Ok(1));
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_suspend_resume() {
+    TestCtx::new()
+        .with_usb(|mut cls, mut dev| {
+            dev.suspend(&mut cls);
+            assert_eq!(dev.usb_dev().state(), UsbDeviceState::Suspend);
+            assert!(dev.is_suspended());
+            assert_eq!(
+                dev.device_get_configuration(&mut cls),
+                Err(AnyUsbError::Suspended)
+            );
+
+            // nothing happens on the bus
+            dev.poll(&mut cls);
+            assert_eq!(dev.usb_dev().state(), UsbDeviceState::Suspend);
+
+            dev.resume(&mut cls);
+            assert_eq!(dev.usb_dev().state(), UsbDeviceState::Configured);
+            assert!(!dev.is_suspended());
+            assert_eq!(dev.device_get_configuration(&mut cls), Ok(1));
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_suspend_bus_reset() {
+    TestCtx::new()
+        .with_usb(|mut cls, mut dev| {
+            dev.suspend(&mut cls);
+            dev.bus_reset(&mut cls);
+            assert_eq!(dev.usb_dev().state(), UsbDeviceState::Default);
+            assert!(!dev.is_suspended());
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_address_not_cleared() {
+    TestCtx::new()
+        .with_usb(|mut cls, mut dev| {
+            assert_eq!(dev.check_default_address(), Ok(()));
+
+            dev.bus_reset(&mut cls);
+            // a stale address, as if the bus didn't clear it
+            dev.usb_dev().bus().set_device_address(5);
+            assert_eq!(
+                dev.check_default_address(),
+                Err(AnyUsbError::AddressNotCleared { address: 5 })
+            );
+            assert_eq!(
+                dev.setup(&mut cls),
+                Err(AnyUsbError::AddressNotCleared { address: 5 })
+            );
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_force_reset() {
+    TestCtx::new()
+        .with_usb(|mut cls, mut dev| {
+            assert_eq!(dev.forced_resets(), 0);
+
+            // a detach-like request, the firmware resets the bus after it
+            dev.control_write(
+                &mut cls,
+                CtrRequestType::to_device().class().interface(),
+                3,
+                0,
+                0,
+                b"detach",
+            )
+            .expect("detach");
+            assert_eq!(cls.payload, b"detach");
+            dev.usb_dev().force_reset().expect("force_reset");
+            assert_eq!(dev.forced_resets(), 1);
+            assert_eq!(dev.usb_dev().state(), UsbDeviceState::Configured);
+
+            dev.poll(&mut cls);
+            assert_eq!(dev.usb_dev().state(), UsbDeviceState::Default);
+            assert_eq!(dev.assigned_address(), 0);
+            assert_eq!(dev.check_default_address(), Ok(()));
+            assert_eq!(dev.forced_resets(), 1);
+
+            dev.setup(&mut cls).expect("setup");
+            assert_eq!(dev.usb_dev().state(), UsbDeviceState::Configured);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_raw_configuration_descriptor() {
+    TestCtx::new()
+        .with_usb(|mut cls, mut dev| {
+            let raw = dev.raw_configuration_descriptor(&mut cls).expect("raw");
+            let total = u16::from_le_bytes([raw[2], raw[3]]);
+            assert_eq!(raw.len(), total as usize);
+            assert_eq!(raw[0..2], [9, 2]);
+
+            let conf = dev
+                .device_get_configuration_descriptor(&mut cls, 0)
+                .expect("conf");
+            assert_eq!(raw, conf);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_device_get_config_descriptor_raw_index() {
+    TestCtx::new()
+        .with_usb(|mut cls, mut dev| {
+            cls.configurations = Some(1);
+
+            let conf = dev
+                .device_get_config_descriptor_raw(&mut cls, 0, 9)
+                .expect("conf");
+            assert_eq!(conf.len(), 9);
+            assert_eq!(conf[1], 2);
+
+            let res = dev.device_get_config_descriptor_raw(&mut cls, 1, 9);
+            assert_eq!(res, Err(AnyUsbError::EP0Stalled));
+
+            let res = dev.device_get_configuration_descriptor(&mut cls, 1);
+            assert_eq!(res, Err(AnyUsbError::EP0Stalled));
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_device_control_read_empty() {
+    TestCtx::new()
+        .with_usb(|mut cls, mut dev| {
+            let reqt = CtrRequestType::to_host().class().interface();
+            let mut buf = [0; 256];
+            cls.payload = Vec::new();
+
+            let res = dev
+                .control_read(&mut cls, reqt, 4, 0, 0, 200)
+                .expect("read");
+            assert_eq!(res, []);
+
+            let setup = SetupPacket::new(reqt, 4, 0, 0, 200);
+            let res = dev.ep0(&mut cls, setup, None, &mut buf).expect("res");
+            assert_eq!(res.read, Some(0));
+            assert_eq!(res.packets_in, 1);
+            assert!(res.zlp);
+            assert!(res.status_ok);
+
+            // the Device is still responsive
+            cls.payload = vec![1, 2, 3];
+            let res = dev
+                .control_read(&mut cls, reqt, 4, 0, 0, 200)
+                .expect("read");
+            assert_eq!(res, [1, 2, 3]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_device_control_in_outcome() {
+    TestCtx::new()
+        .with_usb(|mut cls, mut dev| {
+            let reqt = CtrRequestType::to_host().class().interface();
+
+            cls.payload = vec![1, 2, 3];
+            let res = dev.control_in_outcome(&mut cls, reqt, 4, 0, 0, 200);
+            assert_eq!(res, Ok(ControlOutcome::Data(vec![1, 2, 3])));
+
+            cls.payload = Vec::new();
+            let res = dev.control_in_outcome(&mut cls, reqt, 4, 0, 0, 200);
+            assert_eq!(res, Ok(ControlOutcome::Empty));
+
+            // rejected by the class
+            let res = dev.control_in_outcome(&mut cls, reqt, 5, 0, 0, 200);
+            assert_eq!(res, Ok(ControlOutcome::Stalled));
+
+            // not handled by any class
+            let reqt = CtrRequestType::to_host().vendor().device();
+            let res = dev.control_in_outcome(&mut cls, reqt, 1, 0, 0, 200);
+            assert_eq!(res, Ok(ControlOutcome::Stalled));
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_last_control_handled() {
+    TestCtx::new()
+        .with_usb(|mut cls, mut dev| {
+            let reqt = CtrRequestType::to_host().class().interface();
+
+            dev.control_read(&mut cls, reqt, 1, 0, 0, 8)
+                .expect("accepted");
+            assert!(dev.last_control_handled());
+
+            // rejected by the class
+            let res = dev.control_read(&mut cls, reqt, 5, 0, 0, 8);
+            assert_eq!(res, Err(AnyUsbError::EP0Stalled));
+            assert!(dev.last_control_handled());
+
+            // not handled by any class
+            let reqt = CtrRequestType::to_host().vendor().device();
+            let res = dev.control_read(&mut cls, reqt, 1, 0, 0, 8);
+            assert_eq!(res, Err(AnyUsbError::EP0Stalled));
+            assert!(!dev.last_control_handled());
+
+            // handled by usb-device
+            dev.device_get_status(&mut cls).expect("status");
+            assert!(!dev.last_control_handled());
+
+            let reqt = CtrRequestType::to_device().class().interface();
+            dev.control_write(&mut cls, reqt, 2, 0, 0, &[1])
+                .expect("accepted");
+            assert!(dev.last_control_handled());
         })
         .expect("with_usb");
 }
+
+#[test]
+fn test_device_control_read_full() {
+    TestCtx::new()
+        .with_usb(|mut cls, mut dev| {
+            let reqt = CtrRequestType::to_host().class().interface();
+            let len16 = |d: &[u8]| Some(u16::from_le_bytes([*d.first()?, *d.get(1)?]));
+
+            cls.payload = [&[20, 0], &[0x55; 18][..]].concat();
+            let res = dev
+                .control_read_full(&mut cls, reqt, 4, 0, 0, 2, len16)
+                .expect("full");
+            assert_eq!(res, cls.payload);
+
+            // complete response in the prefix, truncated to its length
+            cls.payload = vec![3, 0, 1, 2, 3];
+            let res = dev
+                .control_read_full(&mut cls, reqt, 4, 0, 0, 8, len16)
+                .expect("full");
+            assert_eq!(res, [3, 0, 1]);
+
+            // Device sends less than announced
+            cls.payload = [&[20, 0], &[0x55; 8][..]].concat();
+            let res = dev.control_read_full(&mut cls, reqt, 4, 0, 0, 2, len16);
+            assert_eq!(
+                res,
+                Err(AnyUsbError::ResponseTooShort {
+                    expected: 20,
+                    got: 10
+                })
+            );
+
+            // length field is not in the prefix
+            cls.payload = vec![1];
+            let res = dev.control_read_full(&mut cls, reqt, 4, 0, 0, 2, len16);
+            assert_eq!(
+                res,
+                Err(AnyUsbError::ResponseTooShort {
+                    expected: 2,
+                    got: 1
+                })
+            );
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_device_control_read_as() {
+    TestCtx::new()
+        .with_usb(|mut cls, mut dev| {
+            let reqt = CtrRequestType::to_host().class().interface();
+            cls.byte = 0xaa;
+
+            let res: [u8; 3] = dev.control_read_as(&mut cls, reqt, 1, 0, 0).expect("res");
+            assert_eq!(res, [1, 2, 0xaa]);
+
+            let res: u16 = dev.control_read_as(&mut cls, reqt, 1, 0, 0).expect("res");
+            assert_eq!(res, 0x0201);
+
+            let res = dev.control_read_as::<u32>(&mut cls, reqt, 1, 0, 0);
+            assert_eq!(
+                res,
+                Err(AnyUsbError::ResponseTooShort {
+                    expected: 4,
+                    got: 3
+                })
+            );
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_from_control_data_length() {
+    assert_eq!(<[u8; 2]>::from_control_data(&[1, 2]), Ok([1, 2]));
+    assert_eq!(
+        <[u8; 2]>::from_control_data(&[1]),
+        Err(AnyUsbError::ResponseTooShort {
+            expected: 2,
+            got: 1
+        })
+    );
+    assert_eq!(
+        <[u8; 2]>::from_control_data(&[1, 2, 3]),
+        Err(AnyUsbError::ResponseTooLong {
+            expected: 2,
+            got: 3
+        })
+    );
+    assert_eq!(
+        u16::from_control_data(&[1, 2, 3]),
+        Err(AnyUsbError::ResponseTooLong {
+            expected: 2,
+            got: 3
+        })
+    );
+}
+
+#[test]
+fn test_device_control_write_short_data_stage() {
+    TestCtx::new()
+        .with_usb(|mut cls, mut dev| {
+            let setup: [u8; 8] =
+                SetupPacket::new(CtrRequestType::to_device().class().interface(), 2, 0, 0, 8)
+                    .into();
+
+            // host sends only 4 bytes out of 8 promised
+            let res = dev
+                .ep_raw(&mut cls, 0, Some(&setup), Some(&[0xaa, 1, 2, 3]), &mut [])
+                .expect("res");
+            assert_eq!(res.wrote, Some(4));
+            assert_eq!(res.read, Some(0));
+
+            // control pipe still waits for the rest of the data
+            assert_eq!(cls.byte, 0);
+
+            // the rest of the data stage completes the request
+            let res = dev
+                .ep_raw(&mut cls, 0, None, Some(&[4, 5, 6, 7]), &mut [])
+                .expect("res");
+            assert_eq!(res.wrote, Some(4));
+            assert_eq!(cls.byte, 0xaa);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_device_control_write_short_data_stage_new_request() {
+    TestCtx::new()
+        .with_usb(|mut cls, mut dev| {
+            let setup: [u8; 8] =
+                SetupPacket::new(CtrRequestType::to_device().class().interface(), 2, 0, 0, 8)
+                    .into();
+
+            let res = dev
+                .ep_raw(&mut cls, 0, Some(&setup), Some(&[0xaa, 1, 2, 3]), &mut [])
+                .expect("res");
+            assert_eq!(res.wrote, Some(4));
+
+            // a new request is rejected by the class
+            let res = dev.control_write(
+                &mut cls,
+                CtrRequestType::to_device().class().interface(),
+                2,
+                0,
+                0,
+                &[],
+            );
+            assert_eq!(res, Err(AnyUsbError::EP0Stalled));
+            assert_eq!(cls.byte, 0);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_device_control_abort_data_stage() {
+    TestCtx::new()
+        .with_usb(|mut cls, mut dev| {
+            let reqt = CtrRequestType::to_device().class().interface();
+
+            // first half of a 16-byte data stage
+            let setup: [u8; 8] = SetupPacket::new(reqt, 2, 0, 0, 16).into();
+            dev.ep_raw(&mut cls, 0, Some(&setup), Some(&[0xbb; 8]), &mut [])
+                .expect("res");
+            assert_eq!(cls.byte, 0);
+
+            let setup = SetupPacket::new(reqt, 2, 0, 0, 1);
+            dev.ep0_abort_with(&mut cls, setup, Some(&[0xcc]), &mut [])
+                .expect("res");
+            assert_eq!(cls.byte, 0xcc);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_device_control_abort_discards_data() {
+    TestCtx::new()
+        .with_usb(|mut cls, mut dev| {
+            let reqt = CtrRequestType::to_device().class().interface();
+
+            // the Device doesn't consume data beyond wLength
+            let setup: [u8; 8] = SetupPacket::new(reqt, 2, 0, 0, 8).into();
+            dev.ep_raw(&mut cls, 0, Some(&setup), Some(&[0xbb; 12]), &mut [])
+                .expect("res");
+            assert_eq!(cls.byte, 0xbb);
+
+            let setup = SetupPacket::new(CtrRequestType::to_host().class().interface(), 1, 0, 0, 8);
+            let mut buf = [0; 8];
+            let res = dev
+                .ep0_abort_with(&mut cls, setup, None, &mut buf)
+                .expect("res");
+            assert_eq!(&buf[..res.read.unwrap()], [1, 2, 0xbb]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_device_control_abort_and_restart() {
+    TestCtx::new()
+        .with_usb(|mut cls, mut dev| {
+            let reqt = CtrRequestType::to_host().class().interface();
+            cls.payload = (0..20).collect();
+
+            // the first response doesn't fit into one packet
+            let first = SetupPacket::new(reqt, 4, 0, 0, 20);
+            let setup = SetupPacket::new(reqt, 1, 0, 0, 8);
+            let mut buf = [0; 8];
+            let res = dev
+                .ep0_abort_and_restart(&mut cls, first, setup, None, &mut buf)
+                .expect("res");
+            assert_eq!(&buf[..res.read.unwrap()], [1, 2, 0]);
+            assert!(res.status_ok);
+
+            // aborted request is not affected
+            let res = dev.control_read(&mut cls, reqt, 4, 0, 0, 20).expect("read");
+            assert_eq!(res, cls.payload);
+
+            // Host-to-device request aborted before its data stage
+            let first =
+                SetupPacket::new(CtrRequestType::to_device().class().interface(), 2, 0, 0, 1);
+            let res = dev
+                .ep0_abort_and_restart(&mut cls, first, setup, None, &mut buf)
+                .expect("res");
+            assert_eq!(&buf[..res.read.unwrap()], [1, 2, 0]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_device_control_write_length_mismatch() {
+    TestCtx::new()
+        .with_usb(|mut cls, mut dev| {
+            let reqt = CtrRequestType::to_device().class().interface();
+
+            let setup = SetupPacket::new(reqt, 2, 0, 0, 8);
+            let res = dev.ep0(&mut cls, setup, Some(&[0xbb; 4]), &mut []);
+            assert_eq!(res.err(), Some(AnyUsbError::DataLengthMismatch));
+
+            // no data is zero-length data
+            let res = dev.ep0(&mut cls, setup, None, &mut []);
+            assert_eq!(res.err(), Some(AnyUsbError::DataLengthMismatch));
+            let res = dev.ep0_queue(setup, None);
+            assert_eq!(res.err(), Some(AnyUsbError::DataLengthMismatch));
+
+            let setup = SetupPacket::new(reqt, 2, 0, 0, 0);
+            let res = dev.ep0_queue(setup, Some(&[0xbb]));
+            assert_eq!(res.err(), Some(AnyUsbError::DataLengthMismatch));
+
+            let res = dev.ep_io_control(&mut cls, reqt, 2, 0, 0, 1, Some(&[0xbb; 2]));
+            assert_eq!(res, Err(AnyUsbError::DataLengthMismatch));
+            assert_eq!(cls.byte, 0);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_device_control_write_detailed_error() {
+    TestCtx::new()
+        .with_usb(|mut cls, mut dev| {
+            let reqt = CtrRequestType::to_device().class().interface();
+
+            let res = dev.control_write_detailed(&mut cls, reqt, 2, 0, 0, &[]);
+            assert_eq!(
+                res,
+                Err(DetailedError {
+                    error: AnyUsbError::EP0Stalled,
+                    ep_index: 0,
+                    setup: Some(SetupPacket::new(reqt, 2, 0, 0, 0)),
+                })
+            );
+
+            let res = dev
+                .control_write_detailed(&mut cls, reqt, 2, 0, 0, &[0xaa])
+                .map_err(AnyUsbError::from);
+            assert_eq!(res, Ok(vec![]));
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_device_control_read_zlp() {
+    TestCtx::new()
+        .with_usb(|mut cls, mut dev| {
+            let mut buf = [0; 64];
+
+            // wLength == EP0_SIZE, data stage ends with a ZLP
+            let setup = SetupPacket::new(CtrRequestType::to_host(), 6, 0x100, 0, 8);
+            let res = dev.ep0(&mut cls, setup, None, &mut buf).expect("res");
+            assert_eq!(res.read, Some(8));
+            assert!(res.zlp);
+
+            // short packet
+            let setup = SetupPacket::new(CtrRequestType::to_host(), 0, 0, 0, 2);
+            let res = dev.ep0(&mut cls, setup, None, &mut buf).expect("res");
+            assert_eq!(res.read, Some(2));
+            assert!(!res.zlp);
+        })
+        .expect("with_usb");
+}
+
+struct ZlpCtx<const EP0: u8> {}
+
+impl<const EP0: u8> UsbDeviceCtx for ZlpCtx<EP0> {
+    type C<'c> = TestUsbClass;
+    const EP0_SIZE: u8 = EP0;
+    const STRICT_ZLP: bool = true;
+
+    fn create_class(&mut self, alloc: &UsbBusAllocator<EmulatedUsbBus>) -> AnyResult<TestUsbClass> {
+        Ok(TestUsbClass::new(alloc))
+    }
+}
+
+fn check_zlp<const EP0: u8, const LEN: usize>() {
+    ZlpCtx::<EP0> {}
+        .with_usb(|mut cls, mut dev| {
+            let reqt = CtrRequestType::to_host().class().interface();
+            let mut buf = [0; 256];
+            cls.payload = (0..LEN as u8).collect();
+
+            // response is shorter than wLength
+            let setup = SetupPacket::new(reqt, 4, 0, 0, 200);
+            let res = dev.ep0(&mut cls, setup, None, &mut buf).expect("res");
+            assert_eq!(buf[..res.read.unwrap()], cls.payload);
+            assert_eq!(res.zlp, LEN.is_multiple_of(EP0 as usize));
+
+            // response is exactly wLength
+            let setup = SetupPacket::new(reqt, 4, 0, 0, LEN as u16);
+            let res = dev.ep0(&mut cls, setup, None, &mut buf).expect("res");
+            assert_eq!(res.read, Some(LEN));
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_device_control_read_zlp_ep0_8() {
+    check_zlp::<8, 8>();
+    check_zlp::<8, 16>();
+    check_zlp::<8, 64>();
+    check_zlp::<8, 63>();
+}
+
+#[test]
+fn test_device_control_read_zlp_ep0_16() {
+    check_zlp::<16, 8>();
+    check_zlp::<16, 16>();
+    check_zlp::<16, 64>();
+}
+
+#[test]
+fn test_device_control_read_zlp_ep0_64() {
+    check_zlp::<64, 8>();
+    check_zlp::<64, 16>();
+    check_zlp::<64, 64>();
+    check_zlp::<64, 128>();
+}
+
+#[test]
+fn test_device_control_status_stage() {
+    TestCtx::new()
+        .with_usb(|mut cls, mut dev| {
+            let mut buf = [0; 8];
+
+            let setup = SetupPacket::new(CtrRequestType::to_host().class().interface(), 1, 0, 0, 8);
+            let res = dev.ep0(&mut cls, setup, None, &mut buf).expect("res");
+            assert_eq!(res.read, Some(3));
+            assert!(res.status_ok);
+
+            let setup =
+                SetupPacket::new(CtrRequestType::to_device().class().interface(), 2, 0, 0, 1);
+            let res = dev
+                .ep0(&mut cls, setup, Some(&[0xaa]), &mut [])
+                .expect("res");
+            assert_eq!(res.read, Some(0));
+            assert!(res.status_ok);
+            assert_eq!(cls.byte, 0xaa);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_device_control_packets() {
+    TestCtx::new()
+        .with_usb(|mut cls, mut dev| {
+            let mut buf = [0; 64];
+            cls.payload = (0..20).collect();
+
+            let setup =
+                SetupPacket::new(CtrRequestType::to_host().class().interface(), 4, 0, 0, 64);
+            let res = dev.ep0(&mut cls, setup, None, &mut buf).expect("res");
+            assert_eq!(res.read, Some(20));
+            assert_eq!(res.packets_in, 3);
+            assert_eq!(res.packets_out, 0);
+            assert_eq!(res.polls, 5);
+            assert_eq!(res.stall, None);
+
+            // 16 bytes end with a zero-length packet
+            cls.payload.truncate(16);
+            let res = dev.ep0(&mut cls, setup, None, &mut buf).expect("res");
+            assert_eq!(res.read, Some(16));
+            assert_eq!(res.packets_in, 3);
+            assert!(res.zlp);
+
+            let data = [0x55; 20];
+            let setup =
+                SetupPacket::new(CtrRequestType::to_device().class().interface(), 3, 0, 0, 20);
+            let res = dev.ep0(&mut cls, setup, Some(&data), &mut []).expect("res");
+            assert_eq!(res.wrote, Some(20));
+            assert_eq!(res.packets_out, 3);
+            assert_eq!(res.packets_in, 1);
+            assert_eq!(cls.payload, data);
+        })
+        .expect("with_usb");
+}
+
+struct SkipStatusCtx {}
+
+impl UsbDeviceCtx for SkipStatusCtx {
+    type C<'c> = TestUsbClass;
+    const SKIP_STATUS_STAGE: bool = true;
+
+    fn create_class(&mut self, alloc: &UsbBusAllocator<EmulatedUsbBus>) -> AnyResult<TestUsbClass> {
+        Ok(TestUsbClass::new(alloc))
+    }
+
+    fn skip_setup(&mut self) -> bool {
+        // SET_ADDRESS takes effect only after the status stage
+        true
+    }
+}
+
+#[test]
+fn test_device_control_skip_status_stage() {
+    SkipStatusCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let mut buf = [0; 8];
+
+            let setup = SetupPacket::new(CtrRequestType::to_host().class().interface(), 1, 0, 0, 8);
+            let res = dev.ep0(&mut cls, setup, None, &mut buf).expect("res");
+            assert_eq!(&buf[..res.read.unwrap()], [1, 2, 0]);
+            assert!(!res.status_ok);
+
+            // the Device's zero-length status packet is not read
+            let setup =
+                SetupPacket::new(CtrRequestType::to_device().class().interface(), 2, 0, 0, 1);
+            let res = dev
+                .ep0(&mut cls, setup, Some(&[0xaa]), &mut [])
+                .expect("res");
+            assert_eq!(res.read, Some(0));
+            assert!(!res.zlp);
+            assert!(!res.status_ok);
+            assert_eq!(cls.byte, 0xaa);
+
+            let setup = SetupPacket::new(CtrRequestType::to_host().class().interface(), 1, 0, 0, 8);
+            let res = dev
+                .ep0_abort_with(&mut cls, setup, None, &mut buf)
+                .expect("res");
+            assert_eq!(&buf[..res.read.unwrap()], [1, 2, 0xaa]);
+
+            dev.device_set_address(&mut cls, 5).expect("res");
+            assert_eq!(dev.assigned_address(), 0);
+            assert_eq!(dev.usb_dev().state(), UsbDeviceState::Default);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_device_malformed_setup() {
+    TestCtx::new()
+        .with_usb(|mut cls, mut dev| {
+            let status: [u8; 8] = SetupPacket::new(CtrRequestType::to_host(), 0, 0, 0, 2).into();
+            let mut long = status.to_vec();
+            long.push(0);
+
+            assert_eq!(
+                dev.ep0_malformed_setup(&mut cls, &[]),
+                SetupOutcome::Ignored
+            );
+            assert_eq!(
+                dev.ep0_malformed_setup(&mut cls, &status[..7]),
+                SetupOutcome::Ignored
+            );
+            assert_eq!(
+                dev.ep0_malformed_setup(&mut cls, &long),
+                SetupOutcome::Ignored
+            );
+            assert_eq!(
+                dev.ep0_malformed_setup(&mut cls, &[0xa5; 200]),
+                SetupOutcome::Ignored
+            );
+            assert_eq!(
+                dev.ep0_malformed_setup(&mut cls, &[0xa5; 2000]),
+                SetupOutcome::Ignored
+            );
+
+            // reserved request type
+            assert_eq!(
+                dev.ep0_malformed_setup(&mut cls, &[0xe0, 0, 0, 0, 0, 0, 0, 0]),
+                SetupOutcome::Stalled
+            );
+
+            assert_eq!(
+                dev.ep0_malformed_setup(&mut cls, &status),
+                SetupOutcome::Accepted
+            );
+
+            // the Device still works
+            dev.device_get_status(&mut cls).expect("status");
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_device_set_configuration_checked() {
+    TestCtx::new()
+        .with_usb(|mut cls, mut dev| {
+            dev.set_configuration_checked(&mut cls, 0)
+                .expect("unconfigured");
+            assert_eq!(dev.usb_dev().state(), UsbDeviceState::Addressed);
+
+            dev.set_configuration_checked(&mut cls, 1)
+                .expect("configured");
+            assert_eq!(dev.usb_dev().state(), UsbDeviceState::Configured);
+
+            let res = dev.set_configuration_checked(&mut cls, 2);
+            assert_eq!(res, Err(AnyUsbError::EP0Stalled));
+        })
+        .expect("with_usb");
+}
+
+/// A class which answers GET_CONFIGURATION itself
+/// with a wrong value.
+struct BadConfigUsbClass {}
+
+impl<B: UsbBus> UsbClass<B> for BadConfigUsbClass {
+    fn control_in(&mut self, xfer: usb_device::class::ControlIn<B>) {
+        let req = xfer.request();
+        if req.request_type == usb_device::control::RequestType::Standard
+            && req.recipient == usb_device::control::Recipient::Device
+            && req.request == usb_device::control::Request::GET_CONFIGURATION
+        {
+            xfer.accept_with(&[7]).ok();
+        }
+    }
+}
+
+struct BadConfigCtx {}
+
+impl UsbDeviceCtx for BadConfigCtx {
+    type C<'c> = Composite<BadConfigUsbClass, TestUsbClass>;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<Composite<BadConfigUsbClass, TestUsbClass>> {
+        Ok(Composite(BadConfigUsbClass {}, TestUsbClass::new(alloc)))
+    }
+}
+
+#[test]
+fn test_device_set_configuration_checked_mismatch() {
+    BadConfigCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let res = dev.set_configuration_checked(&mut cls, 1);
+            assert_eq!(
+                res,
+                Err(AnyUsbError::ConfigurationMismatch {
+                    requested: 1,
+                    actual: 7,
+                    state: UsbDeviceState::Configured,
+                })
+            );
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_device_inconsistent_request_type() {
+    TestCtx::new()
+        .with_usb(|mut cls, mut dev| {
+            let mut buf = [0; 8];
+
+            // Device-to-host class request 2 with OUT data
+            let setup = SetupPacket::new(CtrRequestType::raw(0xa1), 2, 0, 0, 1);
+            let res = dev.ep0_unchecked(&mut cls, setup, Some(&[0xaa]), &mut buf);
+            assert_eq!(res.err(), Some(AnyUsbError::EP0Stalled));
+
+            // Host-to-device class request 1 which expects IN data
+            let setup = SetupPacket::new(CtrRequestType::raw(0x21), 1, 0, 0, 8);
+            let res = dev.ep0_unchecked(&mut cls, setup, Some(&[0xaa; 8]), &mut buf);
+            assert_eq!(res.err(), Some(AnyUsbError::EPStalled));
+
+            // reserved Type
+            let setup = SetupPacket::new(CtrRequestType::raw(0xe1), 1, 0, 0, 8);
+            let res = dev.ep0_unchecked(&mut cls, setup, None, &mut buf);
+            assert_eq!(res.err(), Some(AnyUsbError::EP0Stalled));
+
+            // reserved Recipient
+            let setup = SetupPacket::new(CtrRequestType::raw(0xbf), 1, 0, 0, 8);
+            let res = dev.ep0_unchecked(&mut cls, setup, None, &mut buf);
+            assert_eq!(res.err(), Some(AnyUsbError::EP0Stalled));
+
+            assert_eq!(cls.byte, 0);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_device_raw_setup() {
+    TestCtx::new()
+        .with_usb(|mut cls, mut dev| {
+            let mut buf = [0; 64];
+            cls.payload = vec![1, 2, 3];
+
+            // class request 4 with wLength of 0xffff
+            let setup = [0xa1, 4, 0, 0, 0, 0, 0xff, 0xff];
+            let res = dev
+                .ep0_raw_setup(&mut cls, &setup, None, &mut buf)
+                .expect("res");
+            assert_eq!(res.read, Some(3));
+            assert_eq!(buf[..3], [1, 2, 3]);
+
+            // reserved request type
+            let setup = [0xe1, 4, 0, 0, 0, 0, 8, 0];
+            let res = dev.ep0_raw_setup(&mut cls, &setup, None, &mut buf);
+            assert_eq!(res.err(), Some(AnyUsbError::EP0Stalled));
+
+            // Host-to-device request with wLength larger than the data,
+            // the Device waits for the rest of it
+            let setup = [0x21, 3, 0, 0, 0, 0, 0xff, 0xff];
+            let res = dev
+                .ep0_raw_setup(&mut cls, &setup, Some(&[1, 2]), &mut [])
+                .expect("res");
+            assert!(!res.status_ok);
+            assert_eq!(cls.payload, [1, 2, 3]);
+
+            dev.device_get_status(&mut cls).expect("status");
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_device_control_queue_collect() {
+    TestCtx::new()
+        .with_usb(|mut cls, mut dev| {
+            let mut buf = [0; 8];
+            let reqt = CtrRequestType::to_host().class().interface();
+
+            let res = dev.ep0_collect(&mut cls, &mut buf);
+            assert_eq!(res.err(), Some(AnyUsbError::NoQueuedTransfer));
+
+            // the class sees the value set after the transfer is queued
+            dev.ep0_queue(SetupPacket::new(reqt, 1, 0, 0, 8), None)
+                .expect("queue");
+            cls.byte = 0x11;
+            dev.poll(&mut cls);
+            cls.byte = 0x22;
+            let res = dev.ep0_collect(&mut cls, &mut buf).expect("collect");
+            assert_eq!(buf[..res.read.unwrap()], [1, 2, 0x11]);
+            assert!(res.status_ok);
+
+            // collect polls the Setup packet if the case didn't
+            let setup =
+                SetupPacket::new(CtrRequestType::to_device().class().interface(), 2, 0, 0, 1);
+            dev.ep0_queue(setup, Some(&[0xaa])).expect("queue");
+            let res = dev.ep0_collect(&mut cls, &mut []).expect("collect");
+            assert_eq!(res.wrote, Some(1));
+            assert_eq!(cls.byte, 0xaa);
+
+            dev.ep0_queue(SetupPacket::new(reqt, 5, 0, 0, 8), None)
+                .expect("queue");
+            dev.poll(&mut cls);
+            let res = dev.ep0_collect(&mut cls, &mut buf);
+            assert_eq!(res.err(), Some(AnyUsbError::EP0Stalled));
+
+            // another transfer replaces the queued one
+            dev.ep0_queue(SetupPacket::new(reqt, 1, 0, 0, 8), None)
+                .expect("queue");
+            dev.device_get_status(&mut cls).expect("status");
+            let res = dev.ep0_collect(&mut cls, &mut buf);
+            assert_eq!(res.err(), Some(AnyUsbError::NoQueuedTransfer));
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_device_control_read_after_stall() {
+    TestCtx::new()
+        .with_usb(|mut cls, mut dev| {
+            let reqt = CtrRequestType::to_host().class().interface();
+            let stall = SetupPacket::new(reqt, 5, 0, 0, 8);
+            let setup = SetupPacket::new(reqt, 1, 0, 0, 8);
+
+            let vec = dev
+                .control_read_after_stall(&mut cls, stall, setup)
+                .expect("vec");
+            assert_eq!(vec, [1, 2, 0]);
+
+            // stall doesn't persist
+            let vec = dev
+                .control_read_after_stall(&mut cls, stall, setup)
+                .expect("vec");
+            assert_eq!(vec, [1, 2, 0]);
+
+            let res = dev.control_read_after_stall(&mut cls, setup, setup);
+            assert_eq!(res, Err(AnyUsbError::NotStalled));
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_device_control_back_to_back_setup() {
+    TestCtx::new()
+        .with_usb(|mut cls, mut dev| {
+            // SET_INTERFACE is replaced by the next Setup
+            let first = SetupPacket::new(CtrRequestType::to_device().interface(), 11, 1, 0, 0);
+            let setup = SetupPacket::new(CtrRequestType::to_host().class().interface(), 1, 0, 0, 8);
+
+            let mut buf = [0; 8];
+            let res = dev
+                .ep0_back_to_back(&mut cls, first, setup, None, &mut buf)
+                .expect("res");
+            assert_eq!(&buf[..res.read.unwrap()], [1, 2, 0]);
+            assert_eq!(cls.alt_setting, 0);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_device_trace() {
+    TestCtx::new()
+        .with_usb(|mut cls, mut dev| {
+            dev.device_get_status(&mut cls).expect("status");
+
+            dev.enable_trace();
+
+            dev.interface_get_status(&mut cls, 0).expect("status");
+            dev.control_write(
+                &mut cls,
+                CtrRequestType::to_device().class().interface(),
+                2,
+                0,
+                0,
+                &[],
+            )
+            .expect_err("stall");
+
+            let trace = dev.take_trace();
+            assert_eq!(trace.len(), 2);
+
+            assert_eq!(trace[0].ep_index, 0);
+            assert_eq!(
+                trace[0].setup,
+                Some(SetupPacket::new(
+                    CtrRequestType::to_host().interface(),
+                    0,
+                    0,
+                    0,
+                    2
+                ))
+            );
+            assert_eq!(trace[0].data_out, []);
+            assert_eq!(trace[0].data_in, [0, 0]);
+            assert!(!trace[0].stalled());
+
+            assert_eq!(trace[1].data_in, []);
+            assert!(trace[1].stalled());
+
+            assert!(dev.take_trace().is_empty());
+        })
+        .expect("with_usb");
+}
+
+struct SetupDataCtx<const COMBINED: bool, const EP0: u8> {}
+
+impl<const COMBINED: bool, const EP0: u8> UsbDeviceCtx for SetupDataCtx<COMBINED, EP0> {
+    type C<'c> = TestUsbClass;
+    const EP0_SIZE: u8 = EP0;
+    const SETUP_WITH_DATA: bool = COMBINED;
+
+    fn create_class(&mut self, alloc: &UsbBusAllocator<EmulatedUsbBus>) -> AnyResult<TestUsbClass> {
+        Ok(TestUsbClass::new(alloc))
+    }
+}
+
+fn check_setup_with_data<const COMBINED: bool, const EP0: u8>() {
+    SetupDataCtx::<COMBINED, EP0> {}
+        .with_usb(|mut cls, mut dev| {
+            let setup =
+                SetupPacket::new(CtrRequestType::to_device().class().interface(), 2, 0, 0, 3);
+            let res = dev
+                .ep0(&mut cls, setup, Some(&[0x42, 1, 2]), &mut [])
+                .expect("res");
+            assert_eq!(res.wrote, Some(3));
+            assert_eq!(res.read, Some(0));
+            assert_eq!(cls.byte, 0x42);
+
+            let vec = dev
+                .control_read(
+                    &mut cls,
+                    CtrRequestType::to_host().class().interface(),
+                    1,
+                    0,
+                    0,
+                    8,
+                )
+                .expect("vec");
+            assert_eq!(vec, [1, 2, 0x42]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_device_setup_and_data_separate() {
+    check_setup_with_data::<false, 8>();
+    check_setup_with_data::<false, 64>();
+}
+
+#[test]
+fn test_device_setup_and_data_combined() {
+    check_setup_with_data::<true, 8>();
+    check_setup_with_data::<true, 64>();
+}
+
+fn check_oversized_control_write<const COMBINED: bool>() {
+    SetupDataCtx::<COMBINED, 8> {}
+        .with_usb(|mut cls, mut dev| {
+            let reqt = CtrRequestType::to_device().class().interface();
+            let data = [0x55; 2000];
+
+            // longer than usb-device control buffer,
+            // the request doesn't reach the class
+            let res = dev.control_write(&mut cls, reqt, 2, 0, 0, &data);
+            assert!(res.is_ok());
+            assert_eq!(cls.byte, 0);
+
+            let ep = EndpointAddress::from_parts(0, UsbDirection::Out);
+            let res = dev.ep_raw(&mut cls, 0, Some(&data), None, &mut []);
+            assert_eq!(
+                res.err(),
+                Some(AnyUsbError::HostBufferOverflow {
+                    ep,
+                    capacity: 1024,
+                    attempted: 2000
+                })
+            );
+
+            // the Device is still responsive
+            dev.control_write(&mut cls, reqt, 2, 0, 0, &[0x42])
+                .expect("write");
+            assert_eq!(cls.byte, 0x42);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_device_control_write_oversized() {
+    check_oversized_control_write::<false>();
+    check_oversized_control_write::<true>();
+}
+
+struct ChunkedCtx {}
+
+impl UsbDeviceCtx for ChunkedCtx {
+    type C<'c> = TestUsbClass;
+    const CHUNKED_DATA: bool = true;
+
+    fn create_class(&mut self, alloc: &UsbBusAllocator<EmulatedUsbBus>) -> AnyResult<TestUsbClass> {
+        Ok(TestUsbClass::new(alloc))
+    }
+}
+
+#[test]
+fn test_device_control_write_chunked() {
+    ChunkedCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let data: Vec<u8> = (0..100).collect();
+
+            let setup = SetupPacket::new(
+                CtrRequestType::to_device().class().interface(),
+                3,
+                0,
+                0,
+                100,
+            );
+            let res = dev.ep0(&mut cls, setup, Some(&data), &mut []).expect("res");
+            assert_eq!(res.wrote, Some(100));
+            assert_eq!(res.read, Some(0));
+            assert_eq!(cls.payload, data);
+
+            // exact multiple of EP0 size
+            let setup =
+                SetupPacket::new(CtrRequestType::to_device().class().interface(), 3, 0, 0, 16);
+            let res = dev
+                .ep0(&mut cls, setup, Some(&data[..16]), &mut [])
+                .expect("res");
+            assert_eq!(res.wrote, Some(16));
+            assert_eq!(cls.payload, data[..16]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_device_control_write_chunked_short() {
+    ChunkedCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let data: Vec<u8> = (0..100).collect();
+
+            // the Device waits for more data, but Host doesn't have it
+            let setup: [u8; 8] = SetupPacket::new(
+                CtrRequestType::to_device().class().interface(),
+                3,
+                0,
+                0,
+                100,
+            )
+            .into();
+            let res = dev
+                .ep_raw(&mut cls, 0, Some(&setup), Some(&data[..20]), &mut [])
+                .expect("res");
+            assert_eq!(res.wrote, Some(20));
+            assert_eq!(cls.payload, []);
+        })
+        .expect("with_usb");
+}
+
+struct FailTestUsbClass {}
+
+impl<B: UsbBus> UsbClass<B> for FailTestUsbClass {}
+
+struct FailTestCtx {}
+
+impl UsbDeviceCtx for FailTestCtx {
+    type C<'c> = FailTestUsbClass;
+
+    const ADDRESS: u8 = 55;
+
+    fn create_class<'a>(
+        &mut self,
+        _alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<FailTestUsbClass> {
+        Err(AnyUsbError::UserDefined1)
+    }
+}
+
+#[test]
+#[should_panic(expected = "with_usb: UserDefined1")]
+fn test_create_class_fails() {
+    FailTestCtx {}
+        .with_usb(|mut _cls, mut _dev| {
+            unreachable!("case should not run");
+        })
+        .expect("with_usb");
+}
+
+/// Stops polling after the first OUT data packet.
+struct DataStopCtx {}
+
+impl UsbDeviceCtx for DataStopCtx {
+    type C<'c> = TestUsbClass;
+
+    fn create_class(&mut self, alloc: &UsbBusAllocator<EmulatedUsbBus>) -> AnyResult<TestUsbClass> {
+        Ok(TestUsbClass::new(alloc))
+    }
+
+    fn hook(&mut self, _cls: &mut Self::C<'_>, when: HookWhen) -> HookAction {
+        match when {
+            HookWhen::DataIn(_) => HookAction::Stop,
+            _ => HookAction::Default,
+        }
+    }
+}
+
+#[test]
+fn test_device_control_write_all() {
+    TestCtx::new()
+        .with_usb(|mut cls, mut dev| {
+            let reqt = CtrRequestType::to_device().class().interface();
+            let data: Vec<u8> = (0..20).collect();
+
+            dev.control_write_all(&mut cls, reqt, 3, 0, 0, &data)
+                .expect("write");
+            assert_eq!(cls.payload, data);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_device_control_write_all_remaining() {
+    DataStopCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let reqt = CtrRequestType::to_device().class().interface();
+            let data: Vec<u8> = (0..20).collect();
+
+            let res = dev.control_write_all(&mut cls, reqt, 3, 0, 0, &data);
+            assert_eq!(res, Err(AnyUsbError::EP0DataRemaining { remaining: 12 }));
+            assert!(cls.payload.is_empty());
+
+            // fits into the first packet
+            dev.control_write_all(&mut cls, reqt, 3, 0, 0, &[1, 2])
+                .expect("write");
+        })
+        .expect("with_usb");
+}
+
+struct EP0SizeMismatchCtx {}
+
+impl UsbDeviceCtx for EP0SizeMismatchCtx {
+    type C<'c> = TestUsbClass;
+
+    fn create_class(&mut self, alloc: &UsbBusAllocator<EmulatedUsbBus>) -> AnyResult<TestUsbClass> {
+        Ok(TestUsbClass::new(alloc))
+    }
+
+    fn build_usb_device<'a>(
+        &mut self,
+        alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<UsbDevice<'a, EmulatedUsbBus>> {
+        Ok(UsbDeviceBuilder::new(alloc, UsbVidPid(0x1234, 0x5678))
+            .max_packet_size_0(64)
+            .map_err(AnyUsbError::UsbDeviceBuilder)?
+            .build())
+    }
+}
+
+#[test]
+fn test_device_ep0_size_mismatch() {
+    let res = EP0SizeMismatchCtx {}.with_usb(|mut _cls, mut _dev| {
+        unreachable!("case should not run");
+    });
+    assert_eq!(
+        res,
+        Err(AnyUsbError::EP0SizeMismatch {
+            descriptor: 64,
+            expected: 8
+        })
+    );
+}
@@ -9,7 +9,13 @@ pub struct TestUsbClass {
     pub iface: InterfaceNumber,
     pub interface_string: StringIndex,
     pub byte: u8,
+    pub payload: Vec<u8>,
     pub alt_setting: u8,
+    /// If set, reject GET_DESCRIPTOR requests for Configuration
+    /// indexes not below this number.
+    pub configurations: Option<u8>,
+    /// Accept SET_INTERFACE without changing `alt_setting`.
+    pub ignore_alt_setting: bool,
 }
 
 impl TestUsbClass {
@@ -18,7 +24,10 @@ impl TestUsbClass {
             iface: alloc.interface(),
             interface_string: alloc.string(),
             byte: 0,
+            payload: Vec::new(),
             alt_setting: 0,
+            configurations: None,
+            ignore_alt_setting: false,
         }
     }
 }
@@ -27,6 +36,17 @@ impl<B: UsbBus> UsbClass<B> for TestUsbClass {
     fn control_in(&mut self, xfer: usb_device::class::ControlIn<B>) {
         let req = xfer.request();
 
+        if let Some(configurations) = self.configurations {
+            if req.request_type == control::RequestType::Standard
+                && req.request == control::Request::GET_DESCRIPTOR
+                && (req.value >> 8) as u8 == 2
+                && (req.value & 0xff) as u8 >= configurations
+            {
+                xfer.reject().ok();
+                return;
+            }
+        }
+
         if req.request_type != control::RequestType::Class {
             return;
         }
@@ -44,6 +64,9 @@ impl<B: UsbBus> UsbClass<B> for TestUsbClass {
                 let status: [u8; 3] = [1, 2, self.byte];
                 xfer.accept_with(&status).ok();
             }
+            4 => {
+                xfer.accept_with(&self.payload).ok();
+            }
             _ => {
                 xfer.reject().ok();
             }
@@ -67,13 +90,13 @@ impl<B: UsbBus> UsbClass<B> for TestUsbClass {
 
         let data = xfer.data();
         match req.request {
-            2 => {
-                if data.len() > 0 {
-                    self.byte = data[0];
-                    xfer.accept().ok();
-                } else {
-                    xfer.reject().ok();
-                }
+            2 if !data.is_empty() => {
+                self.byte = data[0];
+                xfer.accept().ok();
+            }
+            3 => {
+                self.payload = data.to_vec();
+                xfer.accept().ok();
             }
             _ => {
                 xfer.reject().ok();
@@ -91,7 +114,9 @@ impl<B: UsbBus> UsbClass<B> for TestUsbClass {
 
     fn set_alt_setting(&mut self, interface: InterfaceNumber, alternative: u8) -> bool {
         if interface == self.iface {
-            self.alt_setting = alternative;
+            if !self.ignore_alt_setting {
+                self.alt_setting = alternative;
+            }
             true
         } else {
             false
@@ -0,0 +1,84 @@
+//! `EpTestUsbClass` implementation for a test `UsbClass`
+//! which moves data through non-control endpoints.
+use usb_device::{
+    bus::{InterfaceNumber, UsbBus, UsbBusAllocator},
+    class::UsbClass,
+    endpoint::{EndpointAddress, EndpointIn, EndpointOut},
+};
+
+pub const EP_SIZE: u16 = 64;
+
+pub struct EpTestUsbClass<'a, B: UsbBus> {
+    pub iface: InterfaceNumber,
+    pub ep_out: EndpointOut<'a, B>,
+    pub ep_in: EndpointIn<'a, B>,
+    /// Maximum number of bytes consumed from `ep_out` per poll.
+    pub read_chunk: usize,
+    /// Data received from `ep_out`.
+    pub received: Vec<u8>,
+    /// If set, `ep_out` is read only this many times,
+    /// once per poll.
+    pub ticks: Option<usize>,
+    /// Write another full-size packet to `ep_in` once
+    /// the previous one is transmitted.
+    pub in_stream: bool,
+    /// Number of `endpoint_in_complete()` calls for `ep_in`.
+    pub in_complete: usize,
+}
+
+impl<'a, B: UsbBus> EpTestUsbClass<'a, B> {
+    pub fn new(alloc: &'a UsbBusAllocator<B>) -> Self {
+        Self {
+            iface: alloc.interface(),
+            ep_out: alloc.bulk(EP_SIZE),
+            ep_in: alloc.bulk(EP_SIZE),
+            read_chunk: EP_SIZE as usize,
+            received: Vec::new(),
+            ticks: None,
+            in_stream: false,
+            in_complete: 0,
+        }
+    }
+
+    pub fn write_in_packet(&mut self) {
+        let packet = [0xa5; EP_SIZE as usize];
+        self.ep_in.write(&packet).ok();
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for EpTestUsbClass<'_, B> {
+    fn poll(&mut self) {
+        match self.ticks {
+            Some(0) => return,
+            Some(n) => self.ticks = Some(n - 1),
+            None => {}
+        }
+
+        let mut buf = [0; EP_SIZE as usize];
+        let len = self.read_chunk.min(buf.len());
+
+        if let Ok(count) = self.ep_out.read(&mut buf[..len]) {
+            self.received.extend_from_slice(&buf[..count]);
+        }
+    }
+
+    fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
+        if addr != self.ep_in.address() {
+            return;
+        }
+        self.in_complete += 1;
+        if self.in_stream {
+            self.write_in_packet();
+        }
+    }
+
+    fn get_configuration_descriptors(
+        &self,
+        writer: &mut usb_device::descriptor::DescriptorWriter,
+    ) -> usb_device::Result<()> {
+        writer.interface(self.iface, 0xff, 0x00, 0x00)?;
+        writer.endpoint(&self.ep_out)?;
+        writer.endpoint(&self.ep_in)?;
+        Ok(())
+    }
+}
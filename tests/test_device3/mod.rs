@@ -0,0 +1,77 @@
+//! `CtlTestUsbClass` implementation for a test `UsbClass`
+//! which handles Control transfers on its own endpoint.
+//!
+//! Unlike EP0 handled by `usb-device`, the class doesn't
+//! limit the response to `wLength` of the Setup packet.
+use usb_device::{
+    bus::{InterfaceNumber, UsbBus, UsbBusAllocator},
+    class::UsbClass,
+    endpoint::{EndpointAddress, EndpointIn, EndpointOut},
+};
+
+pub const CTL_EP_SIZE: u16 = 8;
+
+pub struct CtlTestUsbClass<'a, B: UsbBus> {
+    pub iface: InterfaceNumber,
+    pub ep_out: EndpointOut<'a, B>,
+    pub ep_in: EndpointIn<'a, B>,
+    /// Response sent to every Device-to-host request.
+    pub reply: Vec<u8>,
+    /// Send `reply` to Host-to-device requests too.
+    pub reply_to_device: bool,
+    /// The rest of the response to be sent.
+    pending: Vec<u8>,
+}
+
+impl<'a, B: UsbBus> CtlTestUsbClass<'a, B> {
+    pub fn new(alloc: &'a UsbBusAllocator<B>) -> Self {
+        Self {
+            iface: alloc.interface(),
+            ep_out: alloc.control(CTL_EP_SIZE),
+            ep_in: alloc.control(CTL_EP_SIZE),
+            reply: Vec::new(),
+            reply_to_device: false,
+            pending: Vec::new(),
+        }
+    }
+
+    fn write_next(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let len = self.pending.len().min(CTL_EP_SIZE as usize);
+        if let Ok(count) = self.ep_in.write(&self.pending[..len]) {
+            self.pending.drain(..count);
+        }
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for CtlTestUsbClass<'_, B> {
+    fn endpoint_setup(&mut self, addr: EndpointAddress) {
+        if addr != self.ep_out.address() {
+            return;
+        }
+
+        let mut setup = [0; 8];
+        if let Ok(8) = self.ep_out.read(&mut setup) {
+            if setup[0] & 0x80 != 0 || self.reply_to_device {
+                self.pending = self.reply.clone();
+                self.write_next();
+            }
+        }
+    }
+
+    fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
+        if addr == self.ep_in.address() {
+            self.write_next();
+        }
+    }
+
+    fn get_configuration_descriptors(
+        &self,
+        writer: &mut usb_device::descriptor::DescriptorWriter,
+    ) -> usb_device::Result<()> {
+        writer.interface(self.iface, 0xff, 0x00, 0x00)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,92 @@
+//! `LoopbackUsbClass` implementation for a test `UsbClass`
+//! which sends back everything received on its Bulk OUT
+//! endpoint through its Bulk IN endpoint.
+use std::collections::VecDeque;
+
+use usb_device::{
+    bus::{InterfaceNumber, UsbBus, UsbBusAllocator},
+    class::UsbClass,
+    endpoint::{EndpointAddress, EndpointIn, EndpointOut, EndpointType},
+    UsbDirection,
+};
+
+pub const EP_SIZE: u16 = 64;
+
+pub struct LoopbackUsbClass<'a, B: UsbBus> {
+    pub iface: InterfaceNumber,
+    pub ep_out: EndpointOut<'a, B>,
+    pub ep_in: EndpointIn<'a, B>,
+    /// Data received from `ep_out` and not yet sent to `ep_in`.
+    pub buffer: VecDeque<u8>,
+    /// A packet was written to `ep_in` and not yet transmitted.
+    pub in_busy: bool,
+}
+
+impl<'a, B: UsbBus> LoopbackUsbClass<'a, B> {
+    pub fn new(alloc: &'a UsbBusAllocator<B>) -> Self {
+        Self {
+            iface: alloc.interface(),
+            ep_out: alloc.bulk(EP_SIZE),
+            ep_in: alloc.bulk(EP_SIZE),
+            buffer: VecDeque::new(),
+            in_busy: false,
+        }
+    }
+
+    /// Same as `new()`, but allocates both endpoints
+    /// at `index`.
+    pub fn new_at(alloc: &'a UsbBusAllocator<B>, index: usize) -> usb_device::Result<Self> {
+        let addr_out = EndpointAddress::from_parts(index, UsbDirection::Out);
+        let addr_in = EndpointAddress::from_parts(index, UsbDirection::In);
+        Ok(Self {
+            iface: alloc.interface(),
+            ep_out: alloc.alloc(Some(addr_out), EndpointType::Bulk, EP_SIZE, 0)?,
+            ep_in: alloc.alloc(Some(addr_in), EndpointType::Bulk, EP_SIZE, 0)?,
+            buffer: VecDeque::new(),
+            in_busy: false,
+        })
+    }
+
+    fn write_in_packet(&mut self) {
+        if self.in_busy || self.buffer.is_empty() {
+            return;
+        }
+
+        let len = self.buffer.len().min(EP_SIZE as usize);
+        let packet: Vec<u8> = self.buffer.iter().take(len).copied().collect();
+
+        if self.ep_in.write(&packet).is_ok() {
+            self.buffer.drain(..len);
+            self.in_busy = true;
+        }
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for LoopbackUsbClass<'_, B> {
+    fn poll(&mut self) {
+        let mut buf = [0; EP_SIZE as usize];
+
+        if let Ok(count) = self.ep_out.read(&mut buf) {
+            self.buffer.extend(&buf[..count]);
+        }
+
+        self.write_in_packet();
+    }
+
+    fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
+        if addr == self.ep_in.address() {
+            self.in_busy = false;
+            self.write_in_packet();
+        }
+    }
+
+    fn get_configuration_descriptors(
+        &self,
+        writer: &mut usb_device::descriptor::DescriptorWriter,
+    ) -> usb_device::Result<()> {
+        writer.interface(self.iface, 0xff, 0x00, 0x00)?;
+        writer.endpoint(&self.ep_out)?;
+        writer.endpoint(&self.ep_in)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,1565 @@
+mod test_device2;
+use test_device2::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::{UsbBus, UsbBusAllocator};
+use usb_device::endpoint::{EndpointAddress, EndpointType, In, Out};
+use usb_device::{UsbDirection, UsbError};
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+struct TestCtx {}
+
+impl UsbDeviceCtx for TestCtx {
+    type C<'c> = EpTestUsbClass<'c, EmulatedUsbBus>;
+
+    fn create_class<'a>(
+        &mut self,
+        alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<EpTestUsbClass<'a, EmulatedUsbBus>> {
+        Ok(EpTestUsbClass::new(alloc))
+    }
+}
+
+struct UnconfiguredCtx<const REQUIRE: bool> {}
+
+impl<const REQUIRE: bool> UsbDeviceCtx for UnconfiguredCtx<REQUIRE> {
+    type C<'c> = EpTestUsbClass<'c, EmulatedUsbBus>;
+    const REQUIRE_CONFIGURED: bool = REQUIRE;
+
+    fn create_class<'a>(
+        &mut self,
+        alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<EpTestUsbClass<'a, EmulatedUsbBus>> {
+        Ok(EpTestUsbClass::new(alloc))
+    }
+
+    fn skip_setup(&mut self) -> bool {
+        true
+    }
+}
+
+struct StrictSizeCtx<const STRICT: bool> {}
+
+impl<const STRICT: bool> UsbDeviceCtx for StrictSizeCtx<STRICT> {
+    type C<'c> = EpTestUsbClass<'c, EmulatedUsbBus>;
+    const STRICT_PACKET_SIZE: bool = STRICT;
+
+    fn create_class<'a>(
+        &mut self,
+        alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<EpTestUsbClass<'a, EmulatedUsbBus>> {
+        Ok(EpTestUsbClass::new(alloc))
+    }
+}
+
+struct StrictToggleCtx {}
+
+impl UsbDeviceCtx for StrictToggleCtx {
+    type C<'c> = EpTestUsbClass<'c, EmulatedUsbBus>;
+    const STRICT_DATA_TOGGLE: bool = true;
+
+    fn create_class<'a>(
+        &mut self,
+        alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<EpTestUsbClass<'a, EmulatedUsbBus>> {
+        Ok(EpTestUsbClass::new(alloc))
+    }
+}
+
+struct NakCtx<const RETRIES: usize> {}
+
+impl<const RETRIES: usize> UsbDeviceCtx for NakCtx<RETRIES> {
+    type C<'c> = EpTestUsbClass<'c, EmulatedUsbBus>;
+    const OUT_NAK: bool = true;
+    const MAX_NAK_RETRIES: usize = RETRIES;
+
+    fn create_class<'a>(
+        &mut self,
+        alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<EpTestUsbClass<'a, EmulatedUsbBus>> {
+        Ok(EpTestUsbClass::new(alloc))
+    }
+}
+
+struct StrictOutCtx {}
+
+impl UsbDeviceCtx for StrictOutCtx {
+    type C<'c> = EpTestUsbClass<'c, EmulatedUsbBus>;
+    const STRICT_OUT_DATA: bool = true;
+
+    fn create_class<'a>(
+        &mut self,
+        alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<EpTestUsbClass<'a, EmulatedUsbBus>> {
+        Ok(EpTestUsbClass::new(alloc))
+    }
+}
+
+struct ManyPollsCtx {}
+
+impl UsbDeviceCtx for ManyPollsCtx {
+    type C<'c> = EpTestUsbClass<'c, EmulatedUsbBus>;
+    const MAX_TRANSFER_POLLS: usize = 256;
+
+    fn create_class<'a>(
+        &mut self,
+        alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<EpTestUsbClass<'a, EmulatedUsbBus>> {
+        Ok(EpTestUsbClass::new(alloc))
+    }
+}
+
+struct FewPollsCtx {}
+
+impl UsbDeviceCtx for FewPollsCtx {
+    type C<'c> = EpTestUsbClass<'c, EmulatedUsbBus>;
+    const MAX_TRANSFER_POLLS: usize = 8;
+
+    fn create_class<'a>(
+        &mut self,
+        alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<EpTestUsbClass<'a, EmulatedUsbBus>> {
+        Ok(EpTestUsbClass::new(alloc))
+    }
+}
+
+type AllocFn = fn(&UsbBusAllocator<EmulatedUsbBus>) -> usb_device::Result<()>;
+
+/// Performs extra endpoint allocations before the class.
+struct AllocCtx {
+    alloc: AllocFn,
+}
+
+impl UsbDeviceCtx for AllocCtx {
+    type C<'c> = EpTestUsbClass<'c, EmulatedUsbBus>;
+
+    fn create_class<'a>(
+        &mut self,
+        alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<EpTestUsbClass<'a, EmulatedUsbBus>> {
+        (self.alloc)(alloc).map_err(|e| AnyUsbError::UserDefinedString(format!("{:?}", e)))?;
+        Ok(EpTestUsbClass::new(alloc))
+    }
+}
+
+#[derive(Default)]
+struct TickCtx<const IDLE: usize> {
+    starved: usize,
+}
+
+impl<const IDLE: usize> UsbDeviceCtx for TickCtx<IDLE> {
+    type C<'c> = EpTestUsbClass<'c, EmulatedUsbBus>;
+    const MAX_IDLE_POLLS: usize = IDLE;
+
+    fn create_class<'a>(
+        &mut self,
+        alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<EpTestUsbClass<'a, EmulatedUsbBus>> {
+        Ok(EpTestUsbClass::new(alloc))
+    }
+
+    fn hook(&mut self, cls: &mut Self::C<'_>, when: HookWhen) -> HookAction {
+        // let the class consume one packet after three polls without ticks
+        if let (HookWhen::DataIn(_), Some(0)) = (when, cls.ticks) {
+            self.starved += 1;
+            if self.starved == 3 {
+                self.starved = 0;
+                cls.ticks = Some(1);
+            }
+        }
+        HookAction::Default
+    }
+}
+
+#[test]
+fn test_allocated_endpoints() {
+    TestCtx {}
+        .with_usb(|mut _cls, dev| {
+            let ep = |index, dir| EndpointAddress::from_parts(index, dir);
+            assert_eq!(
+                dev.allocated_endpoints(),
+                [
+                    (ep(0, UsbDirection::Out), EndpointType::Control, 8),
+                    (ep(0, UsbDirection::In), EndpointType::Control, 8),
+                    (ep(1, UsbDirection::Out), EndpointType::Bulk, EP_SIZE),
+                    (ep(1, UsbDirection::In), EndpointType::Bulk, EP_SIZE),
+                ]
+            );
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_ep_write_read() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_out.address().index();
+            let data: Vec<u8> = (0..100).collect();
+
+            let len = dev.ep_write(&mut cls, ep, &data).expect("write");
+            assert_eq!(len, 100);
+            assert_eq!(cls.received, data);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_ep_read_full_packet_without_zlp() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_in.address().index();
+            cls.write_in_packet();
+
+            let mut buf = [0; 256];
+            let res = dev
+                .ep_raw(&mut cls, ep, None, None, &mut buf)
+                .expect("read");
+            assert_eq!(res.read, Some(EP_SIZE as usize));
+            assert!(!res.zlp);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_rwres_display() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_in.address().index();
+            cls.write_in_packet();
+
+            let mut buf = [0; 256];
+            let res = dev
+                .ep_raw(&mut cls, ep, None, None, &mut buf)
+                .expect("read");
+            assert_eq!(res.to_string(), format!("read {} bytes", EP_SIZE));
+            assert_eq!(res.read_len(), EP_SIZE as usize);
+            assert_eq!(res.wrote_len(), 0);
+
+            let ep = cls.ep_out.address().index();
+            let res = dev
+                .ep_raw_write(&mut cls, ep, None, &[0x55; 5])
+                .expect("write");
+            assert_eq!(res.to_string(), "wrote 5 bytes");
+            assert_eq!(res.read_len(), 0);
+            assert_eq!(res.wrote_len(), 5);
+
+            let res = usbd_class_tester::RWRes::default();
+            assert_eq!(res.to_string(), "no transfer");
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_ep_write_poll_limit_reached() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_out.address().index();
+            cls.read_chunk = 1;
+
+            let res = dev.ep_write(&mut cls, ep, &[0x55; 200]);
+            assert_eq!(
+                res,
+                Err(AnyUsbError::OutDataStuck {
+                    ep: cls.ep_out.address(),
+                    queued: 200,
+                    remaining: 200 - TestCtx::MAX_TRANSFER_POLLS,
+                    polls: TestCtx::MAX_TRANSFER_POLLS,
+                })
+            );
+            assert_eq!(cls.received.len(), TestCtx::MAX_TRANSFER_POLLS);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_ep_write_poll_limit_raised() {
+    ManyPollsCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_out.address().index();
+            cls.read_chunk = 1;
+
+            let len = dev.ep_write(&mut cls, ep, &[0x55; 200]).expect("write");
+            assert_eq!(len, 200);
+            assert_eq!(cls.received, [0x55; 200]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_ep_write_poll_limit_lowered() {
+    FewPollsCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_out.address().index();
+            cls.read_chunk = 4;
+
+            let res = dev.ep_write(&mut cls, ep, &[0x55; 64]);
+            assert_eq!(
+                res,
+                Err(AnyUsbError::OutDataStuck {
+                    ep: cls.ep_out.address(),
+                    queued: 64,
+                    remaining: 32,
+                    polls: 8,
+                })
+            );
+            assert_eq!(cls.received.len(), 32);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_ep_read_poll_limit_reached() {
+    FewPollsCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_in.address().index();
+            cls.in_stream = true;
+            cls.write_in_packet();
+
+            let res = dev.ep_read(&mut cls, ep, 1024);
+            assert_eq!(res, Err(AnyUsbError::EPReadFailed));
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_ep_write_idle_polls_default() {
+    TickCtx::<0>::default()
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_out.address().index();
+            cls.ticks = Some(0);
+
+            let res = dev
+                .ep_raw(&mut cls, ep, None, Some(&[0x55; 256]), &mut [])
+                .expect("write");
+            assert_eq!(res.idle_polls, 1);
+            assert_eq!(res.remaining, 256);
+            assert_eq!(cls.received, []);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_ep_write_idle_polls_retry() {
+    TickCtx::<4>::default()
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_out.address().index();
+            cls.ticks = Some(0);
+
+            let res = dev
+                .ep_raw(&mut cls, ep, None, Some(&[0x55; 256]), &mut [])
+                .expect("write");
+            assert_eq!(res.wrote, Some(256));
+            assert_eq!(res.idle_polls, 9);
+            assert_eq!(res.remaining, 0);
+            assert_eq!(cls.received, [0x55; 256]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_poll_n() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_out.address().index();
+            assert_eq!(dev.poll_n(&mut cls, 0), 0);
+            assert_eq!(dev.poll_n(&mut cls, 5), 0);
+
+            cls.ticks = Some(0);
+            dev.ep_raw(&mut cls, ep, None, Some(&[0x55; 256]), &mut [])
+                .expect("write");
+
+            cls.ticks = Some(2);
+            assert_eq!(dev.poll_n(&mut cls, 10), 10);
+            assert_eq!(cls.received, [0x55; 2 * EP_SIZE as usize]);
+
+            cls.ticks = None;
+            assert_eq!(dev.poll_n(&mut cls, 100), 256 / EP_SIZE as usize - 2);
+            assert_eq!(cls.received, [0x55; 256]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_bus_reset_endpoints() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep_in = cls.ep_in.address();
+            let ep_out = cls.ep_out.address();
+            let endpoints = dev.endpoints();
+
+            cls.ticks = Some(0);
+            dev.ep_raw(&mut cls, ep_out.index(), None, Some(&[0x55; 16]), &mut [])
+                .expect("write");
+            cls.write_in_packet();
+            dev.force_stall(ep_in, true).expect("stall");
+
+            dev.bus_reset(&mut cls);
+            assert!(!dev.is_endpoint_stalled(ep_in.index(), UsbDirection::In));
+            assert_eq!(dev.peek_out_buffer_len(ep_out.index()), 0);
+            assert!(dev.peek_in_buffer(ep_in.index()).is_empty());
+            assert_eq!(
+                dev.data_toggle(ep_out.index(), UsbDirection::Out),
+                DataToggle::Data0
+            );
+            assert_eq!(dev.endpoints(), endpoints);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_suspended_transfers() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_out.address().index();
+
+            dev.suspend(&mut cls);
+            let res = dev.ep_write(&mut cls, ep, &[0x55; 8]);
+            assert_eq!(res, Err(AnyUsbError::Suspended));
+            let res = dev.ep_read(&mut cls, ep, 8);
+            assert_eq!(res, Err(AnyUsbError::Suspended));
+
+            dev.resume(&mut cls);
+            dev.ep_write(&mut cls, ep, &[0x55; 8]).expect("write");
+            assert_eq!(cls.received, [0x55; 8]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_ep_write_detailed_error() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_out.address().index();
+            cls.ep_out.stall();
+
+            let res = dev.ep_write_detailed(&mut cls, ep, &[1, 2, 3]);
+            assert_eq!(
+                res,
+                Err(DetailedError {
+                    error: AnyUsbError::EPStalled,
+                    ep_index: ep,
+                    setup: None,
+                })
+            );
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_endpoint_get_status_all() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep_out = cls.ep_out.address();
+            let ep_in = cls.ep_in.address();
+
+            let status = dev.endpoint_get_status_all(&mut cls).expect("status");
+            assert_eq!(status, [(ep_out, 0), (ep_in, 0)]);
+
+            dev.endpoint_set_feature(&mut cls, ep_in.into(), 0)
+                .expect("halt");
+
+            let status = dev.endpoint_get_status_all(&mut cls).expect("status");
+            assert_eq!(status, [(ep_out, 0), (ep_in, 1)]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_endpoint_get_status_in_address() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            assert_eq!(u8::from(cls.ep_in.address()), 0x81);
+
+            dev.endpoint_set_feature(&mut cls, 0x81, 0).expect("halt");
+
+            // wIndex has the direction bit, OUT endpoint 1 is not halted
+            assert_eq!(dev.endpoint_get_status(&mut cls, 0x81), Ok(1));
+            assert_eq!(dev.endpoint_get_status(&mut cls, 0x01), Ok(0));
+
+            dev.endpoint_clear_feature(&mut cls, 0x81, 0)
+                .expect("unhalt");
+            assert_eq!(dev.endpoint_get_status(&mut cls, 0x81), Ok(0));
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_ep_transfer_raw() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep_out = cls.ep_out.address().index();
+            let ep_in = cls.ep_in.address().index();
+            cls.write_in_packet();
+
+            let mut buf = [0; 256];
+            let res = dev
+                .ep_transfer_raw(&mut cls, ep_out, ep_in, &[1, 2, 3], &mut buf)
+                .expect("transfer");
+            assert_eq!(res.wrote, Some(3));
+            assert_eq!(res.read, Some(EP_SIZE as usize));
+            assert!(!res.status_ok);
+            assert_eq!(cls.received, [1, 2, 3]);
+            assert_eq!(buf[..EP_SIZE as usize], [0xa5; EP_SIZE as usize]);
+
+            // nothing to send and nothing to read
+            let res = dev
+                .ep_transfer_raw(&mut cls, ep_out, ep_in, &[], &mut buf)
+                .expect("transfer");
+            assert_eq!(res.wrote, None);
+            assert_eq!(res.read, Some(0));
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_ep_transfer_raw_ep0_no_setup() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let mut buf = [0; 64];
+            // usb-device discards OUT data without a Setup packet
+            let res = dev
+                .ep_transfer_raw(&mut cls, 0, 0, &[0x55; 8], &mut buf)
+                .expect("transfer");
+            assert_eq!(res.wrote, Some(8));
+            assert_eq!(res.read, Some(0));
+
+            dev.device_get_status(&mut cls).expect("status");
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_ep_raw_write_in_pending() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_out.address().index();
+
+            let res = dev
+                .ep_raw_write(&mut cls, ep, None, &[1, 2, 3])
+                .expect("write");
+            assert_eq!(res.wrote, Some(3));
+            assert_eq!(res.read, None);
+            assert!(!res.in_pending);
+
+            cls.write_in_packet();
+            let res = dev
+                .ep_raw_write(&mut cls, ep, None, &[4, 5])
+                .expect("write");
+            assert!(res.in_pending);
+            assert_eq!(cls.received, [1, 2, 3, 4, 5]);
+
+            // IN data is still there
+            let data = dev.ep_read(&mut cls, ep, 256).expect("read");
+            assert_eq!(data, [0xa5; EP_SIZE as usize]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_ep_read_overrun() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_in.address().index();
+            cls.write_in_packet();
+
+            let res = dev.ep_read(&mut cls, ep, 16);
+            assert_eq!(
+                res,
+                Err(AnyUsbError::HostReadBufferTooSmall {
+                    ep: cls.ep_in.address(),
+                    needed: EP_SIZE as usize,
+                    available: 16
+                })
+            );
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_peek_buffers() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_out.address().index();
+            assert!(dev.peek_in_buffer(ep).is_empty());
+            assert_eq!(dev.peek_out_buffer_len(ep), 0);
+            assert!(dev.peek_out_buffer(ep).is_empty());
+
+            cls.write_in_packet();
+            assert_eq!(dev.peek_in_buffer(ep), [0xa5; EP_SIZE as usize]);
+            // still there
+            let data = dev.ep_read(&mut cls, ep, 256).expect("read");
+            assert_eq!(data, [0xa5; EP_SIZE as usize]);
+            assert!(dev.peek_in_buffer(ep).is_empty());
+
+            cls.ticks = Some(0);
+            let data: Vec<u8> = (0..100).collect();
+            let res = dev
+                .ep_raw(&mut cls, ep, None, Some(&data), &mut [])
+                .expect("write");
+            assert_eq!(res.remaining, 100);
+            assert_eq!(dev.peek_out_buffer_len(ep), 100);
+            assert_eq!(dev.peek_out_buffer(ep), data);
+
+            cls.ticks = Some(1);
+            dev.poll(&mut cls);
+            assert_eq!(dev.peek_out_buffer_len(ep), 100 - EP_SIZE as usize);
+            assert_eq!(dev.peek_out_buffer(ep), data[EP_SIZE as usize..]);
+            assert_eq!(cls.received, data[..EP_SIZE as usize]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_peek_buffers_no_endpoint() {
+    TestCtx {}
+        .with_usb(|_cls, dev| {
+            for ep in [5, TestCtx::NUM_ENDPOINTS, 15] {
+                assert!(dev.peek_in_buffer(ep).is_empty());
+                assert_eq!(dev.peek_out_buffer_len(ep), 0);
+                assert!(dev.peek_out_buffer(ep).is_empty());
+            }
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_ep_write_chunked_short_packet() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_out.address().index();
+            let data: Vec<u8> = (0..150).collect();
+            dev.enable_trace();
+
+            let len = dev.ep_write_chunked(&mut cls, ep, &data).expect("write");
+            assert_eq!(len, 150);
+            assert_eq!(cls.received, data);
+
+            let sizes: Vec<usize> = dev
+                .take_trace()
+                .iter()
+                .map(|tr| tr.data_out.len())
+                .collect();
+            assert_eq!(sizes, [64, 64, 22]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_ep_write_chunked_exact_multiple() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_out.address().index();
+            let data: Vec<u8> = (0..2 * EP_SIZE as u8).collect();
+            dev.enable_trace();
+
+            let len = dev.ep_write_chunked(&mut cls, ep, &data).expect("write");
+            assert_eq!(len, data.len());
+            assert_eq!(cls.received, data);
+
+            // no zero-length packet at the end
+            let sizes: Vec<usize> = dev
+                .take_trace()
+                .iter()
+                .map(|tr| tr.data_out.len())
+                .collect();
+            assert_eq!(sizes, [64, 64]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_ep_write_chunked_partial() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_out.address().index();
+            // three half-packet reads: one packet and a half
+            cls.ticks = Some(3);
+            cls.read_chunk = 32;
+
+            let len = dev
+                .ep_write_chunked(&mut cls, ep, &[0x55; 200])
+                .expect("write");
+            assert_eq!(len, 96);
+            assert_eq!(cls.received.len(), 96);
+            assert_eq!(dev.peek_out_buffer_len(ep), 32);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_force_endpoint_stall() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep_in = cls.ep_in.address();
+            let ep_out = cls.ep_out.address();
+
+            dev.force_endpoint_stall(ep_in.index(), UsbDirection::In)
+                .expect("stall");
+            let status = dev.endpoint_get_status_all(&mut cls).expect("status");
+            assert_eq!(status, [(ep_out, 0), (ep_in, 1)]);
+
+            cls.write_in_packet();
+            let res = dev.ep_read(&mut cls, ep_in.index(), 256);
+            assert_eq!(res, Err(AnyUsbError::EPStalled));
+
+            // class recovers
+            cls.ep_in.unstall();
+            let status = dev.endpoint_get_status_all(&mut cls).expect("status");
+            assert_eq!(status, [(ep_out, 0), (ep_in, 0)]);
+
+            // host clears the halt
+            dev.force_endpoint_stall(ep_out.index(), UsbDirection::Out)
+                .expect("stall");
+            let res = dev.ep_write(&mut cls, ep_out.index(), &[1, 2, 3]);
+            assert_eq!(res, Err(AnyUsbError::EPStalled));
+            dev.endpoint_clear_feature(&mut cls, ep_out.into(), 0)
+                .expect("clear");
+            dev.ep_write(&mut cls, ep_out.index(), &[1, 2, 3])
+                .expect("write");
+
+            // no such endpoint
+            let ep = EndpointAddress::from_parts(TestCtx::NUM_ENDPOINTS, UsbDirection::In);
+            let res = dev.force_endpoint_stall(ep.index(), ep.direction());
+            assert_eq!(res, Err(AnyUsbError::EndpointNotAllocated(ep)));
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_endpoint_get_status_typed() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep_in = cls.ep_in.address();
+
+            let status = dev
+                .endpoint_get_status_typed(&mut cls, ep_in.into())
+                .expect("status");
+            assert_eq!(status, EndpointStatus { halt: false });
+
+            dev.endpoint_set_feature(&mut cls, ep_in.into(), 0)
+                .expect("halt");
+            let status = dev
+                .endpoint_get_status_typed(&mut cls, ep_in.into())
+                .expect("status");
+            assert_eq!(status, EndpointStatus { halt: true });
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_endpoint_not_allocated() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep_in = EndpointAddress::from_parts(5, UsbDirection::In);
+            let ep_out = EndpointAddress::from_parts(5, UsbDirection::Out);
+
+            let res = dev.ep_read(&mut cls, 5, 64);
+            assert_eq!(res, Err(AnyUsbError::EndpointNotAllocated(ep_in)));
+            let res = dev.ep_write(&mut cls, 5, &[1, 2, 3]);
+            assert_eq!(res, Err(AnyUsbError::EndpointNotAllocated(ep_out)));
+            let res = dev.bulk_read(&mut cls, 5, 64);
+            assert_eq!(res, Err(AnyUsbError::EndpointNotAllocated(ep_in)));
+
+            let ep_in = EndpointAddress::from_parts(9, UsbDirection::In);
+            let ep_out = EndpointAddress::from_parts(9, UsbDirection::Out);
+            let res = dev.ep_write(&mut cls, 9, &[1, 2, 3]);
+            assert_eq!(res, Err(AnyUsbError::EndpointNotAllocated(ep_out)));
+            let res = dev.bulk_write(&mut cls, 9, &[1, 2, 3]);
+            assert_eq!(res, Err(AnyUsbError::EndpointNotAllocated(ep_out)));
+            let res = dev.bulk_read(&mut cls, 9, 64);
+            assert_eq!(res, Err(AnyUsbError::EndpointNotAllocated(ep_in)));
+            let res = dev.interrupt_poll(&mut cls, 9, 1);
+            assert_eq!(res, Err(AnyUsbError::EndpointNotAllocated(ep_in)));
+            let res = dev.ep_write_interrupt(&mut cls, 9, &[1]);
+            assert_eq!(res, Err(AnyUsbError::EndpointNotAllocated(ep_out)));
+            let res = dev.ep_write_chunked(&mut cls, 9, &[1]);
+            assert_eq!(res, Err(AnyUsbError::EndpointNotAllocated(ep_out)));
+
+            // EP0 is always allocated by usb-device
+            dev.device_get_status(&mut cls).expect("status");
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_endpoint_out_of_range() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            for index in [TestCtx::NUM_ENDPOINTS, 9, 15] {
+                let ep_in = EndpointAddress::from_parts(index, UsbDirection::In);
+                let ep_out = EndpointAddress::from_parts(index, UsbDirection::Out);
+                let not_in = Some(AnyUsbError::EndpointNotAllocated(ep_in));
+                let not_out = Some(AnyUsbError::EndpointNotAllocated(ep_out));
+
+                assert_eq!(dev.ep_read(&mut cls, index, 64).err(), not_in);
+                assert_eq!(dev.ep_read_exact(&mut cls, index, 64).err(), not_in);
+                assert_eq!(dev.ep_read_packet(&mut cls, index).err(), not_in);
+                assert_eq!(dev.ep_read_packets(&mut cls, index, 2).err(), not_in);
+                assert_eq!(dev.drain_endpoint(&mut cls, index).err(), not_in);
+                assert_eq!(dev.wait_for_in_data(&mut cls, index, 3).err(), not_in);
+                assert_eq!(dev.bulk_read(&mut cls, index, 64).err(), not_in);
+                assert_eq!(dev.interrupt_poll(&mut cls, index, 1).err(), not_in);
+                assert_eq!(dev.iso_read_frame(&mut cls, index).err(), not_in);
+
+                assert_eq!(dev.ep_write(&mut cls, index, &[1]).err(), not_out);
+                assert_eq!(dev.ep_write_chunked(&mut cls, index, &[1]).err(), not_out);
+                assert_eq!(dev.bulk_write(&mut cls, index, &[1]).err(), not_out);
+                assert_eq!(dev.ep_write_interrupt(&mut cls, index, &[1]).err(), not_out);
+                assert_eq!(dev.iso_write_frame(&mut cls, index, &[1]).err(), not_out);
+
+                let res = dev.ep_raw(&mut cls, index, None, None, &mut [0; 64]);
+                assert_eq!(res.err(), not_in);
+                let res = dev.ep_raw_write(&mut cls, index, None, &[1]);
+                assert_eq!(res.err(), not_out);
+                let res = dev.ep_transfer_raw(&mut cls, index, 1, &[1], &mut [0; 64]);
+                assert_eq!(res.err(), not_out);
+                let res = dev.ep_transfer_raw(&mut cls, 1, index, &[], &mut [0; 64]);
+                assert_eq!(res.err(), not_in);
+
+                let res = dev.ep_read_detailed(&mut cls, index, 64);
+                assert_eq!(res.map_err(|e| e.error).err(), not_in);
+                let res = dev.ep_write_detailed(&mut cls, index, &[1]);
+                assert_eq!(res.map_err(|e| e.error).err(), not_out);
+            }
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_endpoint_not_configured() {
+    UnconfiguredCtx::<true> {}
+        .with_usb(|mut cls, mut dev| {
+            let ep_out = cls.ep_out.address();
+            let ep_in = cls.ep_in.address();
+
+            let res = dev.ep_write(&mut cls, ep_out.index(), &[1, 2, 3]);
+            assert_eq!(res, Err(AnyUsbError::NotConfigured(ep_out)));
+            let res = dev.ep_read(&mut cls, ep_in.index(), 64);
+            assert_eq!(res, Err(AnyUsbError::NotConfigured(ep_in)));
+
+            // EP0 works in any state
+            dev.device_get_status(&mut cls).expect("status");
+
+            dev.setup(&mut cls).expect("setup");
+            dev.ep_write(&mut cls, ep_out.index(), &[1, 2, 3])
+                .expect("write");
+
+            dev.device_set_configuration(&mut cls, 0)
+                .expect("unconfigure");
+            let res = dev.ep_write(&mut cls, ep_out.index(), &[1, 2, 3]);
+            assert_eq!(res, Err(AnyUsbError::NotConfigured(ep_out)));
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_endpoint_not_configured_allowed() {
+    UnconfiguredCtx::<false> {}
+        .with_usb(|mut cls, mut dev| {
+            let ep_out = cls.ep_out.address();
+
+            dev.ep_write(&mut cls, ep_out.index(), &[1, 2, 3])
+                .expect("write");
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_strict_packet_size() {
+    StrictSizeCtx::<true> {}
+        .with_usb(|mut cls, mut dev| {
+            let ep_in = cls.ep_in.address();
+
+            cls.ep_in.write(&[0; EP_SIZE as usize + 1]).ok();
+            let res = dev.ep_read(&mut cls, ep_in.index(), 64);
+            assert_eq!(
+                res,
+                Err(AnyUsbError::PacketSizeViolation {
+                    ep: ep_in,
+                    max_size: EP_SIZE as usize,
+                    got: EP_SIZE as usize + 1
+                })
+            );
+
+            // reported once
+            let res = dev.ep_read(&mut cls, ep_in.index(), 64).expect("read");
+            assert!(res.is_empty());
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_packet_size_not_strict() {
+    StrictSizeCtx::<false> {}
+        .with_usb(|mut cls, mut dev| {
+            let ep_in = cls.ep_in.address();
+
+            cls.ep_in.write(&[0; EP_SIZE as usize + 1]).ok();
+            let res = dev.ep_read(&mut cls, ep_in.index(), 64).expect("read");
+            assert!(res.is_empty());
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_endpoint_halt_status() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            for ep in [cls.ep_in.address(), cls.ep_out.address()] {
+                let other = match ep.direction() {
+                    UsbDirection::In => UsbDirection::Out,
+                    UsbDirection::Out => UsbDirection::In,
+                };
+                assert!(!dev.is_endpoint_stalled(ep.index(), ep.direction()));
+
+                dev.endpoint_set_feature(&mut cls, ep.into(), 0)
+                    .expect("halt");
+                assert!(dev.is_endpoint_stalled(ep.index(), ep.direction()));
+                assert!(!dev.is_endpoint_stalled(ep.index(), other));
+                let status = dev
+                    .endpoint_get_status_typed(&mut cls, ep.into())
+                    .expect("status");
+                assert_eq!(status, EndpointStatus { halt: true });
+
+                dev.endpoint_clear_feature(&mut cls, ep.into(), 0)
+                    .expect("clear");
+                assert!(!dev.is_endpoint_stalled(ep.index(), ep.direction()));
+                let status = dev
+                    .endpoint_get_status_typed(&mut cls, ep.into())
+                    .expect("status");
+                assert_eq!(status, EndpointStatus { halt: false });
+            }
+
+            assert!(!dev.is_endpoint_stalled(5, UsbDirection::In));
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_endpoint_halt_unhalt() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep_in = cls.ep_in.address();
+
+            dev.endpoint_halt(&mut cls, ep_in).expect("halt");
+            let res = dev.ep_read(&mut cls, ep_in.index(), EP_SIZE);
+            assert_eq!(res, Err(AnyUsbError::EPStalled));
+
+            dev.endpoint_unhalt(&mut cls, ep_in).expect("unhalt");
+            cls.write_in_packet();
+            let data = dev.ep_read(&mut cls, ep_in.index(), EP_SIZE).expect("read");
+            assert_eq!(data, [0xa5; EP_SIZE as usize]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_data_toggle() {
+    StrictToggleCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep_out = cls.ep_out.address();
+            let ep_in = cls.ep_in.address();
+            let toggle = |dev: &Device<_, _>, ep: EndpointAddress| {
+                dev.data_toggle(ep.index(), ep.direction())
+            };
+
+            assert_eq!(toggle(&dev, ep_out), DataToggle::Data0);
+            dev.ep_write(&mut cls, ep_out.index(), &[1, 2, 3])
+                .expect("write");
+            assert_eq!(toggle(&dev, ep_out), DataToggle::Data1);
+            // two packets
+            dev.ep_write(&mut cls, ep_out.index(), &[1; EP_SIZE as usize + 1])
+                .expect("write");
+            assert_eq!(toggle(&dev, ep_out), DataToggle::Data1);
+
+            cls.write_in_packet();
+            dev.ep_read(&mut cls, ep_in.index(), EP_SIZE).expect("read");
+            assert_eq!(toggle(&dev, ep_in), DataToggle::Data1);
+
+            // SET_CONFIGURATION resets toggles
+            dev.ep_write(&mut cls, ep_out.index(), &[1, 2, 3])
+                .expect("write");
+            dev.device_set_configuration(&mut cls, 1)
+                .expect("configure");
+            assert_eq!(toggle(&dev, ep_out), DataToggle::Data0);
+            assert_eq!(toggle(&dev, ep_in), DataToggle::Data0);
+
+            // no such endpoint
+            let ep = StrictToggleCtx::NUM_ENDPOINTS;
+            assert_eq!(dev.data_toggle(ep, UsbDirection::In), DataToggle::Data0);
+            assert_eq!(dev.data_toggle(ep, UsbDirection::Out), DataToggle::Data0);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_data_toggle_halt_resets() {
+    StrictToggleCtx {}
+        .with_usb(|mut cls, mut dev| {
+            for ep in [cls.ep_out.address(), cls.ep_in.address()] {
+                cls.write_in_packet();
+                dev.ep_transfer_raw(&mut cls, ep.index(), ep.index(), &[1], &mut [0; 64])
+                    .expect("transfer");
+                assert_eq!(
+                    dev.data_toggle(ep.index(), ep.direction()),
+                    DataToggle::Data1
+                );
+
+                dev.endpoint_halt(&mut cls, ep).expect("halt");
+                dev.endpoint_unhalt(&mut cls, ep).expect("unhalt");
+                assert_eq!(
+                    dev.data_toggle(ep.index(), ep.direction()),
+                    DataToggle::Data0
+                );
+
+                // both sides are reset
+                cls.write_in_packet();
+                dev.ep_transfer_raw(&mut cls, ep.index(), ep.index(), &[1], &mut [0; 64])
+                    .expect("transfer");
+            }
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_data_toggle_mismatch() {
+    StrictToggleCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep_out = cls.ep_out.address();
+
+            dev.ep_write(&mut cls, ep_out.index(), &[1, 2, 3])
+                .expect("write");
+
+            // the Device resets its toggle without telling the Host
+            cls.ep_out.unstall();
+            let res = dev.ep_write(&mut cls, ep_out.index(), &[1, 2, 3]);
+            assert_eq!(
+                res,
+                Err(AnyUsbError::DataToggleMismatch {
+                    ep: ep_out,
+                    expected: DataToggle::Data0
+                })
+            );
+
+            // the Device dropped the packet and resynchronized
+            dev.ep_write(&mut cls, ep_out.index(), &[1, 2, 3])
+                .expect("write");
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_out_nak_busy() {
+    NakCtx::<3> {}
+        .with_usb(|mut cls, mut dev| {
+            let ep_out = cls.ep_out.address();
+            cls.ticks = Some(0);
+
+            dev.ep_raw(&mut cls, ep_out.index(), None, Some(&[1; 16]), &mut [])
+                .expect("write");
+
+            let res = dev.ep_write(&mut cls, ep_out.index(), &[2; 16]);
+            assert_eq!(
+                res,
+                Err(AnyUsbError::EndpointBusy {
+                    ep: ep_out,
+                    retries: 3,
+                    remaining: 16
+                })
+            );
+            let res = dev.ep_write_chunked(&mut cls, ep_out.index(), &[2; 16]);
+            assert!(matches!(res, Err(AnyUsbError::EndpointBusy { .. })));
+            assert_eq!(dev.peek_out_buffer_len(ep_out.index()), 16);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_out_nak_retry() {
+    NakCtx::<3> {}
+        .with_usb(|mut cls, mut dev| {
+            let ep_out = cls.ep_out.address();
+            cls.ticks = Some(0);
+            cls.read_chunk = 8;
+
+            dev.ep_raw(&mut cls, ep_out.index(), None, Some(&[1; 16]), &mut [])
+                .expect("write");
+
+            // the class consumes the old data during retries
+            cls.ticks = None;
+            let len = dev
+                .ep_write(&mut cls, ep_out.index(), &[2; 16])
+                .expect("write");
+            assert_eq!(len, 16);
+            assert_eq!(cls.received, [[1; 16], [2; 16]].concat());
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_force_stall() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            for ep in [cls.ep_in.address(), cls.ep_out.address()] {
+                dev.force_stall(ep, true).expect("stall");
+                assert!(dev.usb_dev().bus().is_stalled(ep));
+                let status = dev
+                    .endpoint_get_status_typed(&mut cls, ep.into())
+                    .expect("status");
+                assert_eq!(status, EndpointStatus { halt: true });
+
+                dev.force_stall(ep, false).expect("stall");
+                assert!(!dev.usb_dev().bus().is_stalled(ep));
+            }
+
+            let ep_in = cls.ep_in.address();
+            dev.force_stall(ep_in, true).expect("stall");
+            let res = dev.ep_read(&mut cls, ep_in.index(), EP_SIZE);
+            assert_eq!(res, Err(AnyUsbError::EPStalled));
+
+            // Host recovers with CLEAR_FEATURE
+            dev.endpoint_unhalt(&mut cls, ep_in).expect("unhalt");
+            cls.write_in_packet();
+            let data = dev.ep_read(&mut cls, ep_in.index(), EP_SIZE).expect("read");
+            assert_eq!(data, [0xa5; EP_SIZE as usize]);
+
+            // not allocated or no such endpoint
+            for index in [2, TestCtx::NUM_ENDPOINTS] {
+                let ep = EndpointAddress::from_parts(index, UsbDirection::Out);
+                let res = dev.force_stall(ep, true);
+                assert_eq!(res, Err(AnyUsbError::EndpointNotAllocated(ep)));
+            }
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_endpoint_bytes_transferred() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_in.address().index();
+            dev.reset_stats();
+
+            for i in 1..=3 {
+                cls.write_in_packet();
+                dev.ep_read(&mut cls, ep, EP_SIZE).expect("read");
+                assert_eq!(
+                    dev.endpoint_bytes_transferred(ep, UsbDirection::In),
+                    i * EP_SIZE as usize
+                );
+            }
+
+            dev.ep_write(&mut cls, ep, &[1; 10]).expect("write");
+            assert_eq!(dev.endpoint_bytes_transferred(ep, UsbDirection::Out), 10);
+            assert_eq!(
+                dev.endpoint_bytes_transferred(ep, UsbDirection::In),
+                3 * EP_SIZE as usize
+            );
+
+            dev.reset_stats();
+            assert_eq!(dev.endpoint_bytes_transferred(ep, UsbDirection::In), 0);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_endpoint_bytes_transferred_bus_reset() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_in.address().index();
+
+            cls.write_in_packet();
+            dev.ep_read(&mut cls, ep, EP_SIZE).expect("read");
+            dev.ep_write(&mut cls, ep, &[1; 10]).expect("write");
+            assert_ne!(dev.endpoint_bytes_transferred(ep, UsbDirection::In), 0);
+
+            dev.bus_reset(&mut cls);
+            assert_eq!(dev.endpoint_bytes_transferred(ep, UsbDirection::In), 0);
+            assert_eq!(dev.endpoint_bytes_transferred(ep, UsbDirection::Out), 0);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_ep_stats() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep_out = cls.ep_out.address();
+            let ep_in = cls.ep_in.address();
+
+            dev.reset_stats();
+            assert_eq!(dev.ep_stats(ep_out), EpStats::default());
+
+            dev.ep_write(&mut cls, ep_out.index(), &[1; 100])
+                .expect("write");
+            let stats = dev.ep_stats(ep_out);
+            assert_eq!(stats.packets, 2);
+            assert_eq!(stats.bytes, 100);
+            assert_eq!(stats.polls, 2);
+            assert_eq!(stats.stalled, 0);
+
+            // busy-polling an empty endpoint
+            dev.reset_stats();
+            for _ in 0..3 {
+                let res = cls.ep_out.read(&mut [0; EP_SIZE as usize]);
+                assert_eq!(res, Err(UsbError::WouldBlock));
+            }
+            let stats = dev.ep_stats(ep_out);
+            assert_eq!(stats.would_block, 3);
+            assert_eq!(stats.packets, 0);
+            assert_eq!(stats.polls, 0);
+
+            cls.write_in_packet();
+            dev.ep_read(&mut cls, ep_in.index(), EP_SIZE).expect("read");
+            let stats = dev.ep_stats(ep_in);
+            assert_eq!(stats.packets, 1);
+            assert_eq!(stats.bytes, EP_SIZE as usize);
+            assert_eq!(stats.polls, 1);
+
+            dev.force_stall(ep_in, true).expect("stall");
+            cls.write_in_packet();
+            assert_eq!(dev.ep_stats(ep_in).stalled, 1);
+
+            // control transfer: Setup, IN data and status packets
+            dev.reset_stats();
+            dev.device_get_status(&mut cls).expect("status");
+            let ep0_out = EndpointAddress::from_parts(0, UsbDirection::Out);
+            let ep0_in = EndpointAddress::from_parts(0, UsbDirection::In);
+            assert_eq!(dev.ep_stats(ep0_out).packets, 2);
+            assert_eq!(dev.ep_stats(ep0_in).packets, 1);
+            assert_eq!(dev.ep_stats(ep0_in).bytes, 2);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_strict_out_data() {
+    StrictOutCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_out.address().index();
+
+            let len = dev.ep_write(&mut cls, ep, &[1; 16]).expect("write");
+            assert_eq!(len, 16);
+
+            cls.ticks = Some(0);
+            let res = dev.ep_write(&mut cls, ep, &[2; 16]);
+            assert_eq!(res, Err(AnyUsbError::EPDataRemaining { remaining: 16 }));
+            let res = dev.ep_raw(&mut cls, ep, None, Some(&[3; 8]), &mut []);
+            assert!(matches!(
+                res,
+                Err(AnyUsbError::EPDataRemaining { remaining: 24 })
+            ));
+            assert_eq!(cls.received, [1; 16]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_poll_from_thread() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep_out = cls.ep_out.address();
+            let data: Vec<u8> = (0..100).collect();
+
+            std::thread::scope(|s| {
+                s.spawn(|| {
+                    let len = dev
+                        .ep_write(&mut cls, ep_out.index(), &data)
+                        .expect("write");
+                    assert_eq!(len, 100);
+                    dev.poll_n(&mut cls, 3);
+                });
+            });
+            assert_eq!(cls.received, data);
+
+            // the bus is shared between threads
+            dev.force_endpoint_stall(ep_out.index(), UsbDirection::Out)
+                .expect("stall");
+            let bus = dev.usb_dev().bus();
+            std::thread::scope(|s| {
+                let threads: Vec<_> = (0..2).map(|_| s.spawn(|| bus.is_stalled(ep_out))).collect();
+                for t in threads {
+                    assert!(t.join().expect("join"));
+                }
+            });
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_drain_endpoint() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_in.address().index();
+            assert_eq!(dev.drain_endpoint(&mut cls, ep), Ok(vec![]));
+
+            cls.write_in_packet();
+            let data = dev.drain_endpoint(&mut cls, ep).expect("drain");
+            assert_eq!(data, [0xa5; EP_SIZE as usize]);
+            assert!(dev.peek_in_buffer(ep).is_empty());
+
+            // the class keeps sending, draining stops at the poll limit
+            cls.in_stream = true;
+            cls.write_in_packet();
+            let data = dev.drain_endpoint(&mut cls, ep).expect("drain");
+            assert_eq!(data.len(), TestCtx::MAX_TRANSFER_POLLS * EP_SIZE as usize);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_reset_buffers() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_out.address().index();
+            cls.ticks = Some(0);
+            dev.ep_raw(&mut cls, ep, None, Some(&[1; 16]), &mut [])
+                .expect("write");
+            cls.write_in_packet();
+            dev.force_endpoint_stall(ep, UsbDirection::Out)
+                .expect("stall");
+
+            assert_eq!(dev.reset_buffers(), 16 + EP_SIZE as usize);
+            assert_eq!(dev.peek_out_buffer_len(ep), 0);
+            assert!(dev.peek_in_buffer(ep).is_empty());
+            assert!(dev.is_endpoint_stalled(ep, UsbDirection::Out));
+            assert_eq!(dev.reset_buffers(), 0);
+        })
+        .expect("with_usb");
+}
+
+fn alloc_at<D: usb_device::endpoint::EndpointDirection>(
+    alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    addr: u8,
+    ep_type: EndpointType,
+) -> usb_device::Result<EndpointAddress> {
+    alloc
+        .alloc::<D>(Some(addr.into()), ep_type, 16, 1)
+        .map(|ep| ep.address())
+}
+
+#[test]
+fn test_alloc_explicit_duplicate() {
+    let res = AllocCtx {
+        alloc: |alloc| {
+            alloc_at::<Out>(alloc, 0x02, EndpointType::Bulk)?;
+            alloc_at::<Out>(alloc, 0x02, EndpointType::Bulk)?;
+            Ok(())
+        },
+    }
+    .with_usb(|mut _cls, mut _dev| {
+        unreachable!("case should not run");
+    });
+    assert_eq!(
+        res,
+        Err(AnyUsbError::UserDefinedString("InvalidEndpoint".into()))
+    );
+}
+
+#[test]
+fn test_alloc_auto_then_explicit() {
+    let res = AllocCtx {
+        alloc: |alloc| {
+            let ep = alloc.interrupt::<Out>(16, 1);
+            assert_eq!(ep.address(), EndpointAddress::from(0x01));
+            // a different type doesn't matter
+            alloc_at::<Out>(alloc, 0x01, EndpointType::Bulk)?;
+            Ok(())
+        },
+    }
+    .with_usb(|mut _cls, mut _dev| {
+        unreachable!("case should not run");
+    });
+    assert_eq!(
+        res,
+        Err(AnyUsbError::UserDefinedString("InvalidEndpoint".into()))
+    );
+}
+
+#[test]
+fn test_alloc_direction_differs() {
+    AllocCtx {
+        alloc: |alloc| {
+            alloc_at::<In>(alloc, 0x81, EndpointType::Interrupt)?;
+            alloc_at::<Out>(alloc, 0x01, EndpointType::Bulk)?;
+            Ok(())
+        },
+    }
+    .with_usb(|cls, dev| {
+        // automatic assignment skips allocated endpoints
+        assert_eq!(cls.ep_out.address(), EndpointAddress::from(0x02));
+        assert_eq!(cls.ep_in.address(), EndpointAddress::from(0x82));
+
+        let eps: Vec<_> = dev
+            .endpoints()
+            .iter()
+            .map(|ep| (u8::from(ep.address), ep.ep_type))
+            .collect();
+        assert_eq!(
+            eps,
+            [
+                (0x01, EndpointType::Bulk),
+                (0x81, EndpointType::Interrupt),
+                (0x02, EndpointType::Bulk),
+                (0x82, EndpointType::Bulk),
+            ]
+        );
+    })
+    .expect("with_usb");
+}
+
+/// Fails reads of EP1 OUT with `WouldBlock`
+/// while `reads` is not zero.
+struct WouldBlockInjector {
+    reads: &'static AtomicUsize,
+}
+
+impl FaultInjector for WouldBlockInjector {
+    fn read(&mut self, ep: EndpointAddress) -> Option<UsbError> {
+        if ep != EndpointAddress::from(0x01) || self.reads.load(Ordering::Relaxed) == 0 {
+            return None;
+        }
+        self.reads.fetch_sub(1, Ordering::Relaxed);
+        Some(UsbError::WouldBlock)
+    }
+}
+
+struct FaultCtx {
+    reads: &'static AtomicUsize,
+}
+
+impl UsbDeviceCtx for FaultCtx {
+    type C<'c> = EpTestUsbClass<'c, EmulatedUsbBus>;
+    const MAX_IDLE_POLLS: usize = 4;
+
+    fn create_class<'a>(
+        &mut self,
+        alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<EpTestUsbClass<'a, EmulatedUsbBus>> {
+        Ok(EpTestUsbClass::new(alloc))
+    }
+
+    fn fault_injector(&mut self) -> Option<Box<dyn FaultInjector>> {
+        Some(Box::new(WouldBlockInjector { reads: self.reads }))
+    }
+}
+
+static RETRY_READS: AtomicUsize = AtomicUsize::new(0);
+
+#[test]
+fn test_fault_injector_retry() {
+    FaultCtx {
+        reads: &RETRY_READS,
+    }
+    .with_usb(|mut cls, mut dev| {
+        let ep = cls.ep_out.address().index();
+        RETRY_READS.store(3, Ordering::Relaxed);
+
+        let res = dev
+            .ep_raw(&mut cls, ep, None, Some(&[1; 16]), &mut [])
+            .expect("write");
+        assert_eq!(res.idle_polls, 3);
+        assert_eq!(res.remaining, 0);
+        assert_eq!(cls.received, [1; 16]);
+    })
+    .expect("with_usb");
+}
+
+static STORM_READS: AtomicUsize = AtomicUsize::new(0);
+
+#[test]
+fn test_fault_injector_storm() {
+    FaultCtx {
+        reads: &STORM_READS,
+    }
+    .with_usb(|mut cls, mut dev| {
+        let ep = cls.ep_out.address().index();
+        STORM_READS.store(100, Ordering::Relaxed);
+
+        let res = dev
+            .ep_raw(&mut cls, ep, None, Some(&[1; 16]), &mut [])
+            .expect("write");
+        assert_eq!(res.remaining, 16);
+        assert_eq!(dev.peek_out_buffer_len(ep), 16);
+        assert!(cls.received.is_empty());
+    })
+    .expect("with_usb");
+}
+
+struct InCompleteCtx<const ON_POLL: bool> {}
+
+impl<const ON_POLL: bool> UsbDeviceCtx for InCompleteCtx<ON_POLL> {
+    type C<'c> = EpTestUsbClass<'c, EmulatedUsbBus>;
+    const IN_COMPLETE: InCompleteMode = if ON_POLL {
+        InCompleteMode::OnNextPoll
+    } else {
+        InCompleteMode::OnHostRead
+    };
+
+    fn create_class<'a>(
+        &mut self,
+        alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<EpTestUsbClass<'a, EmulatedUsbBus>> {
+        Ok(EpTestUsbClass::new(alloc))
+    }
+}
+
+#[test]
+fn test_in_complete_on_host_read() {
+    InCompleteCtx::<false> {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_in.address().index();
+            for n in 0..4 {
+                cls.ep_in.write(&[n; 16]).expect("write");
+            }
+
+            dev.poll_n(&mut cls, 8);
+            assert_eq!(cls.in_complete, 0);
+
+            let data = dev.ep_read(&mut cls, ep, 64).expect("read");
+            assert_eq!(data.len(), 64);
+            dev.poll_n(&mut cls, 8);
+            assert_eq!(cls.in_complete, 4);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_in_complete_on_next_poll() {
+    InCompleteCtx::<true> {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_in.address().index();
+            for n in 0..4 {
+                cls.ep_in.write(&[n; 16]).expect("write");
+            }
+
+            dev.poll_n(&mut cls, 8);
+            assert_eq!(cls.in_complete, 4);
+
+            let data = dev.ep_read(&mut cls, ep, 64).expect("read");
+            assert_eq!(data.len(), 64);
+            dev.poll_n(&mut cls, 8);
+            assert_eq!(cls.in_complete, 4);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_in_complete_packets() {
+    InCompleteCtx::<false> {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_in.address();
+            for n in 0..3 {
+                cls.ep_in.write(&[n; 8]).expect("write");
+            }
+
+            let packets = dev.ep_read_packets(&mut cls, ep.index(), 3).expect("read");
+            assert_eq!(packets.len(), 3);
+            dev.poll_n(&mut cls, 8);
+            assert_eq!(cls.in_complete, 3);
+            assert_eq!(dev.ep_stats(ep).polls, 3);
+        })
+        .expect("with_usb");
+}
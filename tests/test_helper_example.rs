@@ -1,6 +1,7 @@
 //! An example how to "extend"/add helper methods to
 //! `Device` by using a module that implements a new
 //! trait for `Device`.
+
 mod test_device1;
 use test_device1::*;
 
@@ -15,11 +16,8 @@ struct TestCtx {}
 
 impl UsbDeviceCtx for TestCtx {
     type C<'c> = TestUsbClass;
-    fn create_class<'a>(
-        &mut self,
-        alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
-    ) -> AnyResult<TestUsbClass> {
-        Ok(TestUsbClass::new(&alloc))
+    fn create_class(&mut self, alloc: &UsbBusAllocator<EmulatedUsbBus>) -> AnyResult<TestUsbClass> {
+        Ok(TestUsbClass::new(alloc))
     }
 }
 
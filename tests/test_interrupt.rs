@@ -0,0 +1,580 @@
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::{InterfaceNumber, UsbBus, UsbBusAllocator};
+use usb_device::class::UsbClass;
+use usb_device::endpoint::{EndpointAddress, EndpointIn, EndpointOut, EndpointType};
+
+const EP_SIZE: u16 = 8;
+
+/// Sends queued reports through its Interrupt IN endpoint,
+/// one report per transmitted packet.
+struct ReportUsbClass<'a, B: UsbBus> {
+    ep_in: EndpointIn<'a, B>,
+    reports: Vec<Vec<u8>>,
+    in_busy: bool,
+}
+
+impl<'a, B: UsbBus> ReportUsbClass<'a, B> {
+    fn new(alloc: &'a UsbBusAllocator<B>) -> Self {
+        Self {
+            ep_in: alloc.interrupt(EP_SIZE, 1),
+            reports: Vec::new(),
+            in_busy: false,
+        }
+    }
+
+    fn push_report(&mut self, report: &[u8]) {
+        self.reports.push(report.to_vec());
+        self.write_report();
+    }
+
+    fn write_report(&mut self) {
+        if self.in_busy || self.reports.is_empty() {
+            return;
+        }
+        if self.ep_in.write(&self.reports[0]).is_ok() {
+            self.reports.remove(0);
+            self.in_busy = true;
+        }
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for ReportUsbClass<'_, B> {
+    fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
+        if addr == self.ep_in.address() {
+            self.in_busy = false;
+            self.write_report();
+        }
+    }
+}
+
+/// Pushes a report from the hook after `DELAY` polls
+/// with IN attempts.
+#[derive(Default)]
+struct TestCtx<const DELAY: usize, const EMPTY: usize = 0> {
+    polls: usize,
+}
+
+impl<const DELAY: usize, const EMPTY: usize> UsbDeviceCtx for TestCtx<DELAY, EMPTY> {
+    type C<'c> = ReportUsbClass<'c, EmulatedUsbBus>;
+    const MAX_IN_EMPTY_POLLS: usize = EMPTY;
+
+    fn create_class<'a>(
+        &mut self,
+        alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<ReportUsbClass<'a, EmulatedUsbBus>> {
+        Ok(ReportUsbClass::new(alloc))
+    }
+
+    fn hook(&mut self, cls: &mut Self::C<'_>, when: HookWhen) -> HookAction {
+        if let HookWhen::DataOut(ep) = when {
+            if DELAY > 0 && ep == cls.ep_in.address() {
+                self.polls += 1;
+                if self.polls == DELAY {
+                    cls.push_report(&[0xde, 0xad]);
+                }
+            }
+        }
+        HookAction::Default
+    }
+}
+
+#[test]
+fn test_interrupt_poll_no_reports() {
+    TestCtx::<0>::default()
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_in.address().index();
+
+            let reports = dev.interrupt_poll(&mut cls, ep, 10).expect("poll");
+            assert!(reports.is_empty());
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_interrupt_poll_one_report() {
+    TestCtx::<0>::default()
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_in.address().index();
+            cls.push_report(&[1, 2, 3]);
+
+            let reports = dev.interrupt_poll(&mut cls, ep, 10).expect("poll");
+            assert_eq!(reports, [[1, 2, 3]]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_interrupt_poll_queued_reports() {
+    TestCtx::<0>::default()
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_in.address().index();
+            cls.push_report(&[1]);
+            cls.push_report(&[]);
+            cls.push_report(&[3; EP_SIZE as usize]);
+
+            // one report per frame
+            let reports = dev.interrupt_poll(&mut cls, ep, 2).expect("poll");
+            assert_eq!(reports, [vec![1], vec![]]);
+
+            let reports = dev.interrupt_poll(&mut cls, ep, 2).expect("poll");
+            assert_eq!(reports, [vec![3; EP_SIZE as usize]]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_interrupt_poll_delayed_report() {
+    TestCtx::<5>::default()
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_in.address().index();
+
+            // report appears after the fifth frame's poll
+            let reports = dev.interrupt_poll(&mut cls, ep, 5).expect("poll");
+            assert!(reports.is_empty());
+
+            let reports = dev.interrupt_poll(&mut cls, ep, 5).expect("poll");
+            assert_eq!(reports, [[0xde, 0xad]]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_interrupt_poll_stalled() {
+    TestCtx::<0>::default()
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_in.address().index();
+            cls.ep_in.stall();
+
+            let res = dev.interrupt_poll(&mut cls, ep, 3);
+            assert_eq!(res, Err(AnyUsbError::EPStalled));
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_ep_read_packet_boundaries() {
+    TestCtx::<0>::default()
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_in.address().index();
+            for n in 1..=3 {
+                cls.ep_in.write(&[n; 4]).expect("write");
+            }
+
+            let packet = dev.ep_read_packet(&mut cls, ep).expect("read");
+            assert_eq!(packet, Some(vec![1; 4]));
+
+            let packets = dev.ep_read_packets(&mut cls, ep, 2).expect("read");
+            assert_eq!(packets, [[2; 4], [3; 4]]);
+
+            assert_eq!(dev.ep_read_packet(&mut cls, ep), Ok(None));
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_ep_read_packets_zero_length() {
+    TestCtx::<0>::default()
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_in.address().index();
+            cls.ep_in.write(&[1, 2]).expect("write");
+            cls.ep_in.write(&[]).expect("write");
+
+            let packets = dev.ep_read_packets(&mut cls, ep, 2).expect("read");
+            assert_eq!(packets, [vec![1, 2], vec![]]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_ep_read_concatenates_packets() {
+    TestCtx::<0>::default()
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_in.address().index();
+            for n in 1..=3 {
+                cls.ep_in.write(&[n; 4]).expect("write");
+            }
+
+            let data = dev.ep_read(&mut cls, ep, 64).expect("read");
+            assert_eq!(data, [[1; 4], [2; 4], [3; 4]].concat());
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_interrupt_poll_back_to_back_reports() {
+    TestCtx::<0>::default()
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_in.address().index();
+            for n in 1..=3 {
+                cls.ep_in.write(&[n; 4]).expect("write");
+            }
+
+            let reports = dev.interrupt_poll(&mut cls, ep, 5).expect("poll");
+            assert_eq!(reports, [[1; 4], [2; 4], [3; 4]]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_wait_for_in_data_ready() {
+    TestCtx::<0>::default()
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_in.address().index();
+            cls.push_report(&[1, 2]);
+
+            let data = dev.wait_for_in_data(&mut cls, ep, 0).expect("wait");
+            assert_eq!(data, [1, 2]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_wait_for_in_data_first_poll() {
+    TestCtx::<1>::default()
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_in.address().index();
+
+            let data = dev.wait_for_in_data(&mut cls, ep, 1).expect("wait");
+            assert_eq!(data, [0xde, 0xad]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_wait_for_in_data_nth_poll() {
+    TestCtx::<7>::default()
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_in.address().index();
+
+            let res = dev.wait_for_in_data(&mut cls, ep, 6);
+            assert_eq!(res, Err(AnyUsbError::InDataTimeout { polls: 6 }));
+
+            let data = dev.wait_for_in_data(&mut cls, ep, 10).expect("wait");
+            assert_eq!(data, [0xde, 0xad]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_wait_for_in_data_never() {
+    TestCtx::<0>::default()
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_in.address().index();
+
+            let res = dev.wait_for_in_data(&mut cls, ep, 20);
+            assert_eq!(res, Err(AnyUsbError::InDataTimeout { polls: 20 }));
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_ep_read_packets_delayed() {
+    TestCtx::<5>::default()
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_in.address().index();
+            cls.ep_in.write(&[1]).expect("write");
+
+            // the second packet appears after several polls
+            let packets = dev.ep_read_packets(&mut cls, ep, 2).expect("read");
+            assert_eq!(packets, [vec![1], vec![0xde, 0xad]]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_ep_read_packets_fewer() {
+    TestCtx::<0>::default()
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_in.address().index();
+            cls.ep_in.write(&[1]).expect("write");
+
+            let res = dev.ep_read_packets(&mut cls, ep, 2);
+            assert_eq!(
+                res,
+                Err(AnyUsbError::PacketCountMismatch {
+                    expected: 2,
+                    got: 1
+                })
+            );
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_ep_read_packets_more() {
+    TestCtx::<0>::default()
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_in.address().index();
+            for n in 1..=4 {
+                cls.ep_in.write(&[n]).expect("write");
+            }
+
+            let res = dev.ep_read_packets(&mut cls, ep, 2);
+            assert_eq!(
+                res,
+                Err(AnyUsbError::PacketCountMismatch {
+                    expected: 2,
+                    got: 4
+                })
+            );
+
+            // extra packets are still there
+            let packets = dev.ep_read_packets(&mut cls, ep, 2).expect("read");
+            assert_eq!(packets, [[3], [4]]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_ep_read_empty_polls_default() {
+    TestCtx::<2>::default()
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_in.address().index();
+
+            let data = dev.ep_read(&mut cls, ep, 64).expect("read");
+            assert!(data.is_empty());
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_ep_read_empty_polls_retry() {
+    TestCtx::<2, 2>::default()
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_in.address().index();
+
+            // the report is staged on the second poll
+            let data = dev.ep_read(&mut cls, ep, 64).expect("read");
+            assert_eq!(data, [0xde, 0xad]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_ep_read_empty_polls_not_enough() {
+    TestCtx::<3, 1>::default()
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_in.address().index();
+
+            let data = dev.ep_read(&mut cls, ep, 64).expect("read");
+            assert!(data.is_empty());
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_unconfigure_resets_endpoints() {
+    TestCtx::<0>::default()
+        .with_usb(|mut cls, mut dev| {
+            let ep_in = cls.ep_in.address();
+            cls.push_report(&[1, 2, 3]);
+            dev.force_endpoint_stall(ep_in.index(), ep_in.direction())
+                .expect("stall");
+
+            dev.device_unconfigure(&mut cls).expect("unconfigure");
+            assert!(dev.peek_in_buffer(ep_in.index()).is_empty());
+            let res = dev.interrupt_poll(&mut cls, ep_in.index(), 1);
+            assert_eq!(res, Err(AnyUsbError::NotConfigured(ep_in)));
+
+            dev.set_configuration_checked(&mut cls, 1)
+                .expect("configure");
+            let status = dev
+                .endpoint_get_status_typed(&mut cls, ep_in.into())
+                .expect("status");
+            assert_eq!(status, EndpointStatus { halt: false });
+
+            // the class doesn't track configuration, the report
+            // was never transmitted
+            cls.in_busy = false;
+            cls.push_report(&[4, 5]);
+            let reports = dev
+                .interrupt_poll(&mut cls, ep_in.index(), 2)
+                .expect("poll");
+            assert_eq!(reports, [[4, 5]]);
+        })
+        .expect("with_usb");
+}
+
+/// Handles HID-like output reports received through its
+/// Interrupt OUT endpoint, one report per packet.
+struct OutputReportUsbClass<'a, B: UsbBus> {
+    ep_out: EndpointOut<'a, B>,
+    reports: Vec<Vec<u8>>,
+    /// Stop reading after this many reports.
+    capacity: usize,
+}
+
+impl<B: UsbBus> UsbClass<B> for OutputReportUsbClass<'_, B> {
+    fn endpoint_out(&mut self, addr: EndpointAddress) {
+        if addr != self.ep_out.address() || self.reports.len() == self.capacity {
+            return;
+        }
+        let mut buf = [0; EP_SIZE as usize];
+        if let Ok(len) = self.ep_out.read(&mut buf) {
+            self.reports.push(buf[..len].to_vec());
+        }
+    }
+}
+
+struct OutputCtx {}
+
+impl UsbDeviceCtx for OutputCtx {
+    type C<'c> = OutputReportUsbClass<'c, EmulatedUsbBus>;
+
+    fn create_class<'a>(
+        &mut self,
+        alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<OutputReportUsbClass<'a, EmulatedUsbBus>> {
+        Ok(OutputReportUsbClass {
+            ep_out: alloc.interrupt(EP_SIZE, 1),
+            reports: Vec::new(),
+            capacity: usize::MAX,
+        })
+    }
+}
+
+#[test]
+fn test_ep_write_interrupt() {
+    OutputCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_out.address().index();
+            let data: Vec<u8> = (0..20).collect();
+
+            let len = dev.ep_write_interrupt(&mut cls, ep, &data).expect("write");
+            assert_eq!(len, 20);
+            // one report per packet
+            assert_eq!(cls.reports, [&data[0..8], &data[8..16], &data[16..20]]);
+
+            // no zero-length packet after a full one
+            cls.reports.clear();
+            let len = dev
+                .ep_write_interrupt(&mut cls, ep, &data[..8])
+                .expect("write");
+            assert_eq!(len, 8);
+            assert_eq!(cls.reports, [&data[..8]]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_ep_write_interrupt_not_consumed() {
+    OutputCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_out.address();
+            cls.capacity = 1;
+
+            let len = dev
+                .ep_write_interrupt(&mut cls, ep.index(), &[1; 20])
+                .expect("write");
+            assert_eq!(len, 8);
+            assert_eq!(cls.reports, [[1; 8]]);
+            assert_eq!(dev.peek_out_buffer_len(ep.index()), 8);
+
+            dev.force_endpoint_stall(ep.index(), ep.direction())
+                .expect("stall");
+            let res = dev.ep_write_interrupt(&mut cls, ep.index(), &[1; 20]);
+            assert_eq!(res, Err(AnyUsbError::EPStalled));
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_ep_read_exact() {
+    TestCtx::<0>::default()
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.ep_in.address().index();
+
+            cls.push_report(&[1; EP_SIZE as usize]);
+            let report = dev.ep_read_exact(&mut cls, ep, EP_SIZE).expect("read");
+            assert_eq!(report, [1; EP_SIZE as usize]);
+
+            cls.push_report(&[2; 3]);
+            let res = dev.ep_read_exact(&mut cls, ep, EP_SIZE);
+            assert_eq!(
+                res,
+                Err(AnyUsbError::EPShortRead {
+                    expected: EP_SIZE as usize,
+                    got: 3
+                })
+            );
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_endpoints() {
+    TestCtx::<0>::default()
+        .with_usb(|cls, dev| {
+            assert_eq!(
+                dev.endpoints(),
+                [EndpointInfo {
+                    address: cls.ep_in.address(),
+                    ep_type: EndpointType::Interrupt,
+                    max_packet_size: EP_SIZE,
+                    interval: 1,
+                }]
+            );
+        })
+        .expect("with_usb");
+}
+
+/// Writes `DESCR_INTERVAL` as bInterval of its Interrupt IN
+/// endpoint, allocated with interval 10.
+struct IntervalUsbClass<'a, B: UsbBus, const DESCR_INTERVAL: u8> {
+    iface: InterfaceNumber,
+    ep_in: EndpointIn<'a, B>,
+}
+
+impl<B: UsbBus, const DESCR_INTERVAL: u8> UsbClass<B> for IntervalUsbClass<'_, B, DESCR_INTERVAL> {
+    fn get_configuration_descriptors(
+        &self,
+        writer: &mut usb_device::descriptor::DescriptorWriter,
+    ) -> usb_device::Result<()> {
+        writer.interface(self.iface, 0xff, 0x00, 0x00)?;
+        let [lo, hi] = self.ep_in.max_packet_size().to_le_bytes();
+        writer.write(
+            5,
+            &[self.ep_in.address().into(), 0x03, lo, hi, DESCR_INTERVAL],
+        )
+    }
+}
+
+struct IntervalCtx<const DESCR_INTERVAL: u8> {}
+
+impl<const DESCR_INTERVAL: u8> UsbDeviceCtx for IntervalCtx<DESCR_INTERVAL> {
+    type C<'c> = IntervalUsbClass<'c, EmulatedUsbBus, DESCR_INTERVAL>;
+
+    fn create_class<'a>(
+        &mut self,
+        alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<IntervalUsbClass<'a, EmulatedUsbBus, DESCR_INTERVAL>> {
+        Ok(IntervalUsbClass {
+            iface: alloc.interface(),
+            ep_in: alloc.interrupt(EP_SIZE, 10),
+        })
+    }
+}
+
+#[test]
+fn test_check_endpoint_intervals() {
+    IntervalCtx::<10> {}
+        .with_usb(|mut cls, mut dev| {
+            assert_eq!(dev.endpoints()[0].interval, 10);
+            assert_eq!(dev.check_endpoint_intervals(&mut cls), Ok(()));
+        })
+        .expect("with_usb");
+
+    IntervalCtx::<4> {}
+        .with_usb(|mut cls, mut dev| {
+            assert_eq!(
+                dev.check_endpoint_intervals(&mut cls),
+                Err(AnyUsbError::EndpointIntervalMismatch {
+                    ep: cls.ep_in.address(),
+                    allocated: 10,
+                    descriptor: 4,
+                })
+            );
+        })
+        .expect("with_usb");
+}
@@ -0,0 +1,223 @@
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::{UsbBus, UsbBusAllocator};
+use usb_device::class::UsbClass;
+use usb_device::endpoint::{
+    EndpointAddress, EndpointIn, EndpointOut, IsochronousSynchronizationType, IsochronousUsageType,
+};
+
+/// 48 kHz 16-bit mono samples per 1 ms frame.
+const FRAME_SAMPLES: usize = 48;
+const EP_SIZE: u16 = FRAME_SAMPLES as u16 * 2;
+
+/// UAC-like class with a speaker (Isochronous OUT) and
+/// a microphone (Isochronous IN) streaming 16-bit samples.
+struct AudioUsbClass<'a, B: UsbBus> {
+    speaker: EndpointOut<'a, B>,
+    mic: EndpointIn<'a, B>,
+    /// Read `speaker` only if set.
+    playing: bool,
+    /// Samples received from `speaker`.
+    played: Vec<i16>,
+    /// The first sample of the next `mic` packet.
+    next_sample: i16,
+}
+
+impl<'a, B: UsbBus> AudioUsbClass<'a, B> {
+    fn new(alloc: &'a UsbBusAllocator<B>) -> Self {
+        Self {
+            speaker: alloc.isochronous(
+                IsochronousSynchronizationType::Asynchronous,
+                IsochronousUsageType::Data,
+                EP_SIZE,
+                1,
+            ),
+            mic: alloc.isochronous(
+                IsochronousSynchronizationType::Asynchronous,
+                IsochronousUsageType::Data,
+                EP_SIZE,
+                1,
+            ),
+            playing: true,
+            played: Vec::new(),
+            next_sample: 0,
+        }
+    }
+
+    /// Writes the next frame of samples to `mic`.
+    fn record(&mut self) {
+        let packet: Vec<u8> = (0..FRAME_SAMPLES as i16)
+            .flat_map(|n| (self.next_sample + n).to_le_bytes())
+            .collect();
+        if self.mic.write(&packet).is_ok() {
+            self.next_sample += FRAME_SAMPLES as i16;
+        }
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for AudioUsbClass<'_, B> {
+    fn poll(&mut self) {
+        if !self.playing {
+            return;
+        }
+
+        let mut buf = [0; EP_SIZE as usize];
+        if let Ok(count) = self.speaker.read(&mut buf) {
+            let samples = buf[..count]
+                .chunks_exact(2)
+                .map(|s| i16::from_le_bytes([s[0], s[1]]));
+            self.played.extend(samples);
+        }
+    }
+
+    fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
+        if addr == self.mic.address() {
+            self.record();
+        }
+    }
+}
+
+struct TestCtx {}
+
+impl UsbDeviceCtx for TestCtx {
+    type C<'c> = AudioUsbClass<'c, EmulatedUsbBus>;
+
+    fn create_class<'a>(
+        &mut self,
+        alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<AudioUsbClass<'a, EmulatedUsbBus>> {
+        Ok(AudioUsbClass::new(alloc))
+    }
+}
+
+fn frame_packet(first: i16) -> Vec<u8> {
+    (0..FRAME_SAMPLES as i16)
+        .flat_map(|n| (first + n).to_le_bytes())
+        .collect()
+}
+
+#[test]
+fn test_iso_speaker_stream() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.speaker.address().index();
+
+            for frame in 0..4 {
+                assert_eq!(dev.iso_frame(), frame);
+                let packet = frame_packet(frame as i16 * FRAME_SAMPLES as i16);
+                let len = dev.iso_write_frame(&mut cls, ep, &packet).expect("write");
+                assert_eq!(len, EP_SIZE as usize);
+                assert_eq!(dev.iso_next_frame(&mut cls), 0);
+            }
+
+            let expected: Vec<i16> = (0..4 * FRAME_SAMPLES as i16).collect();
+            assert_eq!(cls.played, expected);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_iso_speaker_dropped() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.speaker.address().index();
+
+            cls.playing = false;
+            dev.iso_write_frame(&mut cls, ep, &frame_packet(0))
+                .expect("write");
+            assert_eq!(dev.iso_next_frame(&mut cls), EP_SIZE as usize);
+
+            cls.playing = true;
+            dev.iso_write_frame(&mut cls, ep, &frame_packet(100))
+                .expect("write");
+            assert_eq!(dev.iso_next_frame(&mut cls), 0);
+
+            let expected: Vec<i16> = (100..100 + FRAME_SAMPLES as i16).collect();
+            assert_eq!(cls.played, expected);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_iso_mic_stream() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let ep = cls.mic.address().index();
+
+            // nothing recorded yet
+            assert_eq!(dev.iso_read_frame(&mut cls, ep), Ok(None));
+            dev.iso_next_frame(&mut cls);
+
+            cls.record();
+            for frame in 0..3 {
+                let packet = dev.iso_read_frame(&mut cls, ep).expect("read");
+                assert_eq!(packet, Some(frame_packet(frame * FRAME_SAMPLES as i16)));
+                dev.iso_next_frame(&mut cls);
+            }
+
+            // a frame which isn't read is lost
+            assert_eq!(dev.iso_next_frame(&mut cls), EP_SIZE as usize);
+            let packet = dev.iso_read_frame(&mut cls, ep).expect("read");
+            assert_eq!(packet, Some(frame_packet(4 * FRAME_SAMPLES as i16)));
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_iso_one_packet_per_frame() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let speaker = cls.speaker.address().index();
+            let mic = cls.mic.address().index();
+
+            dev.iso_write_frame(&mut cls, speaker, &[1, 2])
+                .expect("write");
+            let res = dev.iso_write_frame(&mut cls, speaker, &[3, 4]);
+            assert_eq!(res, Err(AnyUsbError::IsoFrameUsed));
+
+            dev.iso_read_frame(&mut cls, mic).expect("read");
+            let res = dev.iso_read_frame(&mut cls, mic);
+            assert_eq!(res, Err(AnyUsbError::IsoFrameUsed));
+
+            dev.iso_next_frame(&mut cls);
+            dev.iso_write_frame(&mut cls, speaker, &[3, 4])
+                .expect("write");
+            assert_eq!(cls.played, [0x0201, 0x0403]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_iso_errors() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let speaker = cls.speaker.address().index();
+            let mic = cls.mic.address().index();
+
+            let res = dev.iso_write_frame(&mut cls, speaker, &[0; EP_SIZE as usize + 1]);
+            assert_eq!(
+                res,
+                Err(AnyUsbError::IsoPacketTooLarge {
+                    max_size: EP_SIZE as usize,
+                    got: EP_SIZE as usize + 1
+                })
+            );
+
+            let res = dev.iso_write_frame(&mut cls, 0, &[1]);
+            assert_eq!(res, Err(AnyUsbError::NotIsoEndpoint));
+
+            let res = dev.ep_write(&mut cls, speaker, &[1, 2]);
+            assert_eq!(res, Err(AnyUsbError::IsoEndpoint));
+            let res = dev.ep_read(&mut cls, mic, 256);
+            assert_eq!(res, Err(AnyUsbError::IsoEndpoint));
+            let res = dev.bulk_read(&mut cls, mic, 256);
+            assert_eq!(res, Err(AnyUsbError::IsoEndpoint));
+
+            // no stall on Isochronous endpoints
+            cls.mic.stall();
+            cls.record();
+            let packet = dev.iso_read_frame(&mut cls, mic).expect("read");
+            assert_eq!(packet, Some(frame_packet(0)));
+        })
+        .expect("with_usb");
+}
@@ -0,0 +1,135 @@
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::{UsbBus, UsbBusAllocator};
+use usb_device::class::{ControlIn, UsbClass};
+use usb_device::control::{Recipient, Request, RequestType};
+
+const VENDOR_CODE: u8 = 0x42;
+
+/// Microsoft OS 1.0 String Descriptor with `VENDOR_CODE`.
+#[rustfmt::skip]
+const MS_OS_STRING: [u8; 18] = [
+    0x12, 0x03,
+    b'M', 0, b'S', 0, b'F', 0, b'T', 0, b'1', 0, b'0', 0, b'0', 0,
+    VENDOR_CODE, 0,
+];
+
+const VENDOR_CODE_2: u8 = 0x43;
+
+/// Microsoft OS 2.0 descriptor set header with
+/// a Compatible ID descriptor for WinUSB.
+#[rustfmt::skip]
+const MS_OS_20_SET: [u8; 30] = [
+    0x0a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x06, 0x1e, 0x00,
+    0x14, 0x00, 0x03, 0x00,
+    b'W', b'I', b'N', b'U', b'S', b'B', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+];
+
+/// Provides Microsoft OS descriptors like a WinUSB device.
+struct WinUsbClass {
+    ms_os_string: Option<[u8; 18]>,
+}
+
+impl<B: UsbBus> UsbClass<B> for WinUsbClass {
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+        if req.request_type == RequestType::Standard
+            && req.recipient == Recipient::Device
+            && req.request == Request::GET_DESCRIPTOR
+            && req.value == 0x03ee
+        {
+            if let Some(descr) = self.ms_os_string {
+                xfer.accept_with(&descr).ok();
+            }
+        } else if req.request_type == RequestType::Vendor
+            && req.recipient == Recipient::Device
+            && req.request == VENDOR_CODE_2
+            && req.index == 7
+        {
+            xfer.accept_with(&MS_OS_20_SET).ok();
+        }
+    }
+}
+
+struct TestCtx {}
+
+impl UsbDeviceCtx for TestCtx {
+    type C<'c> = WinUsbClass;
+
+    fn create_class(&mut self, _alloc: &UsbBusAllocator<EmulatedUsbBus>) -> AnyResult<WinUsbClass> {
+        Ok(WinUsbClass {
+            ms_os_string: Some(MS_OS_STRING),
+        })
+    }
+}
+
+#[test]
+fn test_ms_os_string_descriptor() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let descr = dev
+                .device_get_ms_os_string_descriptor(&mut cls)
+                .expect("descriptor");
+            assert_eq!(
+                descr,
+                Some(MsOsStringDescriptor {
+                    vendor_code: VENDOR_CODE,
+                    pad: 0
+                })
+            );
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_ms_os_string_descriptor_missing() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            cls.ms_os_string = None;
+            let descr = dev
+                .device_get_ms_os_string_descriptor(&mut cls)
+                .expect("descriptor");
+            assert_eq!(descr, None);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_ms_os_string_descriptor_bad_signature() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let mut descr = MS_OS_STRING;
+            descr[14] = b'1';
+            cls.ms_os_string = Some(descr);
+
+            let res = dev.device_get_ms_os_string_descriptor(&mut cls);
+            assert_eq!(res, Err(AnyUsbError::InvalidMsOsSignature));
+
+            descr[0] = 0x10;
+            cls.ms_os_string = Some(descr);
+            let res = dev.device_get_ms_os_string_descriptor(&mut cls);
+            assert_eq!(res, Err(AnyUsbError::InvalidDescriptorLength));
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_ms_os_2_descriptor_set() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let set = dev
+                .get_ms_os_2_descriptor_set(&mut cls, VENDOR_CODE_2, 0x1e)
+                .expect("set");
+            assert_eq!(set, MS_OS_20_SET);
+
+            // wTotalLength of the set header
+            let set = dev
+                .get_ms_os_2_descriptor_set(&mut cls, VENDOR_CODE_2, 10)
+                .expect("set");
+            assert_eq!(u16::from_le_bytes([set[8], set[9]]), 0x1e);
+
+            let res = dev.get_ms_os_2_descriptor_set(&mut cls, VENDOR_CODE, 0x1e);
+            assert_eq!(res, Err(AnyUsbError::EP0Stalled));
+        })
+        .expect("with_usb");
+}
@@ -0,0 +1,117 @@
+mod test_device2;
+use test_device2::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+
+struct TestCtx {}
+
+impl UsbDeviceCtx for TestCtx {
+    type C<'c> = EpTestUsbClass<'c, EmulatedUsbBus>;
+    const ADDRESS: u8 = 7;
+
+    fn create_class<'a>(
+        &mut self,
+        alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<EpTestUsbClass<'a, EmulatedUsbBus>> {
+        Ok(EpTestUsbClass::new(alloc))
+    }
+}
+
+/// Splits pcap stream into records after checking the file header.
+fn records(pcap: &[u8]) -> Vec<&[u8]> {
+    assert_eq!(pcap[0..4], 0xa1b2c3d4u32.to_le_bytes());
+    assert_eq!(pcap[20..24], 220u32.to_le_bytes());
+
+    let mut res = Vec::new();
+    let mut rest = &pcap[24..];
+    while !rest.is_empty() {
+        let len = u32::from_le_bytes(rest[8..12].try_into().unwrap()) as usize;
+        res.push(&rest[16..16 + len]);
+        rest = &rest[16 + len..];
+    }
+    res
+}
+
+#[test]
+fn test_pcap_empty() {
+    TestCtx {}
+        .with_usb(|mut _cls, dev| {
+            let mut pcap = Vec::new();
+            dev.write_pcap(&mut pcap).expect("pcap");
+            assert_eq!(pcap.len(), 24);
+            assert!(records(&pcap).is_empty());
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_pcap_transactions() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            dev.enable_trace();
+
+            dev.device_get_status(&mut cls).expect("status");
+            let ep = cls.ep_out.address().index();
+            dev.ep_write(&mut cls, ep, &[1, 2, 3]).expect("write");
+
+            let mut pcap = Vec::new();
+            dev.write_pcap(&mut pcap).expect("pcap");
+            let recs = records(&pcap);
+            assert_eq!(recs.len(), 4);
+
+            // GET_STATUS submit: control IN, setup, no data
+            let r = recs[0];
+            assert_eq!(&r[8..14], [b'S', 2, 0x80, 7, 1, 0]);
+            assert_eq!(&r[14..16], [0, b'<']);
+            assert_eq!(r[32..36], 2u32.to_le_bytes());
+            assert_eq!(&r[40..48], [0x80, 0, 0, 0, 0, 0, 2, 0]);
+            assert_eq!(r.len(), 64);
+
+            // GET_STATUS complete with 2 bytes of data
+            let r = recs[1];
+            assert_eq!(&r[8..14], [b'C', 2, 0x80, 7, 1, 0]);
+            assert_eq!(r[28..32], 0i32.to_le_bytes());
+            assert_eq!(r[32..36], 2u32.to_le_bytes());
+            assert_eq!(&r[64..], [0, 0]);
+            assert_eq!(r[0..8], recs[0][0..8]);
+
+            // bulk OUT submit carries the payload
+            let r = recs[2];
+            assert_eq!(&r[8..14], [b'S', 3, ep as u8, 7, 1, 0]);
+            assert_eq!(&r[14..16], [b'-', 0]);
+            assert_eq!(&r[64..], [1, 2, 3]);
+
+            let r = recs[3];
+            assert_eq!(&r[8..10], [b'C', 3]);
+            assert_eq!(r[32..36], 3u32.to_le_bytes());
+            assert_eq!(r.len(), 64);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_pcap_stall() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            dev.enable_trace();
+
+            dev.control_write(
+                &mut cls,
+                CtrRequestType::to_device().vendor().device(),
+                1,
+                0,
+                0,
+                &[],
+            )
+            .expect_err("stall");
+
+            let mut pcap = Vec::new();
+            dev.write_pcap(&mut pcap).expect("pcap");
+            let recs = records(&pcap);
+            assert_eq!(recs.len(), 2);
+            assert_eq!(recs[1][28..32], (-32i32).to_le_bytes());
+        })
+        .expect("with_usb");
+}
@@ -0,0 +1,107 @@
+use std::cell::RefCell;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::{UsbBus, UsbBusAllocator};
+use usb_device::class::UsbClass;
+use usb_device::endpoint::{EndpointAddress, EndpointOut, EndpointType};
+use usb_device::UsbDirection;
+
+/// Records the order in which `endpoint_out()` is called
+/// for its OUT endpoints.
+struct OrderUsbClass<'a, B: UsbBus> {
+    eps: [EndpointOut<'a, B>; 3],
+    /// Read endpoints in `endpoint_out()` only if set.
+    armed: bool,
+    order: Vec<usize>,
+}
+
+impl<'a, B: UsbBus> OrderUsbClass<'a, B> {
+    fn new(alloc: &'a UsbBusAllocator<B>) -> Self {
+        Self {
+            eps: [1, 2, 3].map(|index| {
+                let addr = EndpointAddress::from_parts(index, UsbDirection::Out);
+                alloc
+                    .alloc(Some(addr), EndpointType::Bulk, 8, 0)
+                    .expect("alloc")
+            }),
+            armed: false,
+            order: Vec::new(),
+        }
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for OrderUsbClass<'_, B> {
+    fn endpoint_out(&mut self, addr: EndpointAddress) {
+        if !self.armed {
+            return;
+        }
+        if let Some(ep) = self.eps.iter().find(|ep| ep.address() == addr) {
+            let mut buf = [0; 8];
+            ep.read(&mut buf).ok();
+            self.order.push(addr.index());
+        }
+    }
+}
+
+struct OrderCtx<const ORDER: u8> {}
+
+impl<const ORDER: u8> UsbDeviceCtx for OrderCtx<ORDER> {
+    type C<'c> = OrderUsbClass<'c, EmulatedUsbBus>;
+    const POLL_ORDER: PollOrder = match ORDER {
+        0 => PollOrder::Ascending,
+        1 => PollOrder::Descending,
+        _ => PollOrder::Shuffled(ORDER as u64),
+    };
+
+    fn create_class<'a>(
+        &mut self,
+        alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<OrderUsbClass<'a, EmulatedUsbBus>> {
+        Ok(OrderUsbClass::new(alloc))
+    }
+}
+
+thread_local! {
+    static SERVICED: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Queues data on all OUT endpoints and returns
+/// the order they were serviced in.
+fn service_order<const ORDER: u8>() -> Vec<usize> {
+    OrderCtx::<ORDER> {}
+        .with_usb(|mut cls, mut dev| {
+            let eps: Vec<usize> = cls.eps.iter().map(|ep| ep.address().index()).collect();
+            for ep in eps.iter() {
+                dev.ep_write(&mut cls, *ep, &[0x55; 8]).expect("write");
+            }
+
+            cls.armed = true;
+            for _ in 0..eps.len() {
+                dev.poll(&mut cls);
+            }
+            SERVICED.set(cls.order.clone());
+        })
+        .expect("with_usb");
+    SERVICED.take()
+}
+
+#[test]
+fn test_poll_order_ascending() {
+    assert_eq!(service_order::<0>(), [1, 2, 3]);
+}
+
+#[test]
+fn test_poll_order_descending() {
+    assert_eq!(service_order::<1>(), [3, 2, 1]);
+}
+
+#[test]
+fn test_poll_order_shuffled() {
+    let order = service_order::<42>();
+    assert_eq!(order, service_order::<42>());
+
+    let mut sorted = order.clone();
+    sorted.sort();
+    assert_eq!(sorted, [1, 2, 3]);
+}
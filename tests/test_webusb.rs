@@ -0,0 +1,60 @@
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::{UsbBus, UsbBusAllocator};
+use usb_device::class::{ControlIn, UsbClass};
+use usb_device::control::{Recipient, RequestType};
+
+const VENDOR_CODE: u8 = 0x01;
+const LANDING_PAGE: u8 = 1;
+
+/// Serves WebUSB URL descriptor for "https://example.com"
+/// at index `LANDING_PAGE`.
+struct WebUsbClass {}
+
+impl<B: UsbBus> UsbClass<B> for WebUsbClass {
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+        if req.request_type == RequestType::Vendor
+            && req.recipient == Recipient::Device
+            && req.request == VENDOR_CODE
+            && req.index == 2
+            && req.value == LANDING_PAGE as u16
+        {
+            let url = b"example.com";
+            let mut descr = vec![3 + url.len() as u8, 3, 1];
+            descr.extend_from_slice(url);
+            xfer.accept_with(&descr).ok();
+        }
+    }
+}
+
+struct TestCtx {}
+
+impl UsbDeviceCtx for TestCtx {
+    type C<'c> = WebUsbClass;
+
+    fn create_class(&mut self, _alloc: &UsbBusAllocator<EmulatedUsbBus>) -> AnyResult<WebUsbClass> {
+        Ok(WebUsbClass {})
+    }
+}
+
+#[test]
+fn test_webusb_url() {
+    TestCtx {}
+        .with_usb(|mut cls, mut dev| {
+            let url = dev
+                .get_webusb_url(&mut cls, VENDOR_CODE, LANDING_PAGE)
+                .expect("url");
+            assert_eq!(
+                url,
+                WebUsbUrl {
+                    scheme: 1,
+                    url: "example.com".into()
+                }
+            );
+
+            let res = dev.get_webusb_url(&mut cls, VENDOR_CODE, 2);
+            assert_eq!(res, Err(AnyUsbError::EP0Stalled));
+        })
+        .expect("with_usb");
+}